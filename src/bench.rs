@@ -0,0 +1,235 @@
+//! # Bench
+//!
+//! Find out which method suits the files, by compressing a sample of them with each candidate config.
+//! - [evaluate] samples the inputs and tries each [Candidate], giving a [BenchReport].
+//! - [BenchReport::best] picks the smallest one, optionally within a time budget.
+//!
+//! Only heads of files are sampled, so results of data that changes along a file may be off.
+use std::io::{Cursor, Read};
+use std::path::Path;
+use std::time::{Duration, Instant};
+
+use crate::comde::FnSink;
+use crate::error::ComdeError;
+use crate::utils;
+use crate::vfs::{RealFs, Vfs};
+
+/// Bytes sampled from inputs by [evaluate].
+pub const DEFAULT_SAMPLE_SIZE: u64 = 16 * 1024 * 1024;
+
+/// A config to try.
+#[derive(Clone)]
+pub enum Candidate {
+    #[cfg(feature = "zip")]
+    Zip(crate::formats::zip::ZipCfg),
+    #[cfg(feature = "sevenz")]
+    SevenZ(crate::formats::sevenz::SevenZCfg),
+}
+
+impl Candidate {
+    /// Compress the sample into memory and return the archive.
+    fn compress(&self, sample: &[(String, Vec<u8>)]) -> Result<Vec<u8>, ComdeError> {
+        let mut archive = Cursor::new(vec![]);
+        match self {
+            #[cfg(feature = "zip")]
+            Candidate::Zip(config) => crate::Formats::get_zip().compress_mem(sample, &mut archive, config)?,
+            #[cfg(feature = "sevenz")]
+            Candidate::SevenZ(config) => crate::Formats::get_7z().compress_mem(sample, &mut archive, config)?,
+        };
+        Ok(archive.into_inner())
+    }
+
+    /// Decompress the archive, dropping the data.
+    fn decompress(&self, archive: &[u8]) -> Result<(), ComdeError> {
+        let mut sink = FnSink(|_: &str, data: &mut dyn Read| std::io::copy(data, &mut std::io::sink()).map(|_| ()));
+        match self {
+            #[cfg(feature = "zip")]
+            Candidate::Zip(config) => crate::Formats::get_zip().decompress_into(Cursor::new(archive), &mut sink, config)?,
+            #[cfg(feature = "sevenz")]
+            Candidate::SevenZ(config) => crate::Formats::get_7z().decompress_into(Cursor::new(archive), &mut sink, config)?,
+        };
+        Ok(())
+    }
+}
+
+/// The result of a candidate on the sample.
+#[derive(Clone)]
+pub struct BenchResult {
+    name: String,
+    candidate: Candidate,
+    sample_size: u64,
+    compressed_size: u64,
+    compress_time: Duration,
+    decompress_time: Duration,
+}
+
+impl BenchResult {
+    /// Get the name the candidate is given.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the config of the candidate.
+    pub fn candidate(&self) -> &Candidate {
+        &self.candidate
+    }
+
+    /// Get the size of the archive of the sample.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Get the compressed size divided by the sample size, smaller is better.
+    pub fn ratio(&self) -> f64 {
+        if self.sample_size == 0 { 1.0 } else { self.compressed_size as f64 / self.sample_size as f64 }
+    }
+
+    /// Get the time compressing the sample took.
+    pub fn compress_time(&self) -> Duration {
+        self.compress_time
+    }
+
+    /// Get the time decompressing the sample took.
+    pub fn decompress_time(&self) -> Duration {
+        self.decompress_time
+    }
+
+    /// Get the sample bytes compressed per second.
+    pub fn compress_speed(&self) -> f64 {
+        speed(self.sample_size, self.compress_time)
+    }
+
+    /// Get the sample bytes decompressed per second.
+    pub fn decompress_speed(&self) -> f64 {
+        speed(self.sample_size, self.decompress_time)
+    }
+}
+
+impl std::fmt::Display for BenchResult {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f, "{}: {:.2}%, compress {}/s, decompress {}/s",
+            self.name, self.ratio() * 100.0,
+            utils::h_size(self.compress_speed() as u64), utils::h_size(self.decompress_speed() as u64),
+        )
+    }
+}
+
+fn speed(size: u64, time: Duration) -> f64 {
+    let secs = time.as_secs_f64();
+    if secs > 0.0 { size as f64 / secs } else { 0.0 }
+}
+
+/// The results of all candidates, in the order they are given.
+#[derive(Clone)]
+pub struct BenchReport {
+    input_size: u64,
+    sample_size: u64,
+    results: Vec<BenchResult>,
+}
+
+impl BenchReport {
+    /// Get the size of all inputs.
+    pub fn input_size(&self) -> u64 {
+        self.input_size
+    }
+
+    /// Get the size of the sample that candidates compressed.
+    pub fn sample_size(&self) -> u64 {
+        self.sample_size
+    }
+
+    /// Get the results of candidates.
+    pub fn results(&self) -> &[BenchResult] {
+        &self.results
+    }
+
+    /// Guess the time a candidate takes to compress all inputs, by its speed on the sample.
+    pub fn estimated_time(&self, result: &BenchResult) -> Duration {
+        let speed = result.compress_speed();
+        if speed > 0.0 { Duration::from_secs_f64(self.input_size as f64 / speed) } else { Duration::ZERO }
+    }
+
+    /// Get the result with the smallest archive,
+    /// among those guessed to compress all inputs within `budget` if it's set.
+    /// Ties go to the faster one.
+    pub fn best(&self, budget: Option<Duration>) -> Option<&BenchResult> {
+        self.results.iter()
+            .filter(|result| budget.is_none_or(|budget| self.estimated_time(result) <= budget))
+            .min_by(|a, b| a.compressed_size.cmp(&b.compressed_size).then(a.compress_time.cmp(&b.compress_time)))
+    }
+}
+
+impl std::fmt::Display for BenchReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Sampled {} of {}", utils::h_size(self.sample_size), utils::h_size(self.input_size))?;
+        for result in &self.results {
+            write!(f, "\n\t{}", result)?;
+        }
+        Ok(())
+    }
+}
+
+/// Compress and decompress a sample of inputs with each named candidate,
+/// the sample is at most [DEFAULT_SAMPLE_SIZE] bytes.
+pub fn evaluate<P: AsRef<Path>, N: AsRef<str>>(
+    inputs: &[P],
+    candidates: &[(N, Candidate)],
+) -> Result<BenchReport, ComdeError> {
+    evaluate_with(inputs, candidates, DEFAULT_SAMPLE_SIZE)
+}
+
+/// Compress and decompress a sample of inputs with each named candidate,
+/// the sample is at most `sample_size` bytes.
+/// Heads of files are taken in turn, each gets an even share of the sample if there are many of them.
+pub fn evaluate_with<P: AsRef<Path>, N: AsRef<str>>(
+    inputs: &[P],
+    candidates: &[(N, Candidate)],
+    sample_size: u64,
+) -> Result<BenchReport, ComdeError> {
+    let input_size = utils::size_of_inarray_in(&RealFs, inputs).map_err(ComdeError::from)?;
+    let sample = sample_of(&RealFs, inputs, sample_size)?;
+    let sample_size = sample.iter().map(|(_, data)| data.len() as u64).sum();
+
+    let mut results = Vec::with_capacity(candidates.len());
+    for (name, candidate) in candidates {
+        let time_begin = Instant::now();
+        let archive = candidate.compress(&sample)?;
+        let compress_time = time_begin.elapsed();
+
+        let time_begin = Instant::now();
+        candidate.decompress(&archive)?;
+        let decompress_time = time_begin.elapsed();
+
+        results.push(BenchResult {
+            name: name.as_ref().to_string(),
+            candidate: candidate.clone(),
+            sample_size,
+            compressed_size: archive.len() as u64,
+            compress_time,
+            decompress_time,
+        });
+    }
+    Ok(BenchReport { input_size, sample_size, results })
+}
+
+/// Read the heads of files in inputs, at most `sample_size` bytes in all.
+fn sample_of<V: Vfs + ?Sized, P: AsRef<Path>>(vfs: &V, inputs: &[P], sample_size: u64) -> Result<Vec<(String, Vec<u8>)>, ComdeError> {
+    let (entries, _) = utils::collect_entries(vfs, inputs).map_err(ComdeError::from)?;
+    let files = entries.iter().filter(|entry| !entry.is_dir).collect::<Vec<_>>();
+    let share = (sample_size / files.len().max(1) as u64).max(utils::INCOMPRESSIBLE_SAMPLE_SIZE as u64);
+    let mut sample = vec![];
+    let mut left = sample_size;
+    for entry in files {
+        if left == 0 {
+            break;
+        }
+        let mut data = vec![];
+        vfs.open(&entry.path)
+            .and_then(|file| file.take(share.min(left)).read_to_end(&mut data))
+            .map_err(|e| ComdeError::io(&entry.path, e))?;
+        left -= data.len() as u64;
+        sample.push((entry.name.clone(), data));
+    }
+    Ok(sample)
+}
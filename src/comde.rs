@@ -1,15 +1,64 @@
 //! Comde Module
 
+use std::collections::HashMap;
+use std::io::Read;
 use std::time::Duration;
 
 use crate::utils;
 
+/// The stats of an entry in a compression or a decompression.
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
+pub struct EntryStat {
+    name: String,
+    is_dir: bool,
+    size: u64,
+    compressed_size: Option<u64>,
+}
+
+impl EntryStat {
+    pub fn new(name: &str, is_dir: bool, size: u64, compressed_size: Option<u64>) -> EntryStat {
+        EntryStat {
+            name: name.to_string(),
+            is_dir,
+            size,
+            compressed_size,
+        }
+    }
+
+    /// Get the path of the entry in the archive.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Check whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Get the original size of the entry.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the compressed size of the entry.
+    /// It's `None` when it can't be told on its own, such as an entry packed with others in a solid 7z block,
+    /// or an entry written straight into a zip or a tar.
+    pub fn compressed_size(&self) -> Option<u64> {
+        self.compressed_size
+    }
+}
+
 /// The status of a compression.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct CompressStatus {
     origin_size: u64,
     compressed_size: u64,
     time: Duration,
+    entries: Vec<EntryStat>,
+    skipped: Vec<String>,
+    stored: usize,
 }
 
 impl CompressStatus {
@@ -17,25 +66,111 @@ impl CompressStatus {
         CompressStatus {
             origin_size,
             compressed_size,
-            time
+            time,
+            entries: vec![],
+            skipped: vec![],
+            stored: 0,
         }
     }
+
+    /// Attach the stats of compressed entries.
+    pub fn with_entries(mut self, entries: Vec<EntryStat>) -> CompressStatus {
+        self.entries = entries;
+        self
+    }
+
+    /// Attach the names of skipped entries.
+    pub fn with_skipped(mut self, skipped: Vec<String>) -> CompressStatus {
+        self.skipped = skipped;
+        self
+    }
+
+    /// Attach the count of files stored without compression as they looked incompressible.
+    pub fn with_stored(mut self, stored: usize) -> CompressStatus {
+        self.stored = stored;
+        self
+    }
+
+    /// Get the size of the files before compressing.
+    pub fn origin_size(&self) -> u64 {
+        self.origin_size
+    }
+
+    /// Get the size of the archive.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Get the time the compression took.
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Get the compressed size divided by the origin size, smaller is better.
+    /// It's 1.0 when there's nothing to compress.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.compressed_size, self.origin_size)
+    }
+
+    /// Get the origin bytes compressed per second.
+    pub fn throughput(&self) -> f64 {
+        throughput(self.origin_size, self.time)
+    }
+
+    /// Get the count of entries, files and directories.
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the count of files.
+    pub fn file_count(&self) -> usize {
+        self.entries.iter().filter(|e| !e.is_dir).count()
+    }
+
+    /// Get the count of directories.
+    pub fn dir_count(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_dir).count()
+    }
+
+    /// Get the stats of each entry, in the order they are written.
+    pub fn entries(&self) -> &[EntryStat] {
+        &self.entries
+    }
+
+    /// Get the names of skipped inputs, which are neither files nor directories, such as links.
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
+
+    /// Get the count of files stored without compression as they looked incompressible,
+    /// see `set_detect_incompressible` of the zip and 7z configs.
+    pub fn stored_count(&self) -> usize {
+        self.stored
+    }
 }
 
 impl std::fmt::Display for CompressStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let (os, cs) = (utils::h_size(self.origin_size), utils::h_size(self.compressed_size));
-        write!(f, "\n\tOrigin files size: {}\n\tCompressed archive size: {}\n\tTime  cost: {}", os, cs, self.time.as_secs())
+        write!(f, "\n\tOrigin files size: {}\n\tCompressed archive size: {} ({:.2}%)", os, cs, self.ratio() * 100.0)?;
+        write!(f, "\n\tEntries: {} files, {} directories, {} skipped", self.file_count(), self.dir_count(), self.skipped.len())?;
+        if self.stored > 0 {
+            write!(f, ", {} stored as incompressible", self.stored)?;
+        }
+        write!(f, "\n\tTime cost: {:.2}s ({}/s)", self.time.as_secs_f64(), utils::h_size(self.throughput() as u64))
     }
 }
 
 
 /// The status of a decompression.
-#[derive(Debug)]
+#[derive(Debug, Clone)]
+#[cfg_attr(feature = "serde", derive(serde::Serialize, serde::Deserialize))]
 pub struct DecompressStatus {
     compressed_size: u64,
     decompressed_size: u64,
     time: Duration,
+    entries: Vec<EntryStat>,
+    skipped: Vec<String>,
 }
 
 impl DecompressStatus {
@@ -43,14 +178,124 @@ impl DecompressStatus {
         DecompressStatus {
             compressed_size,
             decompressed_size,
-            time
+            time,
+            entries: vec![],
+            skipped: vec![],
         }
     }
+
+    /// Attach the stats of decompressed entries.
+    pub fn with_entries(mut self, entries: Vec<EntryStat>) -> DecompressStatus {
+        self.entries = entries;
+        self
+    }
+
+    /// Attach the names of skipped entries.
+    pub fn with_skipped(mut self, skipped: Vec<String>) -> DecompressStatus {
+        self.skipped = skipped;
+        self
+    }
+
+    /// Get the size of the archive that was read.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
+
+    /// Get the size of the decompressed files.
+    pub fn decompressed_size(&self) -> u64 {
+        self.decompressed_size
+    }
+
+    /// Get the time the decompression took.
+    pub fn time(&self) -> Duration {
+        self.time
+    }
+
+    /// Get the compressed size divided by the decompressed size, smaller is better.
+    /// It's 1.0 when there's nothing decompressed.
+    pub fn ratio(&self) -> f64 {
+        ratio(self.compressed_size, self.decompressed_size)
+    }
+
+    /// Get the bytes decompressed per second.
+    pub fn throughput(&self) -> f64 {
+        throughput(self.decompressed_size, self.time)
+    }
+
+    /// Get the count of entries, files and directories.
+    pub fn entry_count(&self) -> usize {
+        self.entries.len()
+    }
+
+    /// Get the count of files.
+    pub fn file_count(&self) -> usize {
+        self.entries.iter().filter(|e| !e.is_dir).count()
+    }
+
+    /// Get the count of directories.
+    pub fn dir_count(&self) -> usize {
+        self.entries.iter().filter(|e| e.is_dir).count()
+    }
+
+    /// Get the stats of each entry, in the order they are stored.
+    pub fn entries(&self) -> &[EntryStat] {
+        &self.entries
+    }
+
+    /// Get the names of skipped entries, only tar skips any:
+    /// entries with paths out of the output directory when extracting into files,
+    /// and entries other than files and directories, such as links, when passing them into a sink.
+    /// Zip and 7z refuse paths out of the output directory with [ComdeError::PathTraversal](crate::error::ComdeError::PathTraversal) instead.
+    pub fn skipped(&self) -> &[String] {
+        &self.skipped
+    }
 }
 
 impl std::fmt::Display for DecompressStatus {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         let (cs, ds) = (utils::h_size(self.compressed_size), utils::h_size(self.decompressed_size));
-        write!(f, "\n\tArchive size: {}\n\tDecompressed files size: {}\n\tTime cost: {}s\n", cs, ds, self.time.as_secs())
+        write!(f, "\n\tArchive size: {}\n\tDecompressed files size: {}", cs, ds)?;
+        write!(f, "\n\tEntries: {} files, {} directories, {} skipped", self.file_count(), self.dir_count(), self.skipped.len())?;
+        write!(f, "\n\tTime cost: {:.2}s ({}/s)\n", self.time.as_secs_f64(), utils::h_size(self.throughput() as u64))
     }
-}
\ No newline at end of file
+}
+
+fn ratio(part: u64, whole: u64) -> f64 {
+    if whole == 0 { 1.0 } else { part as f64 / whole as f64 }
+}
+
+fn throughput(size: u64, time: Duration) -> f64 {
+    let secs = time.as_secs_f64();
+    if secs > 0.0 { size as f64 / secs } else { 0.0 }
+}
+
+/// Where decompressed entries go when they are not written into a directory.
+/// Entry names are kept as they are stored in the archive.
+pub trait Sink {
+    /// Receive a directory. Directories are ignored by default.
+    fn dir(&mut self, _name: &str) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Receive a file and its data.
+    fn file(&mut self, name: &str, data: &mut dyn Read) -> std::io::Result<()>;
+}
+
+/// Collect files into memory by their names.
+impl Sink for HashMap<String, Vec<u8>> {
+    fn file(&mut self, name: &str, data: &mut dyn Read) -> std::io::Result<()> {
+        let mut buf = vec![];
+        data.read_to_end(&mut buf)?;
+        self.insert(name.to_string(), buf);
+        Ok(())
+    }
+}
+
+/// A [Sink] that calls the closure for each file.
+pub struct FnSink<F: FnMut(&str, &mut dyn Read) -> std::io::Result<()>>(pub F);
+
+impl <F: FnMut(&str, &mut dyn Read) -> std::io::Result<()>> Sink for FnSink<F> {
+    fn file(&mut self, name: &str, data: &mut dyn Read) -> std::io::Result<()> {
+        (self.0)(name, data)
+    }
+}
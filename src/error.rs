@@ -3,20 +3,25 @@
 //! Here defines the error types that may happen in the crate.
 //!
 
+use std::path::{Path, PathBuf};
 use thiserror::Error;
 
 #[derive(Debug, Error)]
 pub enum AppError {
 
-    #[error("{0}")]
-    FsError(String),
-    
+    #[error("I/O error at {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
     #[error("Error when compressing/decompressing.")]
     ComdeError(#[from] ComdeError),
-    
+
     #[error("Error when viewing archive.")]
     ViewError(#[from] ViewError),
-    
+
     #[error("Unknown error.")]
     #[warn(dead_code)]
     OtherError(),
@@ -31,13 +36,200 @@ pub enum ComdeError {
     #[error("Error when compressing/decompressing 7z: {0}")]
     SevenZError(String),
 
+    #[error("Error when compressing/decompressing tar: {0}")]
+    TarError(String),
+
+    /// Reading or writing failed, `path` is `None` when it's not a named file, such as a stream.
+    #[error("I/O error{}: {source}", at_path(path))]
+    Io {
+        path: Option<PathBuf>,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The password is wrong.
+    #[error("The password is wrong.")]
+    WrongPassword,
+
+    /// The archive is encrypted but no password is given.
+    #[error("A password is required.")]
+    PasswordRequired,
+
+    /// The archive uses a method or a feature that is not supported.
+    #[error("Unsupported method: {0}")]
+    UnsupportedMethod(String),
+
+    /// An option of a config is out of range or can't go with others.
+    #[error("Invalid option: {0}")]
+    InvalidOption(String),
+
+    /// The archive is broken, `entry` is the entry being read if it's known.
+    #[error("Corrupt archive{}: {source}", in_entry(entry))]
+    Corrupt {
+        entry: Option<String>,
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+
+    /// Extracting the archive needs more memory than the limit set, such as by a huge dictionary.
+    #[error("The archive needs about {} of memory to extract, over the limit of {}.", crate::utils::h_size(*required), crate::utils::h_size(*limit))]
+    MemoryLimit {
+        required: u64,
+        limit: u64,
+    },
+
+    /// An entry would be written outside of the output directory.
+    #[error("Entry path escapes the output directory: {entry}")]
+    PathTraversal {
+        entry: String,
+    },
+
     #[error("Unfinished")]
     Other(#[from] Unimplemented)
 }
 
+/// A profile file can't be loaded.
+#[cfg(feature = "profiles")]
+#[derive(Debug, Error)]
+pub enum ProfileError {
+
+    #[error("I/O error at {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    #[error("Invalid profiles: {0}")]
+    Parse(String),
+}
+
+fn at_path(path: &Option<PathBuf>) -> String {
+    path.as_ref().map(|p| format!(" at {}", p.display())).unwrap_or_default()
+}
+
+fn in_entry(entry: &Option<String>) -> String {
+    entry.as_ref().map(|e| format!(" in entry {}", e)).unwrap_or_default()
+}
+
+impl ComdeError {
+
+    /// An I/O error on the file of `path`.
+    pub fn io<P: AsRef<Path>>(path: P, source: std::io::Error) -> ComdeError {
+        ComdeError::Io { path: Some(path.as_ref().to_path_buf()), source }
+    }
+
+    /// Tell the entry that a [ComdeError::Corrupt] happens in, if it's not told yet.
+    pub fn in_entry(self, name: &str) -> ComdeError {
+        match self {
+            ComdeError::Corrupt { entry: None, source } => ComdeError::Corrupt { entry: Some(name.to_string()), source },
+            e => e,
+        }
+    }
+}
+
+/// A [ComdeError] wrapped in an I/O error, such as one returned from a [crate::comde::Sink], is taken out.
+impl From<std::io::Error> for ComdeError {
+    fn from(source: std::io::Error) -> Self {
+        if !source.get_ref().is_some_and(|inner| inner.is::<ComdeError>()) {
+            return ComdeError::Io { path: None, source };
+        }
+        let kind = source.kind();
+        match source.into_inner().map(|inner| inner.downcast::<ComdeError>()) {
+            Some(Ok(e)) => *e,
+            Some(Err(inner)) => ComdeError::Io { path: None, source: std::io::Error::new(kind, inner) },
+            None => ComdeError::Io { path: None, source: kind.into() },
+        }
+    }
+}
+
+impl From<AppError> for ComdeError {
+    fn from(e: AppError) -> Self {
+        match e {
+            AppError::Io { path, source } => ComdeError::Io { path: Some(path), source },
+            AppError::ComdeError(e) => e,
+            e => ComdeError::Io { path: None, source: std::io::Error::other(e.to_string()) },
+        }
+    }
+}
+
+#[cfg(feature = "zip")]
+impl From<zip::result::ZipError> for ComdeError {
+    fn from(e: zip::result::ZipError) -> Self {
+        use zip::result::ZipError;
+        match e {
+            ZipError::Io(source) => ComdeError::from(source),
+            ZipError::InvalidPassword => ComdeError::WrongPassword,
+            ZipError::UnsupportedArchive(ZipError::PASSWORD_REQUIRED) => ComdeError::PasswordRequired,
+            ZipError::UnsupportedArchive(detail) => ComdeError::UnsupportedMethod(detail.to_string()),
+            ZipError::InvalidArchive(_) => ComdeError::Corrupt { entry: None, source: Box::new(e) },
+            e => ComdeError::ZipError(e.to_string()),
+        }
+    }
+}
+
+#[cfg(feature = "sevenz")]
+impl From<sevenz_rust2::Error> for ComdeError {
+    fn from(e: sevenz_rust2::Error) -> Self {
+        use sevenz_rust2::Error;
+        match e {
+            Error::Io(source, _) => ComdeError::from(source),
+            Error::FileOpen(source, path) => ComdeError::io(path, source),
+            Error::MaybeBadPassword(_) => ComdeError::WrongPassword,
+            Error::PasswordRequired => ComdeError::PasswordRequired,
+            Error::UnsupportedCompressionMethod(_)
+            | Error::ExternalUnsupported
+            | Error::UnsupportedVersion { .. }
+            | Error::Unsupported(_) => ComdeError::UnsupportedMethod(e.to_string()),
+            Error::BadSignature(_)
+            | Error::ChecksumVerificationFailed
+            | Error::NextHeaderCrcMismatch
+            | Error::BadTerminatedStreamsInfo(_)
+            | Error::BadTerminatedUnpackInfo
+            | Error::BadTerminatedPackInfo(_)
+            | Error::BadTerminatedSubStreamsInfo
+            | Error::BadTerminatedHeader(_) => ComdeError::Corrupt { entry: None, source: Box::new(e) },
+            e => ComdeError::SevenZError(e.to_string()),
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ViewError {
-    
+
+    #[error("Unknown archive format.")]
+    UnknownFormat,
+
+    /// The archive can't be opened, or its head can't be read.
+    #[error("I/O error at {}: {source}", path.display())]
+    Io {
+        path: PathBuf,
+        #[source]
+        source: std::io::Error,
+    },
+
+    /// The entries of the archive can't be read.
+    #[error("Error when reading archive: {0}")]
+    ReadError(#[source] ComdeError),
+
+    /// The header of the archive is encrypted but no password is given.
+    #[error("A password is required to list the archive.")]
+    PasswordRequired,
+
+    /// The password can't decrypt the header of the archive.
+    #[error("The password is wrong.")]
+    WrongPassword,
+}
+
+/// Password errors are told as they are, others are read errors.
+impl From<ComdeError> for ViewError {
+    fn from(e: ComdeError) -> Self {
+        match e {
+            ComdeError::PasswordRequired => ViewError::PasswordRequired,
+            ComdeError::WrongPassword => ViewError::WrongPassword,
+            e => ViewError::ReadError(e),
+        }
+    }
 }
 
 #[derive(Debug, Error)]
@@ -45,4 +237,4 @@ pub enum Unimplemented {
 
     #[error("Unimplemented feature")]
     Unfinished
-}
\ No newline at end of file
+}
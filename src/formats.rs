@@ -6,6 +6,9 @@ pub mod sevenz;
 #[cfg(feature = "zip")]
 pub mod zip;
 
+#[cfg(feature = "tar")]
+pub mod tar;
+
 pub struct Formats;
 
 impl Formats {
@@ -19,6 +22,11 @@ impl Formats {
     pub fn get_7z() -> sevenz::SevenZComde {
         sevenz::SevenZComde
     }
+
+    #[cfg(feature = "tar")]
+    pub fn get_tar() -> tar::TarComde {
+        tar::TarComde
+    }
 }
 
 pub struct Configs;
@@ -33,4 +41,9 @@ impl Configs {
     pub fn get_7z() -> sevenz::SevenZCfg {
         sevenz::SevenZCfg::new()
     }
+
+    #[cfg(feature = "tar")]
+    pub fn get_tar() -> tar::TarCfg {
+        tar::TarCfg::new()
+    }
 }
\ No newline at end of file
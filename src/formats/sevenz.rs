@@ -5,27 +5,46 @@
 //! 
 //! # Compress & Decompress
 //! 
-//! [todo]
+//! Files are compressed with LZMA2 by default, or another method and [Filter]s set by [SevenZCfg],
+//! and files matching a method rule with its own ones.
+//! In solid mode, files of the same methods are packed into shared blocks, see [SevenZCfg::set_solid_compress].
+//! With a password, blocks are encrypted with AES256, and so is the header with [SevenZCfg::set_encrypt_headers].
+//! With a volume size, `compress_f` splits the archive into `name.001`, `name.002`, ...
+//!
+//! Headers are checked before their counts are trusted, and the memory of decoding can be limited,
+//! see [SevenZCfg::set_max_memory]. Independent blocks are decoded in parallel, see [SevenZCfg::set_threads].
 //! 
 //! # View Operation
 //! 
-//! [todo]
+//! [crate::view::Archive] lists the entries of an archive from its header without decoding any block,
+//! an encrypted header needs its password. Files in a solid block have no compressed size of their own.
+//! 
+//! # Comments
+//! 
+//! 7z archives have no comments: the format has no place for a comment of the archive,
+//! and 7-Zip neither writes nor reads comments of entries, so [SevenZCfg] can't set any.
 //! 
 
-use crate::comde::{CompressStatus, DecompressStatus};
+use crate::comde::{CompressStatus, DecompressStatus, EntryStat, Sink};
+use std::collections::{BTreeMap, HashMap};
 use crate::error::ComdeError;
+use crate::password::{self, PasswordProvider};
+use crate::preset::{self, Preset};
 use crate::utils;
+use crate::vfs::{RealFs, Vfs};
 use std::io::{Read, Write};
 use std::io::Seek;
-use std::path::Path;
+use std::path::{Component, Path};
+use std::cell::Cell;
+use std::rc::Rc;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::time::Instant;
 
-pub struct SevenZComde;
+mod header;
 
-fn todo() {
-    todo!("View to be implemented");
-    todo!("is_password_needed to be implemented. - need View");
-}
+#[derive(Clone, Copy)]
+pub struct SevenZComde;
 
 impl SevenZComde {
 
@@ -36,31 +55,144 @@ impl SevenZComde {
         output: W,
         config: &SevenZCfg 
     ) -> Result<CompressStatus, ComdeError> {
-        let cw = utils::CountingWriter::new(output);
-        let mut writer = sevenz_rust2::ArchiveWriter::new(cw.share()).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
+        self.compress_vfs(&RealFs, input, output, config)
+    }
+
+    /// Compress files of a [Vfs] to an archive and then write into a writer.
+    /// In solid mode, files are opened one by one while the block is being compressed.
+    pub fn compress_vfs<V: Vfs + ?Sized, P: AsRef<Path>, W: Write + Seek>(
+        self,
+        vfs: &V,
+        input: &[P],
+        output: W,
+        config: &SevenZCfg 
+    ) -> Result<CompressStatus, ComdeError> {
+        let (tap, finishing) = HeaderTap::new(output);
+        let mut writer = sevenz_rust2::ArchiveWriter::new(tap).map_err(ComdeError::from)?;
+
+        let time_begin = Instant::now();
+        let size_origin = utils::size_of_inarray_in(vfs, input).map_err(ComdeError::from)?;
+
+        let aes = config.aes_options();
+        config.apply_to(&mut writer, aes.as_ref());
+        let (mut entries, skipped) = utils::collect_entries(vfs, input).map_err(ComdeError::from)?;
+        if config.reproducible {
+            utils::make_reproducible(&mut entries);
+        }
+        let mut stats = Vec::with_capacity(entries.len());
+        let mut stored = 0;
+        if config.solid_compress {
+            // Files compressed with the same methods go into the same solid blocks.
+            let mut groups: BTreeMap<MethodGroup, Vec<&utils::SourceEntry>> = BTreeMap::new();
+            for entry in &entries {
+                if entry.is_dir {
+                    writer.push_archive_entry::<&[u8]>(archive_entry(entry), None).map_err(ComdeError::from)?;
+                    stats.push(EntryStat::new(&entry.name, true, 0, None));
+                } else {
+                    let group = config.group_for(&entry.name, || utils::sample_in(vfs, &entry.path))?;
+                    stored += usize::from(group == MethodGroup::Stored);
+                    groups.entry(group).or_default().push(entry);
+                }
+            }
+            for (group, files) in groups {
+                for block in config.solid_blocks(files, |entry| (&entry.name, entry.metadata.len)) {
+                    writer.set_content_methods(config.methods(group, aes.as_ref()));
+                    push_solid_block(&mut writer, vfs, &block, &mut stats)?;
+                }
+            }
+        } else {
+            for entry in &entries {
+                let reader = if entry.is_dir {
+                    None
+                } else {
+                    let group = config.group_for(&entry.name, || utils::sample_in(vfs, &entry.path))?;
+                    stored += usize::from(group == MethodGroup::Stored);
+                    writer.set_content_methods(config.methods(group, aes.as_ref()));
+                    Some(vfs.open(&entry.path).map_err(|e| ComdeError::io(&entry.path, e))?)
+                };
+                let written = writer.push_archive_entry(archive_entry(entry), reader).map_err(ComdeError::from)?;
+                stats.push(written_stat(written));
+            }
+        }
+        let size_compressed = finish_archive(writer, &finishing, config, aes.as_ref())?;
+        let time_finished = time_begin.elapsed();
+
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats).with_skipped(skipped).with_stored(stored))
+    }
+
+    /// Compress files in memory, given as pairs of name and data,
+    /// to an archive and then write into a writer.
+    pub fn compress_mem<N: AsRef<str>, D: AsRef<[u8]>, W: Write + Seek>(
+        self,
+        input: &[(N, D)],
+        output: W,
+        config: &SevenZCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let (tap, finishing) = HeaderTap::new(output);
+        let mut writer = sevenz_rust2::ArchiveWriter::new(tap).map_err(ComdeError::from)?;
 
         let time_begin = Instant::now();
-        let size_origin = utils::size_of_inarray(&input).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
+        let size_origin = input.iter().map(|(_, data)| data.as_ref().len() as u64).sum();
 
-        writer.set_content_methods(config.configs.clone());
+        let aes = config.aes_options();
+        config.apply_to(&mut writer, aes.as_ref());
+        let mut input = input.iter().collect::<Vec<_>>();
+        if config.reproducible {
+            input.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+        }
+        let mem_entry = |name: &str| {
+            let mut entry = sevenz_rust2::ArchiveEntry::new_file(name);
+            if config.reproducible {
+                if let Ok(date) = sevenz_rust2::NtTime::try_from(utils::reproducible_mtime()) {
+                    entry.last_modified_date = date;
+                    entry.has_last_modified_date = true;
+                }
+                entry.windows_attributes = windows_attributes(false, 0o644);
+                entry.has_windows_attributes = true;
+            }
+            entry
+        };
+        let sample = |data: &D| Ok(data.as_ref()[..data.as_ref().len().min(utils::INCOMPRESSIBLE_SAMPLE_SIZE)].to_vec());
+        let mut stats = Vec::with_capacity(input.len());
+        let mut stored = 0;
         if config.solid_compress {
-            for p in input {
-                writer.push_source_path(p, |_| true).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
+            let mut groups: BTreeMap<MethodGroup, Vec<&(N, D)>> = BTreeMap::new();
+            for item in input {
+                let group = config.group_for(item.0.as_ref(), || sample(&item.1))?;
+                stored += usize::from(group == MethodGroup::Stored);
+                groups.entry(group).or_default().push(item);
+            }
+            for (group, files) in groups {
+                for block in config.solid_blocks(files, |(name, data)| (name.as_ref(), data.as_ref().len() as u64)) {
+                    writer.set_content_methods(config.methods(group, aes.as_ref()));
+                    if let [(name, data)] = block[..] {
+                        let written = writer.push_archive_entry(mem_entry(name.as_ref()), Some(data.as_ref())).map_err(ComdeError::from)?;
+                        stats.push(written_stat(written));
+                        continue;
+                    }
+                    let entries = block.iter().map(|(name, _)| mem_entry(name.as_ref())).collect();
+                    let readers = block.iter().map(|(_, data)| data.as_ref().into()).collect();
+                    writer.push_archive_entries(entries, readers).map_err(ComdeError::from)?;
+                    stats.extend(block.iter().map(|(name, data)| EntryStat::new(name.as_ref(), false, data.as_ref().len() as u64, None)));
+                }
             }
         } else {
-            for p in input {
-                writer.push_source_path_non_solid(p, |_| true).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
+            for (name, data) in input {
+                let group = config.group_for(name.as_ref(), || sample(data))?;
+                stored += usize::from(group == MethodGroup::Stored);
+                writer.set_content_methods(config.methods(group, aes.as_ref()));
+                let written = writer.push_archive_entry(mem_entry(name.as_ref()), Some(data.as_ref())).map_err(ComdeError::from)?;
+                stats.push(written_stat(written));
             }
         }
-        writer.finish().map_err(|e| ComdeError::SevenZError(format!("e: {}\n {}", e.to_string(), "Writing files error.")))?;
-        
-        let size_compressed = cw.bytes_written();
+        let size_compressed = finish_archive(writer, &finishing, config, aes.as_ref())?;
         let time_finished = time_begin.elapsed();
 
-        Ok(CompressStatus::new(size_origin, size_compressed, time_finished))
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats).with_stored(stored))
     }
 
     /// Compress files to an archive and then write into a file of given path.
+    /// If a volume size is set, the archive is split into `output.001`, `output.002`, ...
     pub fn compress_f<P: AsRef<Path>>(
         self,
         input: &[P],
@@ -68,29 +200,214 @@ impl SevenZComde {
         config: &SevenZCfg,
     ) -> Result<CompressStatus, ComdeError> {
         let output  = output.as_ref();
-        let out_file = std::fs::File::create(output).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
+        if let Some(volume_size) = config.volume_size {
+            let out_volumes = utils::VolumeWriter::new(output, volume_size).map_err(|e| ComdeError::io(output, e))?;
+            return self.compress(input, out_volumes, config);
+        }
+        let out_file = std::fs::File::create(output).map_err(|e| ComdeError::io(output, e))?;
         self.compress(input, out_file, config)
     }
 
+    /// Compress files to an archive and then write into a writer that can't seek,
+    /// such as a pipe or stdout.
+    /// As 7z writes its header at the beginning at last, the archive is built in a temporary file first.
+    pub fn compress_stream<P: AsRef<Path>, W: Write>(
+        self,
+        input: &[P],
+        mut output: W,
+        config: &SevenZCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let mut spool = tempfile::tempfile().map_err(ComdeError::from)?;
+        let status = self.compress(input, &mut spool, config)?;
+        spool.seek(std::io::SeekFrom::Start(0)).map_err(ComdeError::from)?;
+        std::io::copy(&mut spool, &mut output).map_err(ComdeError::from)?;
+        output.flush().map_err(ComdeError::from)?;
+        Ok(status)
+    }
+
     /// Decompress an archive from a reader and write into a file.
-    pub fn decompress<P: AsRef<Path>, R: Read + Seek>(
+    /// With more than one thread, independent blocks are extracted in parallel.
+    /// If the password is missing or wrong, the password provider is asked and the archive is read again.
+    pub fn decompress<P: AsRef<Path>, R: Read + Seek + Send>(
+        self,
+        mut input: R,
+        output: P,
+        config: &SevenZCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let output = output.as_ref();
+        let start = input.stream_position().map_err(ComdeError::from)?;
+        config.retry_password(|config| {
+            input.seek(std::io::SeekFrom::Start(start)).map_err(ComdeError::from)?;
+            if config.threads > 1 {
+                self.decompress_parallel(&mut input, output, config)
+            } else {
+                self.decompress_serial(&mut input, output, config)
+            }
+        })
+    }
+
+    /// Decompress the blocks one by one.
+    fn decompress_serial<P: AsRef<Path>, R: Read + Seek>(
+        self,
+        input: R,
+        output: P,
+        config: &SevenZCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let cr = utils::CountingReader::new(input);
+        let time_begin = Instant::now();
+
+        let output = output.as_ref();
+        let mut source = cr.share();
+        let archive = read_archive(&mut source, config, 1)?;
+        let stats = archive_stats(&archive);
+        check_enclosed(&archive)?;
+        std::fs::create_dir_all(output).map_err(|e| ComdeError::io(output, e))?;
+        for_each_entry(&archive, &mut source, config, |entry, data| {
+            sevenz_rust2::default_entry_extract_fn(entry, data, &output.join(entry.name()))
+        })?;
+        let size_read = cr.bytes_read();
+        let size_decompressed = stats.iter().map(|s| s.size()).sum();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats))
+    }
+
+    /// Decompress each block on its own thread, every thread reads the input with its own position.
+    fn decompress_parallel<P: AsRef<Path>, R: Read + Seek + Send>(
         self,
         input: R,
         output: P,
         config: &SevenZCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let sr = utils::SharedReader::new(input).map_err(ComdeError::from)?;
+        let time_begin = Instant::now();
+
+        let output = output.as_ref();
+        let archive = read_archive(&mut sr.share(), config, config.threads)?;
+        check_enclosed(&archive)?;
+        std::fs::create_dir_all(output).map_err(|e| ComdeError::io(output, e))?;
+        let mut extract = |entry: &sevenz_rust2::ArchiveEntry, reader: &mut dyn Read| {
+            sevenz_rust2::default_entry_extract_fn(entry, reader, &output.join(entry.name()))
+        };
+
+        // Directories and empty files are not in any block.
+        for (file, block) in archive.files.iter().zip(&archive.stream_map.file_block_index) {
+            if block.is_none() {
+                extract(file, &mut std::io::empty()).map_err(ComdeError::from)?;
+            }
+        }
+
+        let next = AtomicUsize::new(0);
+        // Set when a worker fails, so the others stop before their next block.
+        let stop = AtomicBool::new(false);
+        let workers = config.threads.min(archive.blocks.len()).max(1);
+        // With fewer blocks than threads, the threads left decode LZMA2 blocks on many threads.
+        let block_threads = match config.max_memory {
+            Some(_) => 1,
+            None => (config.threads / workers).max(1) as u32,
+        };
+        let results = std::thread::scope(|scope| {
+            let workers = (0..workers).map(|_| {
+                let mut source = sr.share();
+                let (archive, next, stop) = (&archive, &next, &stop);
+                scope.spawn(move || {
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= archive.blocks.len() || stop.load(Ordering::Relaxed) {
+                            return Ok(());
+                        }
+                        if let Err(e) = decode_block(archive, index, block_threads, &config.password, &mut source, &mut extract) {
+                            stop.store(true, Ordering::Relaxed);
+                            return Err(e);
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+            workers.into_iter().map(|w| w.join().unwrap_or(Err(ComdeError::SevenZError("Extracting thread panicked.".to_string())))).collect::<Vec<_>>()
+        });
+        results.into_iter().collect::<Result<(), ComdeError>>()?;
+
+        let stats = archive_stats(&archive);
+        let size_read = sr.bytes_read();
+        let size_decompressed = stats.iter().map(|s| s.size()).sum();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats))
+    }
+
+    /// Decompress an archive from a reader that can't seek, such as a pipe or stdin,
+    /// and write into a file.
+    /// As 7z keeps its header at the end, the archive is copied into a temporary file first.
+    pub fn decompress_stream<P: AsRef<Path>, R: Read>(
+        self,
+        mut input: R,
+        output: P,
+        config: &SevenZCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let mut spool = tempfile::tempfile().map_err(ComdeError::from)?;
+        std::io::copy(&mut input, &mut spool).map_err(ComdeError::from)?;
+        spool.seek(std::io::SeekFrom::Start(0)).map_err(ComdeError::from)?;
+        self.decompress(spool, output, config)
+    }
+
+    /// Decompress an archive from a reader and pass its entries into a sink.
+    /// If the password is missing or wrong, the password provider is asked and the archive is read again,
+    /// so the sink may receive some entries again.
+    pub fn decompress_into<R: Read + Seek, S: Sink>(
+        self,
+        mut input: R,
+        sink: &mut S,
+        config: &SevenZCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let start = input.stream_position().map_err(ComdeError::from)?;
+        config.retry_password(|config| {
+            input.seek(std::io::SeekFrom::Start(start)).map_err(ComdeError::from)?;
+            self.decompress_into_once(&mut input, sink, config)
+        })
+    }
+
+    fn decompress_into_once<R: Read + Seek, S: Sink>(
+        self,
+        input: R,
+        sink: &mut S,
+        config: &SevenZCfg
     ) -> Result<DecompressStatus, ComdeError> {
         let cr = utils::CountingReader::new(input);
         let time_begin = Instant::now();
 
-        sevenz_rust2::decompress_with_password(cr.share(), &output, config.password.clone()).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
+        let mut size_decompressed = 0;
+        let mut source = cr.share();
+        let archive = read_archive(&mut source, config, 1)?;
+        let stats = archive_stats(&archive);
+        for_each_entry(&archive, &mut source, config, |entry, data| {
+            if entry.is_directory() {
+                sink.dir(entry.name())?;
+            } else {
+                let counted = utils::CountingReader::new(data);
+                sink.file(entry.name(), &mut counted.share())?;
+                size_decompressed += counted.bytes_read();
+            }
+            Ok(true)
+        })?;
         let size_read = cr.bytes_read();
-        let size_decompressed = utils::size_of(output).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
         let time_finished = time_begin.elapsed();
 
-        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished))
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats))
+    }
+
+    /// Decompress an archive from a reader into memory, files are keyed by their names.
+    pub fn decompress_mem<R: Read + Seek>(
+        self,
+        input: R,
+        config: &SevenZCfg
+    ) -> Result<HashMap<String, Vec<u8>>, ComdeError> {
+        let mut files = HashMap::new();
+        self.decompress_into(input, &mut files, config)?;
+        Ok(files)
     }
 
     /// Decompress an archive of given path and write into a file.
+    /// Given the first part `name.7z.001` of a volume set, all parts are read in turn.
     pub fn decompress_f<P: AsRef<Path>>(
         self,
         input: P,
@@ -98,15 +415,639 @@ impl SevenZComde {
         config: &SevenZCfg,
     ) -> Result<DecompressStatus, ComdeError> {
         let input = input.as_ref();
-        let in_file = std::fs::File::open(input).map_err(|e| ComdeError::SevenZError(e.to_string()))?;
-        self.decompress(in_file, output, config)
+        let in_volumes = utils::VolumeReader::open(input).map_err(|e| ComdeError::io(input, e))?;
+        self.decompress(in_volumes, output, config)
+    }
+}
+
+/// Read the header of the archive `input`, which must fit in the memory limit of `config`,
+/// with the blocks of it that `threads` decode at the same time.
+fn read_archive<R: Read + Seek>(input: &mut R, config: &SevenZCfg, threads: usize) -> Result<sevenz_rust2::Archive, ComdeError> {
+    let archive = header::read_archive(input, &config.password, config.max_memory)?;
+    if let Some(limit) = config.max_memory {
+        let mut memory = archive.blocks.iter().map(header::block_memory).collect::<Vec<_>>();
+        memory.sort_unstable_by(|a, b| b.cmp(a));
+        header::check(memory.iter().take(threads.max(1)).fold(0, |sum, &m| sum.saturating_add(m)), limit)?;
+    }
+    Ok(archive)
+}
+
+/// Pass the entries of all blocks and then those without data into `each`, stopping when it returns `false`.
+fn for_each_entry<R: Read + Seek>(
+    archive: &sevenz_rust2::Archive,
+    source: &mut R,
+    config: &SevenZCfg,
+    mut each: impl FnMut(&sevenz_rust2::ArchiveEntry, &mut dyn Read) -> Result<bool, sevenz_rust2::Error>,
+) -> Result<(), ComdeError> {
+    // A block of LZMA2 compressed on many threads is decoded on the threads of the config, unless the memory is limited.
+    let threads = match config.max_memory {
+        Some(_) => 1,
+        None => config.threads.max(1) as u32,
+    };
+    for index in 0..archive.blocks.len() {
+        if !decode_block(archive, index, threads, &config.password, source, &mut each)? {
+            return Ok(());
+        }
+    }
+    for (file, block) in archive.files.iter().zip(&archive.stream_map.file_block_index) {
+        if block.is_none() && !each(file, &mut std::io::empty()).map_err(ComdeError::from)? {
+            return Ok(());
+        }
+    }
+    Ok(())
+}
+
+/// Pass the entries of block `index` into `each`, return `false` if it stopped.
+/// The password is only given when the block is encrypted, so data errors of other blocks stay as they are.
+fn decode_block<R: Read + Seek, F: FnMut(&sevenz_rust2::ArchiveEntry, &mut dyn Read) -> Result<bool, sevenz_rust2::Error>>(
+    archive: &sevenz_rust2::Archive,
+    index: usize,
+    threads: u32,
+    password: &sevenz_rust2::Password,
+    source: &mut R,
+    each: &mut F,
+) -> Result<bool, ComdeError> {
+    let encrypted = archive.blocks[index].coders.iter().any(|coder| coder.encoder_method_id() == sevenz_rust2::EncoderMethod::ID_AES256_SHA256);
+    let empty = sevenz_rust2::Password::empty();
+    sevenz_rust2::BlockDecoder::new(threads, index, archive, if encrypted { password } else { &empty }, source)
+        .for_each_entries(each)
+        .map_err(|e| wrong_password_if(encrypted && !password.is_empty(), e.into()))
+}
+
+/// Take data errors as a wrong password if `encrypted`, as AES decrypts with any password,
+/// and the wrong one only shows up as broken data later.
+fn wrong_password_if(encrypted: bool, e: ComdeError) -> ComdeError {
+    match e {
+        ComdeError::Corrupt { .. } if encrypted => ComdeError::WrongPassword,
+        ComdeError::Io { path: None, source } if encrypted && source.kind() == std::io::ErrorKind::InvalidData => ComdeError::WrongPassword,
+        e => e,
+    }
+}
+
+/// Solid blocks are split before they grow over 4 GiB, whatever the size limit set.
+const MAX_SOLID_BLOCK_SIZE: u64 = 4 * 1024 * 1024 * 1024;
+
+/// Get the lowercase extension of an entry name, empty if it has none.
+fn extension_of(name: &str) -> String {
+    let file_name = name.rsplit('/').next().unwrap_or(name);
+    match file_name.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => extension.to_ascii_lowercase(),
+        _ => String::new(),
+    }
+}
+
+/// Build the 7z entry of a source entry, with its modified time and permissions.
+fn archive_entry(entry: &utils::SourceEntry) -> sevenz_rust2::ArchiveEntry {
+    let mut archive_entry = if entry.is_dir {
+        sevenz_rust2::ArchiveEntry::new_directory(&entry.name)
+    } else {
+        sevenz_rust2::ArchiveEntry::new_file(&entry.name)
+    };
+    if let Some(date) = entry.metadata.modified.and_then(|t| sevenz_rust2::NtTime::try_from(t).ok()) {
+        archive_entry.last_modified_date = date;
+        archive_entry.has_last_modified_date = u64::from(date) > 0;
+    }
+    if let Some(mode) = entry.metadata.mode {
+        archive_entry.windows_attributes = windows_attributes(entry.is_dir, mode);
+        archive_entry.has_windows_attributes = true;
+    }
+    archive_entry
+}
+
+/// Get the attributes of an entry as p7zip writes them:
+/// the Windows ones, and the Unix `mode` in the high 16 bits flagged by the Unix extension bit.
+fn windows_attributes(is_dir: bool, mode: u32) -> u32 {
+    const READ_ONLY: u32 = 0x1;
+    const DIRECTORY: u32 = 0x10;
+    const ARCHIVE: u32 = 0x20;
+    const UNIX_EXTENSION: u32 = 0x8000;
+    let (attributes, kind) = if is_dir { (DIRECTORY, 0o040000) } else { (ARCHIVE, 0o100000) };
+    let read_only = if mode & 0o200 == 0 { READ_ONLY } else { 0 };
+    attributes | read_only | UNIX_EXTENSION | ((kind | mode & 0o7777) << 16)
+}
+
+/// Compress files into one solid block, and push their stats.
+/// A single file is pushed on its own, which is the same block without the substream info.
+fn push_solid_block<V: Vfs + ?Sized, W: Write + Seek>(
+    writer: &mut sevenz_rust2::ArchiveWriter<W>,
+    vfs: &V,
+    block: &[&utils::SourceEntry],
+    stats: &mut Vec<EntryStat>,
+) -> Result<(), ComdeError> {
+    if let [entry] = block {
+        let reader = vfs.open(&entry.path).map_err(|e| ComdeError::io(&entry.path, e))?;
+        let written = writer.push_archive_entry(archive_entry(entry), Some(reader)).map_err(ComdeError::from)?;
+        stats.push(written_stat(written));
+        return Ok(());
+    }
+    let archive_entries = block.iter().map(|entry| archive_entry(entry)).collect();
+    let readers = block.iter().map(|entry| LazyReader { vfs, path: &entry.path, reader: None, done: false }.into()).collect();
+    writer.push_archive_entries(archive_entries, readers).map_err(ComdeError::from)?;
+    stats.extend(block.iter().map(|entry| EntryStat::new(&entry.name, false, entry.metadata.len, None)));
+    Ok(())
+}
+
+/// Get the stats of an entry written on its own.
+fn written_stat(entry: &sevenz_rust2::ArchiveEntry) -> EntryStat {
+    let compressed_size = entry.has_stream.then_some(entry.compressed_size);
+    EntryStat::new(entry.name(), entry.is_directory(), entry.size, compressed_size)
+}
+
+/// Refuse archives with entries that would be written outside of the output directory.
+fn check_enclosed(archive: &sevenz_rust2::Archive) -> Result<(), ComdeError> {
+    for file in &archive.files {
+        let enclosed = Path::new(file.name()).components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+        if !enclosed {
+            return Err(ComdeError::PathTraversal { entry: file.name().to_string() });
+        }
+    }
+    Ok(())
+}
+
+/// Get the stats of all entries in an archive.
+/// The compressed size is known only for files that have a block of their own.
+fn archive_stats(archive: &sevenz_rust2::Archive) -> Vec<EntryStat> {
+    let mut files_in_block = vec![0; archive.blocks.len()];
+    for (file, block) in archive.files.iter().zip(&archive.stream_map.file_block_index) {
+        if let Some(b) = block && file.has_stream {
+            files_in_block[*b] += 1;
+        }
+    }
+    archive.files.iter().zip(&archive.stream_map.file_block_index).map(|(file, block)| {
+        let alone = file.has_stream && block.is_some_and(|b| files_in_block[b] == 1);
+        EntryStat::new(file.name(), file.is_directory(), file.size, alone.then_some(file.compressed_size))
+    }).collect()
+}
+
+/// The ID of the header property, which a header written as it is starts with.
+const K_HEADER: u8 = 0x01;
+/// The size of the signature and the start header at the beginning of an archive.
+const SIGNATURE_HEADER_SIZE: usize = 32;
+
+/// Finish the archive, and encrypt its header if it's to be encrypted but the writer left it as it is.
+/// The writer keeps the header unencrypted when compressing it doesn't make it smaller enough,
+/// which is common for archives with a few entries.
+/// Return the size of the archive.
+fn finish_archive<W: Write + Seek>(
+    mut writer: sevenz_rust2::ArchiveWriter<HeaderTap<W>>,
+    finishing: &Cell<bool>,
+    config: &SevenZCfg,
+    aes: Option<&sevenz_rust2::encoder_options::AesEncoderOptions>,
+) -> Result<u64, ComdeError> {
+    // The writer encrypts an encoded header with the AES256 coder of the last methods set,
+    // so set them again for the header to have its own IV.
+    if aes.is_some() {
+        writer.set_content_methods(config.methods(MethodGroup::Default, aes));
+    }
+    finishing.set(true);
+    let tap = writer.finish().map_err(ComdeError::from)?;
+    let end = tap.tail_start.unwrap_or(tap.position) + tap.tail.len() as u64;
+    if !config.encrypt_headers || config.password.is_empty() {
+        return Ok(end);
+    }
+    let aes = sevenz_rust2::encoder_options::AesEncoderOptions::new(config.password.clone());
+    // An encoded header is written after its packed stream, so a plain header starts the tail.
+    let next_header_offset = tap.start_header.get(12..20).and_then(|b| b.try_into().ok()).map(u64::from_le_bytes);
+    let plain = tap.tail_start.is_some_and(|start| next_header_offset == Some(start - SIGNATURE_HEADER_SIZE as u64))
+        && tap.tail.first() == Some(&K_HEADER);
+    match tap.tail_start {
+        Some(header_pos) if plain => encrypt_header(tap.inner, header_pos, &tap.tail, aes),
+        _ => Ok(end),
+    }
+}
+
+/// Replace the plain `header` at `header_pos` with the header encrypted by AES256 alone,
+/// and point the start header to it. Return the new size of the archive.
+fn encrypt_header<W: Write + Seek>(
+    mut out: W,
+    header_pos: u64,
+    header: &[u8],
+    aes: sevenz_rust2::encoder_options::AesEncoderOptions,
+) -> Result<u64, ComdeError> {
+    const AES_ID: [u8; 4] = [0x06, 0xF1, 0x07, 0x01];
+
+    // Let a writer in memory encrypt the header as an entry, its packed stream follows the start header.
+    let mut sealer = sevenz_rust2::ArchiveWriter::new(std::io::Cursor::new(Vec::new())).map_err(ComdeError::from)?;
+    sealer.set_content_methods(vec![aes.clone().into()]);
+    let packed_size = sealer.push_archive_entry(sevenz_rust2::ArchiveEntry::new_file("header"), Some(header))
+        .map_err(ComdeError::from)?
+        .compressed_size as usize;
+    let sealed = sealer.finish().map_err(ComdeError::from)?.into_inner();
+    let packed = &sealed[SIGNATURE_HEADER_SIZE..SIGNATURE_HEADER_SIZE + packed_size];
+
+    let mut properties = vec![(aes.num_cycles_power & 0x3F) | 0xC0, 0xFF];
+    properties.extend_from_slice(&aes.salt);
+    properties.extend_from_slice(&aes.iv);
+
+    // An encoded header of one block with the AES256 coder only.
+    // The CRC goes with the block, so the reader checks it and tells a wrong password.
+    let mut encoded = vec![0x17, 0x06];
+    write_number(&mut encoded, header_pos - SIGNATURE_HEADER_SIZE as u64);
+    write_number(&mut encoded, 1);
+    encoded.push(0x09);
+    write_number(&mut encoded, packed.len() as u64);
+    encoded.extend_from_slice(&[0x00, 0x07, 0x0B, 0x01, 0x00, 0x01, 0x20 | AES_ID.len() as u8]);
+    encoded.extend_from_slice(&AES_ID);
+    write_number(&mut encoded, properties.len() as u64);
+    encoded.extend_from_slice(&properties);
+    encoded.push(0x0C);
+    write_number(&mut encoded, header.len() as u64);
+    encoded.extend_from_slice(&[0x0A, 0x01]);
+    encoded.extend_from_slice(&crc32fast::hash(header).to_le_bytes());
+    encoded.extend_from_slice(&[0x00, 0x00]);
+
+    out.seek(std::io::SeekFrom::Start(header_pos)).map_err(ComdeError::from)?;
+    out.write_all(packed).map_err(ComdeError::from)?;
+    out.write_all(&encoded).map_err(ComdeError::from)?;
+
+    let mut start_header = Vec::with_capacity(20);
+    start_header.extend_from_slice(&(header_pos + packed.len() as u64 - SIGNATURE_HEADER_SIZE as u64).to_le_bytes());
+    start_header.extend_from_slice(&(encoded.len() as u64).to_le_bytes());
+    start_header.extend_from_slice(&crc32fast::hash(&encoded).to_le_bytes());
+    out.seek(std::io::SeekFrom::Start(8)).map_err(ComdeError::from)?;
+    out.write_all(&crc32fast::hash(&start_header).to_le_bytes()).map_err(ComdeError::from)?;
+    out.write_all(&start_header).map_err(ComdeError::from)?;
+    out.flush().map_err(ComdeError::from)?;
+    Ok(header_pos + (packed.len() + encoded.len()) as u64)
+}
+
+/// Write a number in the variable length of 7z, where the leading 1 bits of the first byte
+/// tell how many bytes follow it in little endian.
+fn write_number(out: &mut Vec<u8>, value: u64) {
+    let mut first = 0u8;
+    let mut mask = 0x80u8;
+    let mut extra = 0;
+    while extra < 8 {
+        if value < 1 << (7 * (extra + 1)) {
+            first |= (value >> (8 * extra)) as u8;
+            break;
+        }
+        first |= mask;
+        mask >>= 1;
+        extra += 1;
+    }
+    out.push(first);
+    out.extend_from_slice(&value.to_le_bytes()[..extra]);
+}
+
+/// Passes writes through to `inner`, and once `finishing` is set,
+/// keeps a copy of what's written at the end of the archive, where the header goes.
+struct HeaderTap<W> {
+    inner: W,
+    position: u64,
+    finishing: Rc<Cell<bool>>,
+    start_header: Vec<u8>,
+    tail_start: Option<u64>,
+    tail: Vec<u8>,
+}
+
+impl <W> HeaderTap<W> {
+    /// Create a HeaderTap and the flag to set before finishing the archive.
+    fn new(inner: W) -> (Self, Rc<Cell<bool>>) {
+        let finishing = Rc::new(Cell::new(false));
+        let tap = HeaderTap { inner, position: 0, finishing: finishing.clone(), start_header: Vec::new(), tail_start: None, tail: Vec::new() };
+        (tap, finishing)
+    }
+}
+
+impl <W: Write> Write for HeaderTap<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        if self.finishing.get() {
+            // The start header is written at the beginning at last, pointing to the header.
+            if self.position < SIGNATURE_HEADER_SIZE as u64 {
+                if self.position == self.start_header.len() as u64 {
+                    self.start_header.extend_from_slice(&buf[..n]);
+                }
+            } else {
+                let start = *self.tail_start.get_or_insert(self.position);
+                if self.position == start + self.tail.len() as u64 {
+                    self.tail.extend_from_slice(&buf[..n]);
+                }
+            }
+        }
+        self.position += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
     }
 }
 
+impl <W: Seek> Seek for HeaderTap<W> {
+    fn seek(&mut self, pos: std::io::SeekFrom) -> std::io::Result<u64> {
+        self.position = self.inner.seek(pos)?;
+        Ok(self.position)
+    }
+}
+
+/// Opens a file of a [Vfs] at the first read and closes it at the end,
+/// so a solid block doesn't keep all its files open.
+struct LazyReader<'a, V: Vfs + ?Sized> {
+    vfs: &'a V,
+    path: &'a Path,
+    reader: Option<Box<dyn Read + Send + 'a>>,
+    done: bool,
+}
+
+impl <V: Vfs + ?Sized> Read for LazyReader<'_, V> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        if self.done {
+            return Ok(0);
+        }
+        if self.reader.is_none() {
+            self.reader = Some(self.vfs.open(self.path)?);
+        }
+        let n = self.reader.as_mut().map_or(Ok(0), |r| r.read(buf))?;
+        if n == 0 && !buf.is_empty() {
+            self.reader = None;
+            self.done = true;
+        }
+        Ok(n)
+    }
+}
+
+#[cfg(feature = "async")]
+impl SevenZComde {
+
+    /// Async version of [SevenZComde::compress].
+    /// The archive is built in a temporary file on the blocking thread pool,
+    /// and then copied into the writer.
+    pub async fn compress_async<P: AsRef<Path>, W: tokio::io::AsyncWrite + Unpin>(
+        self,
+        input: &[P],
+        mut output: W,
+        config: &SevenZCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let input = input.iter().map(|p| p.as_ref().to_path_buf()).collect::<Vec<_>>();
+        let config = config.clone();
+        let (status, spool) = utils::spawn_blocking(move || {
+            let mut spool = tempfile::tempfile().map_err(ComdeError::from)?;
+            let status = self.compress(&input, &mut spool, &config)?;
+            Ok::<_, ComdeError>((status, spool))
+        }).await.map_err(ComdeError::from)??;
+        utils::unspool_async_write(spool, &mut output).await.map_err(ComdeError::from)?;
+        Ok(status)
+    }
+
+    /// Async version of [SevenZComde::compress_f], running on the blocking thread pool.
+    pub async fn compress_f_async<P: AsRef<Path>>(
+        self,
+        input: &[P],
+        output: P,
+        config: &SevenZCfg,
+    ) -> Result<CompressStatus, ComdeError> {
+        let input = input.iter().map(|p| p.as_ref().to_path_buf()).collect::<Vec<_>>();
+        let output = output.as_ref().to_path_buf();
+        let config = config.clone();
+        utils::spawn_blocking(move || self.compress_f(&input, output, &config))
+            .await.map_err(ComdeError::from)?
+    }
+
+    /// Async version of [SevenZComde::decompress].
+    /// The reader is spooled into a temporary file first, as 7z archives need seeking.
+    pub async fn decompress_async<P: AsRef<Path>, R: tokio::io::AsyncRead + Unpin>(
+        self,
+        mut input: R,
+        output: P,
+        config: &SevenZCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let spool = utils::spool_async_read(&mut input).await.map_err(ComdeError::from)?;
+        let output = output.as_ref().to_path_buf();
+        let config = config.clone();
+        utils::spawn_blocking(move || self.decompress(spool, output, &config))
+            .await.map_err(ComdeError::from)?
+    }
+
+    /// Async version of [SevenZComde::decompress_f], running on the blocking thread pool.
+    pub async fn decompress_f_async<P: AsRef<Path>>(
+        self,
+        input: P,
+        output: P,
+        config: &SevenZCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let input = input.as_ref().to_path_buf();
+        let output = output.as_ref().to_path_buf();
+        let config = config.clone();
+        utils::spawn_blocking(move || self.decompress_f(input, output, &config))
+            .await.map_err(ComdeError::from)?
+    }
+}
+
+#[derive(Clone)]
 pub struct SevenZCfg {
     solid_compress: bool,
+    solid_block_size: Option<u64>,
+    solid_block_files: Option<usize>,
+    solid_by_extension: bool,
     password: sevenz_rust2::Password,
-    configs: Vec<sevenz_rust2::EncoderConfiguration>
+    password_provider: Option<Arc<dyn PasswordProvider>>,
+    password_attempts: usize,
+    encrypt_headers: bool,
+    volume_size: Option<u64>,
+    threads: usize,
+    reproducible: bool,
+    filters: Vec<Filter>,
+    rules: Vec<MethodRule>,
+    detect_incompressible: bool,
+    max_memory: Option<u64>,
+    configs: Vec<sevenz_rust2::EncoderConfiguration>,
+    /// The bytes of memory the method of `configs` takes to compress, about.
+    method_memory: u64,
+    /// The level of LZMA2 if it's the method and set by a level, so threads can be changed keeping it.
+    lzma2_level: Option<u32>,
+}
+
+/// Files matching `pattern` are compressed with `filters` and `configs` instead.
+#[derive(Clone)]
+struct MethodRule {
+    pattern: String,
+    filters: Vec<Filter>,
+    configs: Vec<sevenz_rust2::EncoderConfiguration>,
+    method_memory: u64,
+}
+
+/// Which methods files are compressed with, files of different groups never share a solid block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+enum MethodGroup {
+    Default,
+    Rule(usize),
+    /// Stored without compression as they look incompressible.
+    Stored,
+}
+
+/// # Filter
+/// A filter that data passes through before the compress method,
+/// making it easier to compress without changing its size.
+/// BCJ filters convert addresses in the machine code of executables for the given CPU.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Filter {
+    BcjX86,
+    BcjArm,
+    BcjArmThumb,
+    BcjArm64,
+    BcjPpc,
+    BcjSparc,
+    /// Delta with a distance in bytes, range to 1 - 256 .
+    /// Good for data of fixed-size samples, such as uncompressed audio or images.
+    Delta(u32),
+}
+
+impl From<Filter> for sevenz_rust2::EncoderConfiguration {
+    fn from(filter: Filter) -> Self {
+        use sevenz_rust2::EncoderMethod;
+        match filter {
+            Filter::BcjX86 => EncoderMethod::BCJ_X86_FILTER.into(),
+            Filter::BcjArm => EncoderMethod::BCJ_ARM_FILTER.into(),
+            Filter::BcjArmThumb => EncoderMethod::BCJ_ARM_THUMB_FILTER.into(),
+            Filter::BcjArm64 => EncoderMethod::BCJ_ARM64_FILTER.into(),
+            Filter::BcjPpc => EncoderMethod::BCJ_PPC_FILTER.into(),
+            Filter::BcjSparc => EncoderMethod::BCJ_SPARC_FILTER.into(),
+            Filter::Delta(distance) => sevenz_rust2::encoder_options::DeltaOptions::from_distance(distance).into(),
+        }
+    }
+}
+
+/// The match finder of LZMA2, how it looks for repeated data, which the level picks.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum MatchFinder {
+    /// Hash chain, faster, used by levels 0 - 3.
+    Hc4,
+    /// Binary tree, better ratio, used by levels 4 - 9.
+    Bt4,
+}
+
+/// # Lzma2Tuning
+/// Settings of LZMA2 finer than a level for `SevenZCfg::use_lzma2_with_tuning`:
+/// start from a level, and set the dictionary size and the threads on top of it.
+///
+/// The 7z encoder takes LZMA2 settings only as a level with a dictionary size,
+/// so the literal context, literal position and position bits, the nice length
+/// and the match finder always follow the level, and can't be set here.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Lzma2Tuning {
+    level: u32,
+    dictionary_size: Option<u32>,
+    threads: u32,
+    chunk_size: Option<u64>,
+}
+
+/// The largest dictionary 7-Zip can extract on 64-bit machines.
+const MAX_DICTIONARY_SIZE: u32 = 1536 << 20;
+
+/// Get the dictionary size of an LZMA2 level.
+fn lzma2_dictionary_size(level: u32) -> u32 {
+    const SIZES: [u32; 10] = [256 << 10, 1 << 20, 2 << 20, 4 << 20, 4 << 20, 8 << 20, 8 << 20, 16 << 20, 32 << 20, 64 << 20];
+    SIZES[level.min(9) as usize]
+}
+
+/// Get the match finder of an LZMA2 level.
+fn lzma2_match_finder(level: u32) -> MatchFinder {
+    match level {
+        0..=3 => MatchFinder::Hc4,
+        _ => MatchFinder::Bt4,
+    }
+}
+
+/// Get the bytes of memory Zstandard takes to compress, about 4 times the window of the level.
+#[cfg(feature = "sevenz-zstd")]
+fn zstd_encoder_memory(level: u32) -> u64 {
+    let window_log = match level {
+        0..=2 => 19,
+        3..=4 => 21,
+        5..=12 => 22,
+        13..=19 => 23,
+        20 => 25,
+        21 => 26,
+        _ => 27,
+    };
+    4 << window_log
+}
+
+/// Get the bytes of memory LZMA2 takes to compress, about, close to what `xz` tells for its presets.
+/// Each thread has its own encoder and a chunk of input.
+fn lzma2_encoder_memory(level: u32, dictionary: u32, threads: u32, chunk_size: u64) -> u64 {
+    let encoder = match lzma2_match_finder(level) {
+        MatchFinder::Hc4 => dictionary as u64 * 15 / 2,
+        MatchFinder::Bt4 => dictionary as u64 * 23 / 2,
+    } + (1 << 20);
+    match threads {
+        0 | 1 => encoder,
+        threads => threads as u64 * (encoder + chunk_size),
+    }
+}
+
+impl Lzma2Tuning {
+    /// Start from a level of 0 - 9, on one thread.
+    pub fn from_level(level: u32) -> Lzma2Tuning {
+        Lzma2Tuning {
+            level,
+            dictionary_size: None,
+            threads: 1,
+            chunk_size: None,
+        }
+    }
+
+    /// Set the dictionary size in bytes, range to 4 KiB - 1536 MiB .
+    /// A larger dictionary finds repeats further apart, and takes more memory to compress and extract.
+    pub fn set_dictionary_size(mut self, size: u32) -> Lzma2Tuning {
+        self.dictionary_size = Some(size);
+        self
+    }
+
+    /// Compress on `threads` threads, each one takes chunks of `chunk_size` bytes,
+    /// `None` for 3 times the dictionary. A chunk can't be smaller than the dictionary.
+    pub fn set_threads(mut self, threads: u32, chunk_size: Option<u64>) -> Lzma2Tuning {
+        self.threads = threads;
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Get the dictionary size, the one set or the one of the level.
+    pub fn dictionary_size(&self) -> u32 {
+        self.dictionary_size.unwrap_or_else(|| lzma2_dictionary_size(self.level))
+    }
+
+    /// Get the bytes of memory the settings take to compress, about.
+    fn memory(&self) -> u64 {
+        let dictionary = self.dictionary_size();
+        lzma2_encoder_memory(self.level, dictionary, self.threads, self.chunk_size.unwrap_or(dictionary as u64 * 3))
+    }
+
+    /// Check the settings and make the options of the encoder.
+    fn to_options(self) -> Result<sevenz_rust2::encoder_options::Lzma2Options, ComdeError> {
+        let invalid = |message: String| Err(ComdeError::InvalidOption(message));
+        if self.level > 9 {
+            return invalid(format!("LZMA2 level {} is out of 0 - 9.", self.level));
+        }
+        let dictionary = self.dictionary_size();
+        if !(4096..=MAX_DICTIONARY_SIZE).contains(&dictionary) {
+            return invalid(format!("LZMA2 dictionary size {} is out of 4 KiB - 1536 MiB.", utils::h_size(dictionary as u64)));
+        }
+        if self.threads == 0 {
+            return invalid("LZMA2 threads can't be 0.".to_string());
+        }
+        let chunk_size = self.chunk_size.unwrap_or(dictionary as u64 * 3);
+        if self.threads > 1 && chunk_size < dictionary as u64 {
+            return invalid(format!(
+                "LZMA2 chunk size {} is smaller than the dictionary {}.",
+                utils::h_size(chunk_size), utils::h_size(dictionary as u64),
+            ));
+        }
+        let mut options = match self.threads {
+            1 => sevenz_rust2::encoder_options::Lzma2Options::from_level(self.level),
+            threads => sevenz_rust2::encoder_options::Lzma2Options::from_level_mt(self.level, threads, chunk_size),
+        };
+        options.set_dictionary_size(dictionary);
+        Ok(options)
+    }
+}
+
+impl Default for SevenZCfg {
+    fn default() -> Self {
+        Self::new()
+    }
 }
 
 impl SevenZCfg {
@@ -122,11 +1063,35 @@ impl SevenZCfg {
     pub fn new() -> SevenZCfg {
         SevenZCfg {
             solid_compress: false,
+            solid_block_size: None,
+            solid_block_files: None,
+            solid_by_extension: false,
             password: sevenz_rust2::Password::empty(),
-            configs: vec![]
+            password_provider: None,
+            password_attempts: 0,
+            encrypt_headers: false,
+            volume_size: None,
+            threads: 1,
+            reproducible: false,
+            filters: vec![],
+            rules: vec![],
+            detect_incompressible: false,
+            max_memory: None,
+            configs: vec![],
+            method_memory: lzma2_encoder_memory(6, lzma2_dictionary_size(6), 1, 0),
+            lzma2_level: Some(6),
         }
     }
 
+    /// Switch whether to build the same archive from the same files on any machine.
+    /// Entries are sorted by name, their modified time is set to [utils::reproducible_mtime],
+    /// and permissions in their attributes are fixed to `0o755` for directories and executables and `0o644` for other files.
+    /// Archives with a password still differ, as encrypting uses a random salt.
+    pub fn set_reproducible(mut self, enabled: bool) -> SevenZCfg {
+        self.reproducible = enabled;
+        self
+    }
+
     /// Switch wheter to enable solid compress, which may make compressed file smaller,
     /// but also lead to longer compress time.
     pub fn set_solid_compress(mut self, enabled: bool) -> SevenZCfg {
@@ -134,18 +1099,255 @@ impl SevenZCfg {
         self
     }
 
+    /// Limit the bytes of files in a solid block, like `-ms=64m` of 7-Zip.
+    /// Smaller blocks compress a little worse, but extracting one file only decodes its own block.
+    /// `None` is no limit, which is the default, but blocks are still split at 4 GiB.
+    /// A file larger than the limit gets a block of its own.
+    pub fn set_solid_block_size(mut self, size: Option<u64>) -> SevenZCfg {
+        self.solid_block_size = size;
+        self
+    }
+
+    /// Limit the count of files in a solid block, like `-ms=100f` of 7-Zip.
+    /// `None` is no limit, which is the default.
+    pub fn set_solid_block_files(mut self, count: Option<usize>) -> SevenZCfg {
+        self.solid_block_files = count;
+        self
+    }
+
+    /// Switch whether to put files of each extension into their own solid blocks, like `-ms=e` of 7-Zip, off by default.
+    /// Extensions are compared ignoring ASCII case.
+    pub fn set_solid_by_extension(mut self, enabled: bool) -> SevenZCfg {
+        self.solid_by_extension = enabled;
+        self
+    }
+
+    /// Split files into solid blocks by extension and the limits, keeping their order in each block.
+    /// `info` gives the name and the size of a file.
+    fn solid_blocks<T>(&self, files: Vec<T>, info: impl Fn(&T) -> (&str, u64)) -> Vec<Vec<T>> {
+        let max_size = self.solid_block_size.map_or(MAX_SOLID_BLOCK_SIZE, |size| size.min(MAX_SOLID_BLOCK_SIZE));
+        let max_files = self.solid_block_files.unwrap_or(usize::MAX).max(1);
+        let mut by_extension: BTreeMap<String, Vec<T>> = BTreeMap::new();
+        for file in files {
+            let extension = if self.solid_by_extension { extension_of(info(&file).0) } else { String::new() };
+            by_extension.entry(extension).or_default().push(file);
+        }
+        let mut blocks = vec![];
+        for files in by_extension.into_values() {
+            let mut block: Vec<T> = vec![];
+            let mut block_size = 0;
+            for file in files {
+                let size = info(&file).1;
+                if !block.is_empty() && (block_size + size > max_size || block.len() >= max_files) {
+                    blocks.push(std::mem::take(&mut block));
+                    block_size = 0;
+                }
+                block_size += size;
+                block.push(file);
+            }
+            if !block.is_empty() {
+                blocks.push(block);
+            }
+        }
+        blocks
+    }
+
     /// Set the password for compressing or decompressing, encrypt goes with AES256.
     pub fn set_password(mut self, password: &str) -> SevenZCfg {
         self.password = sevenz_rust2::Password::new(password);
         self
     }
 
+    /// Switch whether to encrypt the header too when a password is set, off by default.
+    /// With the header encrypted, even the names of entries can't be listed without the password.
+    pub fn set_encrypt_headers(mut self, enabled: bool) -> SevenZCfg {
+        self.encrypt_headers = enabled;
+        self
+    }
+
+    /// Add a filter before the compress method, filters are applied in the order they are added.
+    /// Unlike `use_*` methods, filters are kept when the compress method is changed.
+    pub fn add_filter(mut self, filter: Filter) -> SevenZCfg {
+        self.filters.push(filter);
+        self
+    }
+
+    /// Compress files matching `pattern` with the compress method and filters of `config` instead,
+    /// such as `add_method_rule("*.jpg|*.png|*.zip", SevenZCfg::new().use_copy())`.
+    /// See [utils::matches_pattern] for patterns, the first matching rule is used.
+    /// In solid mode, files of each rule go into their own blocks.
+    pub fn add_method_rule(mut self, pattern: &str, config: SevenZCfg) -> SevenZCfg {
+        self.rules.push(MethodRule {
+            pattern: pattern.to_string(),
+            filters: config.filters,
+            configs: config.configs,
+            method_memory: config.method_memory,
+        });
+        self
+    }
+
+    /// Make the AES256 options of an archive if a password is set.
+    /// The salt is made once and shared by all blocks, so the key is derived once,
+    /// while each block gets its own IV from [SevenZCfg::methods].
+    fn aes_options(&self) -> Option<sevenz_rust2::encoder_options::AesEncoderOptions> {
+        (!self.password.is_empty()).then(|| sevenz_rust2::encoder_options::AesEncoderOptions::new(self.password.clone()))
+    }
+
+    /// Set the default methods of the writer.
+    fn apply_to<W: Write + Seek>(
+        &self,
+        writer: &mut sevenz_rust2::ArchiveWriter<W>,
+        aes: Option<&sevenz_rust2::encoder_options::AesEncoderOptions>,
+    ) {
+        writer.set_content_methods(self.methods(MethodGroup::Default, aes));
+        writer.set_encrypt_header(self.encrypt_headers);
+    }
+
+    /// Switch whether to store files without compression when they look incompressible, off by default.
+    /// The head of each file is sampled by [utils::is_incompressible], files matching a method rule are not sampled.
+    /// In solid mode, stored files go into their own blocks.
+    pub fn set_detect_incompressible(mut self, enabled: bool) -> SevenZCfg {
+        self.detect_incompressible = enabled;
+        self
+    }
+
+    /// Get the group of the file `name`, by the first matching rule,
+    /// or by the head of it from `sample` if detecting incompressible files.
+    fn group_for(&self, name: &str, sample: impl FnOnce() -> Result<Vec<u8>, ComdeError>) -> Result<MethodGroup, ComdeError> {
+        if let Some(index) = self.rules.iter().position(|rule| utils::matches_pattern(&rule.pattern, name)) {
+            return Ok(MethodGroup::Rule(index));
+        }
+        if self.detect_incompressible && utils::is_incompressible(&sample()?) {
+            return Ok(MethodGroup::Stored);
+        }
+        Ok(MethodGroup::Default)
+    }
+
+    /// Get the methods of a block of a group.
+    /// The writer takes the method next to the output first, so the chain is
+    /// AES256 if a password is set, then the compress method, then filters in reverse.
+    /// AES256 is given a new random IV on each call, as a block must not share the key and IV with another.
+    fn methods(
+        &self,
+        group: MethodGroup,
+        aes: Option<&sevenz_rust2::encoder_options::AesEncoderOptions>,
+    ) -> Vec<sevenz_rust2::EncoderConfiguration> {
+        let copy = [sevenz_rust2::EncoderMethod::COPY.into()];
+        let (filters, configs) = match group {
+            MethodGroup::Rule(index) => (&self.rules[index].filters[..], &self.rules[index].configs[..]),
+            MethodGroup::Stored => (&[][..], &copy[..]),
+            MethodGroup::Default => (&self.filters[..], &self.configs[..]),
+        };
+        let mut methods = Vec::with_capacity(configs.len() + filters.len() + 2);
+        if let Some(aes) = aes {
+            let iv = sevenz_rust2::encoder_options::AesEncoderOptions::new(sevenz_rust2::Password::empty()).iv;
+            methods.push(sevenz_rust2::encoder_options::AesEncoderOptions { iv, ..aes.clone() }.into());
+        }
+        if configs.is_empty() {
+            methods.push(sevenz_rust2::EncoderMethod::LZMA2.into());
+        }
+        methods.extend(configs.iter().cloned());
+        methods.extend(filters.iter().rev().map(|&f| f.into()));
+        methods
+    }
+
+    /// Set a provider that is asked for the password when decompressing an encrypted archive
+    /// without a password or with a wrong one, at most `attempts` times.
+    /// A wrong password can't always be told from a broken archive in 7z,
+    /// so data errors of an encrypted block or header are taken as a wrong password too.
+    pub fn set_password_provider<P: PasswordProvider + 'static>(mut self, provider: P, attempts: usize) -> SevenZCfg {
+        self.password_provider = Some(Arc::new(provider));
+        self.password_attempts = attempts;
+        self
+    }
+
+    /// Run `job`, with passwords from the provider when the password is missing or wrong.
+    fn retry_password<T>(&self, job: impl FnMut(&SevenZCfg) -> Result<T, ComdeError>) -> Result<T, ComdeError> {
+        password::retry(
+            self,
+            self.password_provider.as_deref(),
+            self.password_attempts,
+            |config, password| config.clone().set_password(password),
+            job,
+        )
+    }
+
+    /// Split the archive into volumes of `size` bytes when compressing with `compress_f`,
+    /// named `name.7z.001`, `name.7z.002`, ...
+    /// `None` writes a single file, which is the default.
+    pub fn set_volume_size(mut self, size: Option<u64>) -> SevenZCfg {
+        self.volume_size = size;
+        self
+    }
+
+    /// Set the count of threads used to decompress, 1 by default.
+    /// Blocks are independent of each other, so a non-solid archive is extracted in parallel,
+    /// and the threads left over decode each block of LZMA2 compressed on many threads in parallel too,
+    /// unless the memory is limited by [SevenZCfg::set_max_memory].
+    pub fn set_threads(mut self, threads: usize) -> SevenZCfg {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Limit the memory that decoding an archive may take, `None` is no limit, which is the default.
+    /// Before extracting, the coders of all blocks are read from the header, and an archive needing more,
+    /// such as one declaring a huge LZMA dictionary, is rejected with [ComdeError::MemoryLimit].
+    /// With more than one thread, the blocks decoded at the same time are counted together.
+    ///
+    /// The memory is told by the properties of coders, about, except Zstandard,
+    /// which is taken as 128 MiB as its window is only known from the data.
+    pub fn set_max_memory(mut self, limit: Option<u64>) -> SevenZCfg {
+        self.max_memory = limit;
+        self
+    }
+
+    /// Get the bytes of memory compressing with the config takes, about.
+    /// It's the largest of the default method and those of method rules, as blocks are compressed one by one.
+    /// Filters and encrypting take little, so they are not counted.
+    pub fn estimated_memory(&self) -> u64 {
+        self.rules.iter().map(|rule| rule.method_memory).fold(self.method_memory, u64::max)
+    }
+
+    /// Store files without compression.
+    pub fn use_copy(mut self) -> SevenZCfg {
+        self.configs.clear();
+        self.lzma2_level = None;
+        self.configs.push(sevenz_rust2::EncoderMethod::COPY.into());
+        self.method_memory = 0;
+        self
+    }
+
+    /// Use the method and level of a preset, and compress on all CPUs.
+    /// - `Store` stores files.
+    /// - `Fastest`, `Fast`, `Normal`, `Maximum` and `Ultra` use LZMA2 of level 1, 3, 5, 7 and 9,
+    ///   the dictionary grows with the level from 1 MiB to 64 MiB.
+    ///
+    /// Threads compress chunks of 3 times the dictionary, so the ratio is close to a single thread.
+    pub fn use_preset(self, preset: Preset) -> SevenZCfg {
+        self.use_preset_with_threads(preset, preset::preset_threads())
+    }
+
+    /// Use the method and level of a preset like `use_preset`, and compress on `threads` threads.
+    pub fn use_preset_with_threads(self, preset: Preset, threads: usize) -> SevenZCfg {
+        let level = match preset {
+            Preset::Store => return self.use_copy(),
+            Preset::Fastest => 1,
+            Preset::Fast => 3,
+            Preset::Normal => 5,
+            Preset::Maximum => 7,
+            Preset::Ultra => 9,
+        };
+        self.use_lzma2_with_multithread(level, threads.max(1) as u32, lzma2_dictionary_size(level) as u64 * 3)
+    }
+
     /// Use LZMA2 to compress.
     /// Level range to 0 - 9 .
     pub fn use_lzma2_with_level(mut self, level: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::Lzma2Options::from_level(level);
         self.configs.clear();
+        self.lzma2_level = Some(level);
         self.configs.push(cfg.into());
+        self.method_memory = lzma2_encoder_memory(level, lzma2_dictionary_size(level), 1, 0);
         self
     }
 
@@ -161,16 +1363,42 @@ impl SevenZCfg {
     pub fn use_lzma2_with_multithread(mut self, level: u32, threads: u32, chunk_size: u64) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::Lzma2Options::from_level_mt(level, threads, chunk_size);
         self.configs.clear();
+        self.lzma2_level = Some(level);
         self.configs.push(cfg.into());
+        self.method_memory = lzma2_encoder_memory(level, lzma2_dictionary_size(level), threads, chunk_size.max(lzma2_dictionary_size(level) as u64));
         self
     }
 
+    /// Compress on `threads` threads, keeping the level of LZMA2,
+    /// which is the only method here that compresses on many threads.
+    /// It takes the default method and those set by `use_lzma2_with_level` or `use_lzma2_with_multithread`,
+    /// other methods are left as they are. Threads to decompress are set by [SevenZCfg::set_threads].
+    pub fn set_compress_threads(self, threads: usize) -> SevenZCfg {
+        match self.lzma2_level {
+            Some(level) => self.use_lzma2_with_multithread(level, threads.max(1) as u32, lzma2_dictionary_size(level) as u64 * 3),
+            None => self,
+        }
+    }
+
+    /// Use LZMA2 to compress, with settings finer than a level, such as a dictionary larger than the one of level 9.
+    /// Returns an error telling which setting is wrong if they are out of range or can't go together.
+    pub fn use_lzma2_with_tuning(mut self, tuning: Lzma2Tuning) -> Result<SevenZCfg, ComdeError> {
+        let cfg = tuning.to_options()?;
+        self.configs.clear();
+        self.lzma2_level = None;
+        self.configs.push(cfg.into());
+        self.method_memory = tuning.memory();
+        Ok(self)
+    }
+
     /// Use Delta to compress. This is also the default compress algorithm.
     /// Distance range to 1 - 256 .
     pub fn use_delta_with_distance(mut self, distance: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::DeltaOptions::from_distance(distance);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = 0;
         self
     }
 
@@ -180,7 +1408,9 @@ impl SevenZCfg {
     pub fn use_zstd_with_level(mut self, level: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::ZstandardOptions::from_level(level);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = zstd_encoder_memory(level);
         self
     }
 
@@ -190,7 +1420,9 @@ impl SevenZCfg {
     pub fn use_deflate_with_level(mut self, level: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::DeflateOptions::from_level(level);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = 256 << 10;
         self
     }
     
@@ -200,7 +1432,9 @@ impl SevenZCfg {
     pub fn use_bzip2_with_level(mut self, level: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::Bzip2Options::from_level(level);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = (400 << 10) + 8 * level.clamp(1, 9) as u64 * 100_000;
         self
     }
 
@@ -210,7 +1444,9 @@ impl SevenZCfg {
     pub fn use_ppmd_with_level(mut self, level: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::PpmdOptions::from_level(level);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = 1 << (level.min(9) + 19);
         self
     }
 
@@ -220,7 +1456,9 @@ impl SevenZCfg {
     pub fn use_ppmd_with_order_memsize(mut self, order: u32, memory_size: u32) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::PpmdOptions::from_order_memory_size(order, memory_size);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = memory_size as u64;
         self
     }
 
@@ -232,7 +1470,9 @@ impl SevenZCfg {
     pub fn use_ppmd_fast(mut self) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::PpmdOptions::from_order_memory_size(3, 1 << 20);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = 1 << 20;
         self
     }
 
@@ -244,7 +1484,9 @@ impl SevenZCfg {
     pub fn use_ppmd_balanced(mut self) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::PpmdOptions::from_order_memory_size(8, 1 << 23);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = 1 << 23;
         self
     }
 
@@ -256,7 +1498,9 @@ impl SevenZCfg {
     pub fn use_ppmd_max(mut self) -> SevenZCfg {
         let cfg = sevenz_rust2::encoder_options::PpmdOptions::from_order_memory_size(16, 1 << 25);
         self.configs.clear();
+        self.lzma2_level = None;
         self.configs.push(cfg.into());
+        self.method_memory = 1 << 25;
         self
     }
 
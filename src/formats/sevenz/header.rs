@@ -0,0 +1,464 @@
+//! Read a 7z header with sevenz-rust2, guarded against broken and hostile headers,
+//! and tell the memory decoding the blocks takes from the coders it reads.
+//!
+//! sevenz-rust2 allocates for the counts of a header before reading the items they count,
+//! so a header is walked here first, each count checked against the bytes left in it,
+//! and only then given to `Archive::read`.
+//! An encoded header is decoded by sevenz-rust2 too, after the memory of its coders is checked,
+//! and the header it decodes to is walked in turn.
+//!
+//! The properties of coders are kept to sevenz-rust2, only its `Debug` output shows them,
+//! so they're taken from there.
+use std::io::{Cursor, Read, Seek, SeekFrom};
+
+use sevenz_rust2::{Archive, Block, Coder, EncoderMethod, Password};
+
+use crate::error::ComdeError;
+
+const SIGNATURE: [u8; 6] = [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C];
+const START_HEADER_SIZE: u64 = 32;
+
+const K_END: u8 = 0x00;
+const K_HEADER: u8 = 0x01;
+const K_ARCHIVE_PROPERTIES: u8 = 0x02;
+const K_ADDITIONAL_STREAMS_INFO: u8 = 0x03;
+const K_MAIN_STREAMS_INFO: u8 = 0x04;
+const K_FILES_INFO: u8 = 0x05;
+const K_PACK_INFO: u8 = 0x06;
+const K_UNPACK_INFO: u8 = 0x07;
+const K_SUB_STREAMS_INFO: u8 = 0x08;
+const K_SIZE: u8 = 0x09;
+const K_CRC: u8 = 0x0A;
+const K_FOLDER: u8 = 0x0B;
+const K_CODERS_UNPACK_SIZE: u8 = 0x0C;
+const K_NUM_UNPACK_STREAM: u8 = 0x0D;
+const K_NAME: u8 = 0x11;
+const K_ENCODED_HEADER: u8 = 0x17;
+
+/// The most streams a coder may take or give, as 7-Zip accepts.
+const MAX_CODER_STREAMS: u64 = 64;
+
+/// The most cycles of key derivation accepted, as a power of 2, which is what 7-Zip takes at most in practice.
+const MAX_CYCLES_POWER: u8 = 24;
+
+/// Read the header of the archive at the start of `input`, which is where sevenz-rust2 reads it too.
+/// An encoded header must fit in `limit` bytes of memory with its coders, if it's set, before it's decoded,
+/// the password is only given to it if it's encrypted, so a broken header isn't taken as a wrong password.
+pub(super) fn read_archive<R: Read + Seek>(input: &mut R, password: &Password, limit: Option<u64>) -> Result<Archive, ComdeError> {
+    let end = input.seek(SeekFrom::End(0))?;
+    input.seek(SeekFrom::Start(0))?;
+    let mut start_header = [0u8; START_HEADER_SIZE as usize];
+    input.read_exact(&mut start_header).map_err(|_| corrupt("not a 7z archive"))?;
+    if start_header[..6] != SIGNATURE {
+        return Err(corrupt("not a 7z archive"));
+    }
+    // An archive whose start header was never written is left to sevenz-rust2, which looks for the header itself.
+    if start_header[8..].iter().all(|&b| b == 0) {
+        input.seek(SeekFrom::Start(0))?;
+        return read_guarded(input, &Password::empty());
+    }
+    if crc32fast::hash(&start_header[12..]).to_le_bytes() != start_header[8..12] {
+        return Err(corrupt("start header CRC mismatch"));
+    }
+    let next_header_offset = u64_at(&start_header, 12);
+    let next_header_size = u64_at(&start_header, 20);
+    let next_header_end = START_HEADER_SIZE.checked_add(next_header_offset).and_then(|start| start.checked_add(next_header_size));
+    if next_header_end.is_none_or(|next_header_end| next_header_end > end) {
+        return Err(corrupt("header out of the archive"));
+    }
+    if let Some(limit) = limit {
+        check(next_header_size, limit)?;
+    }
+    input.seek(SeekFrom::Start(START_HEADER_SIZE + next_header_offset))?;
+    let mut header = vec![];
+    input.take(next_header_size).read_to_end(&mut header)?;
+    if crc32fast::hash(&header).to_le_bytes() != start_header[28..32] {
+        return Err(corrupt("header CRC mismatch"));
+    }
+
+    if header.first() == Some(&K_ENCODED_HEADER) {
+        header = decode_header(input, &header, password, limit)?;
+    }
+    if header.first() != Some(&K_HEADER) {
+        return Err(corrupt("no header"));
+    }
+    walk_header(&mut Walker { bytes: &header[1..] })?;
+    read_guarded(&mut Cursor::new(plain_archive(&header)), &Password::empty())
+}
+
+/// Decode the encoded `header` from `input`, with its coders checked first.
+fn decode_header<R: Read + Seek>(input: &mut R, header: &[u8], password: &Password, limit: Option<u64>) -> Result<Vec<u8>, ComdeError> {
+    let streams_info = &header[1..];
+    let left = walk_streams_info(&mut Walker { bytes: streams_info })?;
+    // The header is read by sevenz-rust2 as the data of a file, in a stand-in archive with only that file,
+    // so its block is split into that one file, whatever sub streams it had.
+    let mut stand_in = vec![K_HEADER, K_MAIN_STREAMS_INFO];
+    stand_in.extend_from_slice(&streams_info[..streams_info.len() - left]);
+    stand_in.extend_from_slice(&[K_SUB_STREAMS_INFO, K_END, K_END]);
+    stand_in.extend_from_slice(&[K_FILES_INFO, 1, K_NAME, 5, 0, b'h', 0, 0, 0, K_END, K_END]);
+    let archive = read_guarded(&mut Cursor::new(plain_archive(&stand_in)), &Password::empty())?;
+    let block = archive.blocks.first().ok_or_else(|| corrupt("encoded header without data"))?;
+
+    if let Some(limit) = limit {
+        // The sizes come from the archive, so they may be anything up to `u64::MAX`.
+        let required = (header.len() as u64)
+            .saturating_add(block.get_unpack_size())
+            .saturating_add(block_memory(block));
+        check(required, limit)?;
+    }
+    let encrypted = block.coders.iter().any(|coder| coder.encoder_method_id() == EncoderMethod::ID_AES256_SHA256);
+    let empty = Password::empty();
+    let mut decoded = vec![];
+    sevenz_rust2::BlockDecoder::new(1, 0, &archive, if encrypted { password } else { &empty }, input)
+        .for_each_entries(&mut |_, data| {
+            data.read_to_end(&mut decoded)?;
+            Ok(true)
+        })
+        .map_err(|e| super::wrong_password_if(encrypted && !password.is_empty(), e.into()))?;
+    if decoded.first() == Some(&K_ENCODED_HEADER) {
+        return Err(corrupt("header encoded more than once"));
+    }
+    Ok(decoded)
+}
+
+/// Read an archive with sevenz-rust2, after its header is walked, and check the key cycles of its blocks.
+/// A broken header may still make sevenz-rust2 index out of what it read, which is taken as corrupt.
+fn read_guarded<R: Read + Seek>(input: &mut R, password: &Password) -> Result<Archive, ComdeError> {
+    let archive = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| Archive::read(input, password)))
+        .map_err(|_| corrupt("broken header"))??;
+    for coder in archive.blocks.iter().flat_map(|block| &block.coders) {
+        check_cycles(coder)?;
+    }
+    Ok(archive)
+}
+
+/// Make an archive of the plain `header` right after the start header, with no data.
+fn plain_archive(header: &[u8]) -> Vec<u8> {
+    let mut start_header = vec![];
+    start_header.extend_from_slice(&0u64.to_le_bytes());
+    start_header.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    start_header.extend_from_slice(&crc32fast::hash(header).to_le_bytes());
+    let mut archive = SIGNATURE.to_vec();
+    archive.extend_from_slice(&[0, 4]);
+    archive.extend_from_slice(&crc32fast::hash(&start_header).to_le_bytes());
+    archive.extend_from_slice(&start_header);
+    archive.extend_from_slice(header);
+    archive
+}
+
+/// Get the bytes of memory the coders of `block` need to decode it, all at the same time, about.
+/// Zstandard tells its window in the data rather than the properties,
+/// so it's taken as the largest window the decoder accepts.
+pub(super) fn block_memory(block: &Block) -> u64 {
+    block.coders.iter().fold(0, |sum, coder| {
+        let properties = properties(coder);
+        let memory = match coder.encoder_method_id() {
+            EncoderMethod::ID_LZMA => {
+                let props = properties.first().copied().unwrap_or(0) as u64;
+                let (lc, lp) = (props % 9, props / 9 % 5);
+                dictionary(&properties).saturating_add((0x300 << (lc + lp)) * 2)
+            }
+            EncoderMethod::ID_LZMA2 => {
+                let dictionary = match properties.first().copied().unwrap_or(0) & 0x3F {
+                    40.. => u32::MAX as u64,
+                    bits => (2 | (bits as u64 & 1)) << (bits / 2 + 11),
+                };
+                dictionary + (0x300 << 4) * 2
+            }
+            EncoderMethod::ID_PPMD => dictionary(&properties),
+            EncoderMethod::ID_BZIP2 => 4 << 20,
+            EncoderMethod::ID_ZSTD => 128 << 20,
+            _ => 64 << 10,
+        };
+        sum.saturating_add(memory)
+    })
+}
+
+/// Get the size in bytes 1 to 4 of properties, the dictionary of LZMA and the memory of PPMd.
+fn dictionary(properties: &[u8]) -> u64 {
+    properties.get(1..5).map_or(0, |size| u32_at(size, 0) as u64)
+}
+
+/// Get the properties of `coder` from its `Debug` output, none if they can't be read from it.
+fn properties(coder: &Coder) -> Vec<u8> {
+    let debug = format!("{coder:?}");
+    let properties = debug.split_once("properties: [").and_then(|(_, rest)| rest.split_once(']'));
+    properties
+        .map(|(list, _)| list.split(',').filter_map(|byte| byte.trim().parse().ok()).collect())
+        .unwrap_or_default()
+}
+
+/// Refuse AES256 with too many cycles of key derivation, as each is a round of SHA-256,
+/// and a large power would take forever to derive the key.
+fn check_cycles(coder: &Coder) -> Result<(), ComdeError> {
+    if coder.encoder_method_id() != EncoderMethod::ID_AES256_SHA256 {
+        return Ok(());
+    }
+    let cycles_power = properties(coder).first().copied().unwrap_or(0) & 0x3F;
+    if cycles_power > MAX_CYCLES_POWER && cycles_power != 0x3F {
+        return Err(ComdeError::UnsupportedMethod(format!("AES256 with 2^{cycles_power} key cycles")));
+    }
+    Ok(())
+}
+
+/// Fail with [ComdeError::MemoryLimit] if `required` is over `limit`.
+pub(super) fn check(required: u64, limit: u64) -> Result<(), ComdeError> {
+    if required > limit {
+        return Err(ComdeError::MemoryLimit { required, limit });
+    }
+    Ok(())
+}
+
+fn corrupt(message: &str) -> ComdeError {
+    ComdeError::Corrupt { entry: None, source: message.into() }
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> u32 {
+    u32::from_le_bytes(bytes[at..at + 4].try_into().unwrap_or_default())
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> u64 {
+    u64::from_le_bytes(bytes[at..at + 8].try_into().unwrap_or_default())
+}
+
+/// The bytes left of a header being walked.
+struct Walker<'a> {
+    bytes: &'a [u8],
+}
+
+impl Walker<'_> {
+    fn u8(&mut self) -> Result<u8, ComdeError> {
+        let (&byte, rest) = self.bytes.split_first().ok_or_else(|| corrupt("header ends early"))?;
+        self.bytes = rest;
+        Ok(byte)
+    }
+
+    /// Read a number of 7z, the leading 1 bits of the first byte tell the count of bytes after it.
+    fn number(&mut self) -> Result<u64, ComdeError> {
+        let first = self.u8()?;
+        let mut mask = 0x80;
+        let mut value = 0u64;
+        for i in 0..8 {
+            if first & mask == 0 {
+                let high = (first & (mask - 1)) as u64;
+                return Ok(value | (high << (8 * i)));
+            }
+            value |= (self.u8()? as u64) << (8 * i);
+            mask >>= 1;
+        }
+        Ok(value)
+    }
+
+    /// Read a count of items, each takes a byte of the header at least, so there can't be more than bytes left.
+    fn count(&mut self) -> Result<u64, ComdeError> {
+        let count = self.number()?;
+        if count > self.bytes.len() as u64 {
+            return Err(corrupt("count over the size of the header"));
+        }
+        Ok(count)
+    }
+
+    fn skip(&mut self, size: u64) -> Result<(), ComdeError> {
+        if size > self.bytes.len() as u64 {
+            return Err(corrupt("header ends early"));
+        }
+        self.bytes = &self.bytes[size as usize..];
+        Ok(())
+    }
+
+    /// Skip a bit field of `count` bits, or none if all are defined, and get how many are defined.
+    fn skip_defined(&mut self, count: u64) -> Result<u64, ComdeError> {
+        if self.u8()? != 0 {
+            return Ok(count);
+        }
+        let mut defined = 0;
+        for _ in 0..count.div_ceil(8) {
+            defined += self.u8()?.count_ones() as u64;
+        }
+        Ok(defined)
+    }
+
+    /// Skip the CRCs of `count` items.
+    fn skip_crcs(&mut self, count: u64) -> Result<(), ComdeError> {
+        let defined = self.skip_defined(count)?;
+        self.skip(defined.saturating_mul(4))
+    }
+}
+
+/// Walk a plain header after its ID.
+fn walk_header(walker: &mut Walker) -> Result<(), ComdeError> {
+    let mut id = walker.u8()?;
+    if id == K_ARCHIVE_PROPERTIES {
+        while walker.u8()? != K_END {
+            let size = walker.number()?;
+            walker.skip(size)?;
+        }
+        id = walker.u8()?;
+    }
+    if id == K_ADDITIONAL_STREAMS_INFO {
+        walk_streams_info(walker)?;
+        id = walker.u8()?;
+    }
+    if id == K_MAIN_STREAMS_INFO {
+        walk_streams_info(walker)?;
+        id = walker.u8()?;
+    }
+    if id == K_FILES_INFO {
+        walker.count()?;
+        loop {
+            let id = walker.u8()?;
+            if id == K_END {
+                break;
+            }
+            let size = walker.number()?;
+            walker.skip(size)?;
+        }
+        id = walker.u8()?;
+    }
+    if id != K_END {
+        return Err(corrupt("unknown header"));
+    }
+    Ok(())
+}
+
+/// Walk streams info, get the count of its bytes from its sub streams info, or its end without one.
+fn walk_streams_info(walker: &mut Walker) -> Result<usize, ComdeError> {
+    let mut id = walker.u8()?;
+    if id == K_PACK_INFO {
+        walker.number()?;
+        let count = walker.count()?;
+        id = walker.u8()?;
+        if id == K_SIZE {
+            for _ in 0..count {
+                walker.number()?;
+            }
+            id = walker.u8()?;
+        }
+        if id == K_CRC {
+            walker.skip_crcs(count)?;
+            id = walker.u8()?;
+        }
+        if id != K_END {
+            return Err(corrupt("unknown pack info"));
+        }
+        id = walker.u8()?;
+    }
+    // Whether each block has a CRC, which tells how many CRCs sub streams have.
+    let mut blocks = vec![];
+    if id == K_UNPACK_INFO {
+        blocks = walk_unpack_info(walker)?;
+        id = walker.u8()?;
+    }
+    let left = walker.bytes.len() + 1;
+    if id == K_SUB_STREAMS_INFO {
+        walk_sub_streams_info(walker, &blocks)?;
+        id = walker.u8()?;
+    }
+    if id != K_END {
+        return Err(corrupt("unknown streams info"));
+    }
+    Ok(left)
+}
+
+/// Walk unpack info, get whether each block has a CRC.
+fn walk_unpack_info(walker: &mut Walker) -> Result<Vec<bool>, ComdeError> {
+    if walker.u8()? != K_FOLDER {
+        return Err(corrupt("blocks expected"));
+    }
+    let count = walker.count()?;
+    if walker.u8()? != 0 {
+        return Err(ComdeError::UnsupportedMethod("blocks kept out of the header".to_string()));
+    }
+    let outputs = (0..count).map(|_| walk_block(walker)).collect::<Result<Vec<_>, _>>()?;
+    if walker.u8()? != K_CODERS_UNPACK_SIZE {
+        return Err(corrupt("unpack sizes expected"));
+    }
+    for _ in 0..outputs.iter().sum::<u64>() {
+        walker.number()?;
+    }
+    let mut crcs = vec![false; count as usize];
+    let mut id = walker.u8()?;
+    if id == K_CRC {
+        if walker.u8()? != 0 {
+            crcs.fill(true);
+        } else {
+            for chunk in crcs.chunks_mut(8) {
+                let bits = walker.u8()?;
+                for (i, crc) in chunk.iter_mut().enumerate() {
+                    *crc = bits & (0x80 >> i) != 0;
+                }
+            }
+        }
+        walker.skip(crcs.iter().filter(|&&crc| crc).count() as u64 * 4)?;
+        id = walker.u8()?;
+    }
+    if id != K_END {
+        return Err(corrupt("unknown unpack info"));
+    }
+    Ok(crcs)
+}
+
+/// Walk a block, get the count of its outputs.
+fn walk_block(walker: &mut Walker) -> Result<u64, ComdeError> {
+    let (mut inputs, mut outputs) = (0, 0);
+    for _ in 0..walker.count()? {
+        let flags = walker.u8()?;
+        walker.skip((flags & 0x0F) as u64)?;
+        let (coder_inputs, coder_outputs) = match flags & 0x10 {
+            0 => (1, 1),
+            _ => (walker.number()?, walker.number()?),
+        };
+        if coder_inputs > MAX_CODER_STREAMS || coder_outputs > MAX_CODER_STREAMS {
+            return Err(ComdeError::UnsupportedMethod("coder of too many streams".to_string()));
+        }
+        (inputs, outputs) = (inputs + coder_inputs, outputs + coder_outputs);
+        if flags & 0x20 != 0 {
+            let size = walker.number()?;
+            walker.skip(size)?;
+        }
+    }
+    if outputs == 0 || inputs < outputs - 1 {
+        return Err(corrupt("block without output"));
+    }
+    for _ in 0..(outputs - 1) * 2 {
+        walker.number()?;
+    }
+    let packed = inputs - (outputs - 1);
+    if packed > 1 {
+        for _ in 0..packed {
+            walker.number()?;
+        }
+    }
+    Ok(outputs)
+}
+
+/// Walk sub streams info of the blocks which have a CRC or not.
+fn walk_sub_streams_info(walker: &mut Walker, blocks: &[bool]) -> Result<(), ComdeError> {
+    let mut streams = vec![1; blocks.len()];
+    let mut id = walker.u8()?;
+    if id == K_NUM_UNPACK_STREAM {
+        for count in &mut streams {
+            *count = walker.number()?;
+        }
+        // All but the last stream of a block have their sizes, so there can't be more of them than bytes left.
+        if streams.iter().fold(0u64, |sum, count| sum.saturating_add(count.saturating_sub(1))) > walker.bytes.len() as u64 {
+            return Err(corrupt("count over the size of the header"));
+        }
+        id = walker.u8()?;
+    }
+    if id == K_SIZE {
+        for _ in 0..streams.iter().map(|count| count.saturating_sub(1)).sum::<u64>() {
+            walker.number()?;
+        }
+        id = walker.u8()?;
+    }
+    if id == K_CRC {
+        let digests = streams.iter().zip(blocks).filter(|&(&count, &crc)| count != 1 || !crc).map(|(count, _)| count).sum();
+        walker.skip_crcs(digests)?;
+        id = walker.u8()?;
+    }
+    if id != K_END {
+        return Err(corrupt("unknown sub streams info"));
+    }
+    Ok(())
+}
@@ -0,0 +1,334 @@
+//! # tar support
+//! We have 2 struct here:
+//! - TarComde (ZST)
+//! - TarCfg
+//!
+//! # Compress & Decompress
+//!
+//! Tar is a stream format: entries are stored one after another without compression,
+//! so compressing only needs a [Write] and decompressing only needs a [Read].
+
+use crate::comde::{CompressStatus, DecompressStatus, EntryStat, Sink};
+use std::collections::HashMap;
+use crate::error::ComdeError;
+use crate::utils;
+use crate::vfs::{RealFs, Vfs};
+use std::io::{Read, Write};
+use std::path::{Component, Path};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Clone, Copy)]
+pub struct TarComde;
+
+impl TarComde {
+
+    /// Compress files to an archive and then write into a writer.
+    pub fn compress<P: AsRef<Path>, W: Write>(
+        self,
+        input: &[P],
+        output: W,
+        config: &TarCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        self.compress_vfs(&RealFs, input, output, config)
+    }
+
+    /// Compress files of a [Vfs] to an archive and then write into a writer.
+    /// Permissions default to `0o755` for directories and `0o644` for files when the [Vfs] doesn't know them,
+    /// owners are always stored as uid and gid 0 without names.
+    pub fn compress_vfs<V: Vfs + ?Sized, P: AsRef<Path>, W: Write>(
+        self,
+        vfs: &V,
+        input: &[P],
+        output: W,
+        config: &TarCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let cw = utils::CountingWriter::new(output);
+        let mut builder = tar::Builder::new(cw.share());
+
+        let time_begin = Instant::now();
+        let size_origin = utils::size_of_inarray_in(vfs, input).map_err(ComdeError::from)?;
+
+        let (mut entries, skipped) = utils::collect_entries(vfs, input).map_err(ComdeError::from)?;
+        if config.reproducible {
+            utils::make_reproducible(&mut entries);
+        }
+        if config.xattrs {
+            utils::read_xattrs(vfs, &mut entries)?;
+        }
+        for entry in &entries {
+            let keys = entry.xattrs.iter().map(|(name, _)| format!("{}{}", PAX_XATTR, name)).collect::<Vec<_>>();
+            builder.append_pax_extensions(keys.iter().map(String::as_str).zip(entry.xattrs.iter().map(|(_, value)| value.as_slice())))
+                .map_err(ComdeError::from)?;
+            let mut header = tar::Header::new_gnu();
+            let mtime = entry.metadata.modified
+                .and_then(|t| t.duration_since(UNIX_EPOCH).ok())
+                .map(|d| d.as_secs())
+                .unwrap_or(0);
+            header.set_mtime(mtime);
+            if entry.is_dir {
+                header.set_entry_type(tar::EntryType::Directory);
+                header.set_size(0);
+                header.set_mode(entry.metadata.mode.unwrap_or(0o755));
+                builder.append_data(&mut header, &entry.name, std::io::empty()).map_err(ComdeError::from)?;
+            } else {
+                header.set_entry_type(tar::EntryType::Regular);
+                header.set_size(entry.metadata.len);
+                header.set_mode(entry.metadata.mode.unwrap_or(0o644));
+                let file = vfs.open(&entry.path).map_err(|e| ComdeError::io(&entry.path, e))?;
+                builder.append_data(&mut header, &entry.name, file).map_err(ComdeError::from)?;
+            }
+        }
+        builder.finish().map_err(ComdeError::from)?;
+
+        let stats = entries.iter().map(|entry| {
+            let size = if entry.is_dir { 0 } else { entry.metadata.len };
+            EntryStat::new(&entry.name, entry.is_dir, size, None)
+        }).collect();
+        let size_compressed = cw.bytes_written();
+        let time_finished = time_begin.elapsed();
+
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats).with_skipped(skipped))
+    }
+
+    /// Compress files in memory, given as pairs of name and data,
+    /// to an archive and then write into a writer.
+    pub fn compress_mem<N: AsRef<str>, D: AsRef<[u8]>, W: Write>(
+        self,
+        input: &[(N, D)],
+        output: W,
+        config: &TarCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let cw = utils::CountingWriter::new(output);
+        let mut builder = tar::Builder::new(cw.share());
+
+        let time_begin = Instant::now();
+        let size_origin = input.iter().map(|(_, data)| data.as_ref().len() as u64).sum();
+
+        let mtime = if config.reproducible { utils::reproducible_mtime() } else { SystemTime::now() };
+        let mtime = mtime.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+        let mut input = input.iter().collect::<Vec<_>>();
+        if config.reproducible {
+            input.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+        }
+        let mut stats = Vec::with_capacity(input.len());
+        for (name, data) in input {
+            stats.push(EntryStat::new(name.as_ref(), false, data.as_ref().len() as u64, None));
+            let mut header = tar::Header::new_gnu();
+            header.set_entry_type(tar::EntryType::Regular);
+            header.set_size(data.as_ref().len() as u64);
+            header.set_mode(0o644);
+            header.set_mtime(mtime);
+            builder.append_data(&mut header, name.as_ref(), data.as_ref()).map_err(ComdeError::from)?;
+        }
+        builder.finish().map_err(ComdeError::from)?;
+
+        let size_compressed = cw.bytes_written();
+        let time_finished = time_begin.elapsed();
+
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats))
+    }
+
+    /// Compress files to an archive and then write into a file of given path.
+    pub fn compress_f<P: AsRef<Path>>(
+        self,
+        input: &[P],
+        output: P,
+        config: &TarCfg,
+    ) -> Result<CompressStatus, ComdeError> {
+        let output = output.as_ref();
+        let out_file = std::fs::File::create(output).map_err(|e| ComdeError::io(output, e))?;
+        self.compress(input, out_file, config)
+    }
+
+    /// Decompress an archive from a reader and write into a file.
+    /// Entries that would be written outside of `output` are skipped.
+    pub fn decompress<P: AsRef<Path>, R: Read>(
+        self,
+        input: R,
+        output: P,
+        config: &TarCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let cr = utils::CountingReader::new(input);
+        let time_begin = Instant::now();
+
+        let output = output.as_ref();
+        std::fs::create_dir_all(output).map_err(|e| ComdeError::io(output, e))?;
+        let mut archive = tar::Archive::new(cr.share());
+        let (mut stats, mut skipped) = (vec![], vec![]);
+        // Directories are unpacked at last, so files written into them don't change their times or make them read-only too early.
+        let mut directories = vec![];
+        for entry in archive.entries().map_err(ComdeError::from)? {
+            let mut entry = entry.map_err(ComdeError::from)?;
+            entry.set_preserve_permissions(config.preserve_permissions);
+            let stat = entry_stat(&entry);
+            let xattrs = if config.xattrs { xattrs_of(&mut entry)? } else { vec![] };
+            if entry.header().entry_type() == tar::EntryType::Directory {
+                directories.push((entry, stat, xattrs));
+                continue;
+            }
+            let unpacked = unpack_entry(&mut entry, output, &xattrs)?;
+            if unpacked { stats.push(stat) } else { skipped.push(stat.name().to_string()) }
+        }
+        for (mut entry, stat, xattrs) in directories {
+            let unpacked = unpack_entry(&mut entry, output, &xattrs)?;
+            if unpacked { stats.push(stat) } else { skipped.push(stat.name().to_string()) }
+        }
+
+        let size_read = cr.bytes_read();
+        let size_decompressed = stats.iter().map(|s| s.size()).sum();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats).with_skipped(skipped))
+    }
+
+    /// Decompress an archive from a reader and pass its entries into a sink.
+    /// Entries other than files and directories, such as links, are skipped.
+    pub fn decompress_into<R: Read, S: Sink>(
+        self,
+        input: R,
+        sink: &mut S,
+        _config: &TarCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let cr = utils::CountingReader::new(input);
+        let time_begin = Instant::now();
+
+        let mut size_decompressed = 0;
+        let mut archive = tar::Archive::new(cr.share());
+        let (mut stats, mut skipped) = (vec![], vec![]);
+        for entry in archive.entries().map_err(ComdeError::from)? {
+            let mut entry = entry.map_err(ComdeError::from)?;
+            let stat = entry_stat(&entry);
+            match entry.header().entry_type() {
+                tar::EntryType::Directory => sink.dir(stat.name()).map_err(ComdeError::from)?,
+                tar::EntryType::Regular | tar::EntryType::Continuous => {
+                    let counted = utils::CountingReader::new(&mut entry);
+                    sink.file(stat.name(), &mut counted.share()).map_err(ComdeError::from)?;
+                    size_decompressed += counted.bytes_read();
+                }
+                _ => {
+                    skipped.push(stat.name().to_string());
+                    continue;
+                }
+            }
+            stats.push(stat);
+        }
+
+        let size_read = cr.bytes_read();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats).with_skipped(skipped))
+    }
+
+    /// Decompress an archive from a reader into memory, files are keyed by their names.
+    pub fn decompress_mem<R: Read>(
+        self,
+        input: R,
+        config: &TarCfg,
+    ) -> Result<HashMap<String, Vec<u8>>, ComdeError> {
+        let mut files = HashMap::new();
+        self.decompress_into(input, &mut files, config)?;
+        Ok(files)
+    }
+
+    /// Decompress an archive of given path and write into a file.
+    pub fn decompress_f<P: AsRef<Path>>(
+        self,
+        input: P,
+        output: P,
+        config: &TarCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let input = input.as_ref();
+        let in_file = std::fs::File::open(input).map_err(|e| ComdeError::io(input, e))?;
+        self.decompress(in_file, output, config)
+    }
+}
+
+/// The prefix of PAX keys of extended attributes, as GNU tar and bsdtar write them.
+const PAX_XATTR: &str = "SCHILY.xattr.";
+
+/// Get the extended attributes of an entry from its PAX header.
+fn xattrs_of<R: Read>(entry: &mut tar::Entry<'_, R>) -> Result<Vec<(String, Vec<u8>)>, ComdeError> {
+    let Some(extensions) = entry.pax_extensions().map_err(ComdeError::from)? else {
+        return Ok(vec![]);
+    };
+    let mut xattrs = vec![];
+    for extension in extensions {
+        let extension = extension.map_err(ComdeError::from)?;
+        if let Some(name) = extension.key().ok().and_then(|key| key.strip_prefix(PAX_XATTR)) {
+            xattrs.push((name.to_string(), extension.value_bytes().to_vec()));
+        }
+    }
+    Ok(xattrs)
+}
+
+/// Unpack an entry into `output` and set its extended attributes, return whether it's unpacked.
+fn unpack_entry<R: Read>(entry: &mut tar::Entry<'_, R>, output: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<bool, ComdeError> {
+    if !entry.unpack_in(output).map_err(ComdeError::from)? {
+        return Ok(false);
+    }
+    if !xattrs.is_empty() {
+        // The same path unpack_in writes to, which keeps only normal components.
+        let path = entry.path().map_err(ComdeError::from)?.components()
+            .filter(|c| matches!(c, Component::Normal(_)))
+            .fold(output.to_path_buf(), |path, c| path.join(c));
+        utils::restore_xattrs(&path, xattrs)?;
+    }
+    Ok(true)
+}
+
+/// Get the stats of an entry, tar entries are never compressed.
+fn entry_stat<R: Read>(entry: &tar::Entry<'_, R>) -> EntryStat {
+    let name = String::from_utf8_lossy(&entry.path_bytes()).to_string();
+    let is_dir = entry.header().entry_type() == tar::EntryType::Directory;
+    EntryStat::new(&name, is_dir, entry.size(), None)
+}
+
+#[derive(Clone)]
+pub struct TarCfg {
+    preserve_permissions: bool,
+    reproducible: bool,
+    xattrs: bool,
+}
+
+impl Default for TarCfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl TarCfg {
+
+    /// # Create a TarCfg.
+    /// Permissions of entries are not restored by default.
+    pub fn new() -> TarCfg {
+        TarCfg {
+            preserve_permissions: false,
+            reproducible: false,
+            xattrs: false,
+        }
+    }
+
+    /// Switch whether to restore the permissions of entries when decompressing.
+    pub fn set_preserve_permissions(mut self, enabled: bool) -> TarCfg {
+        self.preserve_permissions = enabled;
+        self
+    }
+
+    /// Switch whether to build the same archive from the same files on any machine.
+    /// Entries are sorted by name, their modified time is set to [utils::reproducible_mtime],
+    /// and permissions are fixed to `0o755` for directories and executables and `0o644` for other files.
+    pub fn set_reproducible(mut self, enabled: bool) -> TarCfg {
+        self.reproducible = enabled;
+        self
+    }
+
+    /// Switch whether to keep extended attributes of entries, off by default.
+    /// They go into PAX headers as `SCHILY.xattr.<name>` the way GNU tar and bsdtar write them,
+    /// which takes SELinux labels, file capabilities and POSIX ACLs along on Linux.
+    /// Restoring `security.*` and `trusted.*` ones needs privileges. They are dropped off unix.
+    #[cfg(feature = "xattr")]
+    pub fn set_xattrs(mut self, enabled: bool) -> TarCfg {
+        self.xattrs = enabled;
+        self
+    }
+}
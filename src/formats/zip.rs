@@ -1,13 +1,43 @@
 //! # zip support
-//! 
-//! [todo]
+//! We have 2 struct here:
+//! - ZipComde (ZST)
+//! - ZipCfg
+//!
+//! # Compress & Decompress
+//!
+//! Entries are encrypted with AES256 when a password is set.
+//! A directory in input is stored with its contents at the root of the archive,
+//! a file in input is stored by its file name.
+//!
+//! # Split Archives
+//!
+//! With a volume size, `compress_f` splits the archive as 7-Zip and Info-ZIP do:
+//! `name.z01`, `name.z02`, ... and the last part `name.zip`, with the disk of each entry in the central directory.
+//! `decompress_f` and [crate::view::Archive] read such an archive by its first or last part.
 
-use crate::comde::{CompressStatus, DecompressStatus};
-use crate::error::ComdeError::Other;
-use crate::error::{ComdeError, Unimplemented};
-use std::io::{Seek, Write, Read};
-use std::path::Path;
+use crate::comde::{CompressStatus, DecompressStatus, EntryStat, Sink};
+use std::collections::HashMap;
+use crate::error::ComdeError;
+use crate::password::{self, PasswordProvider};
+use crate::preset::{self, Preset};
+use crate::utils;
+use crate::vfs::{RealFs, Vfs};
+use std::cell::Cell;
+use std::collections::BTreeMap;
+use std::io::{Cursor, Seek, Write, Read};
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{mpsc, Condvar, Mutex};
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+use zip::read::HasZipMetadata;
+use zip::write::{FileOptions, FullFileOptions};
+use zip::CompressionMethod;
 
+mod comment;
+pub(crate) mod split;
+
+#[derive(Clone, Copy)]
 pub struct ZipComde;
 
 impl ZipComde {
@@ -17,47 +47,1120 @@ impl ZipComde {
         self,
         input: &[P],
         output: W,
-        config: &ZipCfg 
+        config: &ZipCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        self.compress_vfs(&RealFs, input, output, config)
+    }
+
+    /// Compress files of a [Vfs] to an archive and then write into a writer.
+    pub fn compress_vfs<V: Vfs + ?Sized, P: AsRef<Path>, W: Write + Seek>(
+        self,
+        vfs: &V,
+        input: &[P],
+        output: W,
+        config: &ZipCfg
     ) -> Result<CompressStatus, ComdeError> {
-        todo!()
+        let cw = utils::CountingWriter::new(output);
+        let time_begin = Instant::now();
+
+        let tail = comment::Tail::default();
+        let (size_origin, stats, skipped, stored) = write_archive(vfs, input, zip::ZipWriter::new(tail.writer(cw.share())), &tail, config, false)?;
+        config.release(&tail, cw.share())?;
+        let size_compressed = cw.bytes_written();
+        let time_finished = time_begin.elapsed();
+
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats).with_skipped(skipped).with_stored(stored))
     }
 
+    /// Compress files to an archive and then write into a writer that can't seek,
+    /// such as a pipe or stdout.
+    /// Sizes and CRC of each entry are written after its data (data descriptors).
+    /// With a password, each file is compressed into memory first, as encrypting needs seeking back,
+    /// so files needing Zip64 can't be encrypted this way, see [ZipCfg::set_force_zip64].
+    pub fn compress_stream<P: AsRef<Path>, W: Write>(
+        self,
+        input: &[P],
+        output: W,
+        config: &ZipCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let cw = utils::CountingWriter::new(output);
+        let time_begin = Instant::now();
+
+        let tail = comment::Tail::default();
+        let (size_origin, stats, skipped, stored) = write_archive(&RealFs, input, zip::ZipWriter::new_stream(tail.writer(cw.share())), &tail, config, true)?;
+        config.release(&tail, cw.share())?;
+        let size_compressed = cw.bytes_written();
+        let time_finished = time_begin.elapsed();
+
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats).with_skipped(skipped).with_stored(stored))
+    }
+
+    /// Compress files in memory, given as pairs of name and data,
+    /// to an archive and then write into a writer.
+    pub fn compress_mem<N: AsRef<str>, D: AsRef<[u8]>, W: Write + Seek>(
+        self,
+        input: &[(N, D)],
+        output: W,
+        config: &ZipCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let cw = utils::CountingWriter::new(output);
+        let tail = comment::Tail::default();
+        let mut writer = zip::ZipWriter::new(tail.writer(cw.share()));
+        config.apply_to(&mut writer)?;
+
+        let time_begin = Instant::now();
+        let size_origin = input.iter().map(|(_, data)| data.as_ref().len() as u64).sum();
+
+        let mut input = input.iter().collect::<Vec<_>>();
+        if config.reproducible {
+            input.sort_by(|a, b| a.0.as_ref().cmp(b.0.as_ref()));
+        }
+        let mut stats = Vec::with_capacity(input.len());
+        let mut stored = 0;
+        for (name, data) in input {
+            let store = config.should_store(name.as_ref(), || Ok(data.as_ref()[..data.as_ref().len().min(utils::INCOMPRESSIBLE_SAMPLE_SIZE)].to_vec()))?;
+            stored += usize::from(store);
+            let mut options = config.file_options_for(name.as_ref(), store, data.as_ref().len() as u64);
+            if config.reproducible {
+                options = options.last_modified_time(zip_datetime(utils::reproducible_mtime()));
+            }
+            write_data(&mut writer, name.as_ref(), options, data.as_ref())?;
+            stats.push(EntryStat::new(name.as_ref(), false, data.as_ref().len() as u64, None));
+        }
+        config.hold(&tail);
+        writer.finish().map_err(ComdeError::from)?;
+        config.release(&tail, cw.share())?;
+
+        let size_compressed = cw.bytes_written();
+        let time_finished = time_begin.elapsed();
+
+        Ok(CompressStatus::new(size_origin, size_compressed, time_finished).with_entries(stats).with_stored(stored))
+    }
+
+    /// Compress files to an archive and then write into a file of given path.
+    /// If a volume size is set, the archive is split into `output.z01`, `output.z02`, ... and `output` as the last part,
+    /// so `output` should end with `.zip`. An archive that fits in one volume is written into `output` only.
     pub fn compress_f<P: AsRef<Path>>(
         self,
-        input: P,
+        input: &[P],
         output: P,
         config: &ZipCfg,
     ) -> Result<CompressStatus, ComdeError> {
-        let (input, output) = (input.as_ref(), output.as_ref());
-        Err(Other(Unimplemented::Unfinished))
+        let output = output.as_ref();
+        if let Some(volume_size) = config.volume_size {
+            return split::write(output, volume_size, |spool| self.compress(input, spool, config));
+        }
+        let out_file = std::fs::File::create(output).map_err(|e| ComdeError::io(output, e))?;
+        self.compress(input, out_file, config)
     }
 
     /// Decompress an archive from a reader and write into a file.
-    pub fn decompress<P: AsRef<Path>, R: Read + Seek>(
+    /// Entries are extracted on `config.threads` threads.
+    /// If the password is missing or wrong, the password provider is asked and the archive is read again.
+    pub fn decompress<P: AsRef<Path>, R: Read + Seek + Send>(
+        self,
+        mut input: R,
+        output: P,
+        config: &ZipCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let output = output.as_ref();
+        let start = input.stream_position().map_err(ComdeError::from)?;
+        config.retry_password(|config| {
+            input.seek(std::io::SeekFrom::Start(start)).map_err(ComdeError::from)?;
+            self.decompress_once(&mut input, output, config)
+        })
+    }
+
+    fn decompress_once<R: Read + Seek + Send>(
+        self,
+        input: R,
+        output: &Path,
+        config: &ZipCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let sr = utils::SharedReader::new(input).map_err(ComdeError::from)?;
+        let time_begin = Instant::now();
+
+        let mut archive = zip::ZipArchive::new(sr.share()).map_err(ComdeError::from)?;
+        let encoding = config.resolve_name_encoding(&mut archive)?;
+        let next = AtomicUsize::new(0);
+        // Set when a worker fails, so the others stop before their next entry.
+        let stop = AtomicBool::new(false);
+        let results = std::thread::scope(|scope| {
+            let workers = (0..config.threads.min(archive.len()).max(1)).map(|_| {
+                let mut archive = archive.clone();
+                let (next, stop) = (&next, &stop);
+                scope.spawn(move || {
+                    let mut stats = vec![];
+                    loop {
+                        let index = next.fetch_add(1, Ordering::Relaxed);
+                        if index >= archive.len() || stop.load(Ordering::Relaxed) {
+                            return Ok(stats);
+                        }
+                        match extract_entry(&mut archive, index, output, config, encoding) {
+                            Ok(stat) => stats.push((index, stat)),
+                            Err(e) => {
+                                stop.store(true, Ordering::Relaxed);
+                                return Err(e);
+                            }
+                        }
+                    }
+                })
+            }).collect::<Vec<_>>();
+            workers.into_iter().map(|w| w.join().unwrap_or(Err(ComdeError::ZipError("Extracting thread panicked.".to_string())))).collect::<Vec<_>>()
+        });
+        let mut stats = results.into_iter().collect::<Result<Vec<_>, ComdeError>>()?.concat();
+        stats.sort_by_key(|(index, _)| *index);
+        let stats = stats.into_iter().map(|(_, stat)| stat).collect::<Vec<_>>();
+
+        let size_read = sr.bytes_read();
+        let size_decompressed = stats.iter().map(|s| s.size()).sum();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats))
+    }
+
+    /// Decompress an archive from a reader and pass its entries into a sink.
+    /// If the password is missing or wrong, the password provider is asked and the archive is read again,
+    /// so the sink may receive some entries again.
+    pub fn decompress_into<R: Read + Seek, S: Sink>(
+        self,
+        mut input: R,
+        sink: &mut S,
+        config: &ZipCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let start = input.stream_position().map_err(ComdeError::from)?;
+        config.retry_password(|config| {
+            input.seek(std::io::SeekFrom::Start(start)).map_err(ComdeError::from)?;
+            self.decompress_into_once(&mut input, sink, config)
+        })
+    }
+
+    fn decompress_into_once<R: Read + Seek, S: Sink>(
+        self,
+        input: R,
+        sink: &mut S,
+        config: &ZipCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let cr = utils::CountingReader::new(input);
+        let time_begin = Instant::now();
+
+        let mut size_decompressed = 0;
+        let mut archive = zip::ZipArchive::new(cr.share()).map_err(ComdeError::from)?;
+        let encoding = config.resolve_name_encoding(&mut archive)?;
+        let mut stats = Vec::with_capacity(archive.len());
+        for i in 0..archive.len() {
+            let name = archive.name_for_index(i).unwrap_or_default().to_string();
+            let mut file = match &config.password {
+                Some(password) => archive.by_index_decrypt(i, password.as_bytes()),
+                None => archive.by_index(i),
+            }.map_err(|e| ComdeError::from(e).in_entry(&name))?;
+            let name = entry_name(&file, encoding);
+            stats.push(file_stat(&file, &name));
+            if file.is_dir() {
+                sink.dir(&name).map_err(ComdeError::from)?;
+            } else {
+                let counted = utils::CountingReader::new(&mut file);
+                sink.file(&name, &mut counted.share()).map_err(ComdeError::from)?;
+                size_decompressed += counted.bytes_read();
+            }
+        }
+
+        let size_read = cr.bytes_read();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats))
+    }
+
+    /// Decompress an archive from a reader into memory, files are keyed by their names.
+    pub fn decompress_mem<R: Read + Seek>(
+        self,
+        input: R,
+        config: &ZipCfg,
+    ) -> Result<HashMap<String, Vec<u8>>, ComdeError> {
+        let mut files = HashMap::new();
+        self.decompress_into(input, &mut files, config)?;
+        Ok(files)
+    }
+
+    /// Decompress an archive from a reader that can't seek, such as a pipe or stdin,
+    /// and write into a file.
+    /// Entries are extracted one by one while walking through their local headers.
+    /// Encrypted entries and entries with sizes after their data can't be read this way,
+    /// so when one of them is met, the rest of the archive is copied into a temporary file,
+    /// and the entries from that one on are extracted from there.
+    pub fn decompress_stream<P: AsRef<Path>, R: Read>(
         self,
         input: R,
         output: P,
         config: &ZipCfg,
     ) -> Result<DecompressStatus, ComdeError> {
-        todo!()
+        let cr = utils::CountingReader::new(input);
+        let time_begin = Instant::now();
+
+        let output = output.as_ref();
+        let recording = Cell::new(false);
+        let mut tee = TeeReader { inner: cr.share(), recorded: vec![], recording: &recording };
+        let mut stats = vec![];
+        // Local headers are kept with their offsets, to make up the archive if an entry can't be streamed.
+        let mut headers = vec![];
+        let unsupported = loop {
+            let offset = cr.bytes_read();
+            recording.set(true);
+            match zip::read::read_zipfile_from_stream(&mut tee) {
+                Ok(Some(mut file)) => {
+                    recording.set(false);
+                    // Names can't be looked at all together here, so each one is guessed on its own.
+                    let encoding = config.name_encoding.resolve([file.name_raw()]);
+                    let name = entry_name(&file, encoding);
+                    stats.push(file_stat(&file, &name));
+                    unpack_file(&mut file, &name, output, config.xattrs)?;
+                }
+                Ok(None) => break false,
+                Err(zip::result::ZipError::UnsupportedArchive(_)) => break true,
+                Err(e) => return Err(ComdeError::from(e)),
+            }
+            headers.push((offset, std::mem::take(&mut tee.recorded)));
+        };
+        if unsupported {
+            // Entries with a data descriptor or that are encrypted need the central directory,
+            // so the archive is spooled from the local header of the first of them, at the same offset,
+            // with only the local headers of the entries extracted before it, and the rest are extracted from there.
+            let TeeReader { mut inner, recorded, .. } = tee;
+            let start = cr.bytes_read() - recorded.len() as u64;
+            let mut spool = tempfile::tempfile().map_err(ComdeError::from)?;
+            for (offset, header) in headers.iter().chain([&(start, recorded)]) {
+                spool.seek(std::io::SeekFrom::Start(*offset)).map_err(ComdeError::from)?;
+                spool.write_all(header).map_err(ComdeError::from)?;
+            }
+            std::io::copy(&mut inner, &mut spool).map_err(ComdeError::from)?;
+            stats.extend(config.retry_password(|config| extract_from(&mut spool, start, output, config))?);
+        }
+
+        let size_read = cr.bytes_read();
+        let size_decompressed = stats.iter().map(|s| s.size()).sum();
+        let time_finished = time_begin.elapsed();
+
+        Ok(DecompressStatus::new(size_read, size_decompressed, time_finished).with_entries(stats))
     }
 
+    /// Decompress an archive of given path and write into a file.
+    /// Given the first part `name.zip.001` of a volume set, all parts are read in turn,
+    /// and a split archive is read by its first part `name.z01` or its last part `name.zip`.
     pub fn decompress_f<P: AsRef<Path>>(
         self,
         input: P,
         output: P,
         config: &ZipCfg,
     ) -> Result<DecompressStatus, ComdeError> {
-        let (input, output) = (input.as_ref(), output.as_ref());
-        Err(Other(Unimplemented::Unfinished))
+        let input = input.as_ref();
+        let in_volumes = utils::VolumeReader::open(input).map_err(|e| ComdeError::io(input, e))?;
+        self.decompress(split::join(in_volumes, input)?, output, config)
     }
 }
 
-pub struct ZipCfg;
+#[cfg(feature = "async")]
+impl ZipComde {
+
+    /// Async version of [ZipComde::compress].
+    /// The archive is built in a temporary file on the blocking thread pool,
+    /// and then copied into the writer.
+    pub async fn compress_async<P: AsRef<Path>, W: tokio::io::AsyncWrite + Unpin>(
+        self,
+        input: &[P],
+        mut output: W,
+        config: &ZipCfg
+    ) -> Result<CompressStatus, ComdeError> {
+        let input = input.iter().map(|p| p.as_ref().to_path_buf()).collect::<Vec<_>>();
+        let config = config.clone();
+        let (status, spool) = utils::spawn_blocking(move || {
+            let mut spool = tempfile::tempfile().map_err(ComdeError::from)?;
+            let status = self.compress(&input, &mut spool, &config)?;
+            Ok::<_, ComdeError>((status, spool))
+        }).await.map_err(ComdeError::from)??;
+        utils::unspool_async_write(spool, &mut output).await.map_err(ComdeError::from)?;
+        Ok(status)
+    }
+
+    /// Async version of [ZipComde::compress_f], running on the blocking thread pool.
+    pub async fn compress_f_async<P: AsRef<Path>>(
+        self,
+        input: &[P],
+        output: P,
+        config: &ZipCfg,
+    ) -> Result<CompressStatus, ComdeError> {
+        let input = input.iter().map(|p| p.as_ref().to_path_buf()).collect::<Vec<_>>();
+        let output = output.as_ref().to_path_buf();
+        let config = config.clone();
+        utils::spawn_blocking(move || self.compress_f(&input, output, &config))
+            .await.map_err(ComdeError::from)?
+    }
+
+    /// Async version of [ZipComde::decompress].
+    /// The reader is spooled into a temporary file first, as zip archives need seeking.
+    pub async fn decompress_async<P: AsRef<Path>, R: tokio::io::AsyncRead + Unpin>(
+        self,
+        mut input: R,
+        output: P,
+        config: &ZipCfg
+    ) -> Result<DecompressStatus, ComdeError> {
+        let spool = utils::spool_async_read(&mut input).await.map_err(ComdeError::from)?;
+        let output = output.as_ref().to_path_buf();
+        let config = config.clone();
+        utils::spawn_blocking(move || self.decompress(spool, output, &config))
+            .await.map_err(ComdeError::from)?
+    }
+
+    /// Async version of [ZipComde::decompress_f], running on the blocking thread pool.
+    pub async fn decompress_f_async<P: AsRef<Path>>(
+        self,
+        input: P,
+        output: P,
+        config: &ZipCfg,
+    ) -> Result<DecompressStatus, ComdeError> {
+        let input = input.as_ref().to_path_buf();
+        let output = output.as_ref().to_path_buf();
+        let config = config.clone();
+        utils::spawn_blocking(move || self.decompress_f(input, output, &config))
+            .await.map_err(ComdeError::from)?
+    }
+}
+
+/// A reader that keeps a copy of the bytes read while `recording` is set.
+struct TeeReader<'a, R: Read> {
+    inner: R,
+    recorded: Vec<u8>,
+    recording: &'a Cell<bool>,
+}
+
+impl <R: Read> Read for TeeReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = self.inner.read(buf)?;
+        if self.recording.get() {
+            self.recorded.extend_from_slice(&buf[..bytes_read]);
+        }
+        Ok(bytes_read)
+    }
+}
+
+/// Extract the entries of the archive `input` whose local headers are at `start` or after, return their stats.
+fn extract_from<R: Read + Seek>(input: R, start: u64, output: &Path, config: &ZipCfg) -> Result<Vec<EntryStat>, ComdeError> {
+    let mut archive = zip::ZipArchive::new(input).map_err(ComdeError::from)?;
+    let encoding = config.resolve_name_encoding(&mut archive)?;
+    let mut stats = vec![];
+    for index in 0..archive.len() {
+        if archive.by_index_raw(index).map_err(ComdeError::from)?.header_start() >= start {
+            stats.push(extract_entry(&mut archive, index, output, config, encoding)?);
+        }
+    }
+    Ok(stats)
+}
+
+/// Extract the `index`-th entry of the archive into `output`, return its stats.
+fn extract_entry<R: Read + Seek>(
+    archive: &mut zip::ZipArchive<R>,
+    index: usize,
+    output: &Path,
+    config: &ZipCfg,
+    encoding: NameEncoding,
+) -> Result<EntryStat, ComdeError> {
+    let name = archive.name_for_index(index).unwrap_or_default().to_string();
+    let mut file = match &config.password {
+        Some(password) => archive.by_index_decrypt(index, password.as_bytes()),
+        None => archive.by_index(index),
+    }.map_err(|e| ComdeError::from(e).in_entry(&name))?;
+    let name = entry_name(&file, encoding);
+    unpack_file(&mut file, &name, output, config.xattrs)?;
+    Ok(file_stat(&file, &name))
+}
+
+/// Get the stats of an entry of `name`.
+fn file_stat<R: Read>(file: &zip::read::ZipFile<'_, R>, name: &str) -> EntryStat {
+    EntryStat::new(name, file.is_dir(), file.size(), Some(file.compressed_size()))
+}
+
+/// Get the name of an entry, names flagged as UTF-8 are always read so.
+pub(crate) fn entry_name<R: Read>(file: &zip::read::ZipFile<'_, R>, encoding: NameEncoding) -> String {
+    if file.get_metadata().is_utf8 {
+        return file.name().to_string();
+    }
+    encoding.decode(file.name_raw()).unwrap_or_else(|| file.name().to_string())
+}
+
+/// Get the path of an entry name in the output directory, `None` if it would escape from it.
+fn enclosed_path(name: &str) -> Option<PathBuf> {
+    let path = Path::new(name);
+    let enclosed = !name.contains('\0') && path.components().all(|c| matches!(c, Component::Normal(_) | Component::CurDir));
+    enclosed.then(|| path.to_path_buf())
+}
+
+/// Write an entry of `name` into `output`, refusing paths that would escape from it.
+/// With `xattrs`, its extended attributes are set too.
+fn unpack_file<R: Read>(file: &mut zip::read::ZipFile<'_, R>, name: &str, output: &Path, xattrs: bool) -> Result<(), ComdeError> {
+    let Some(relative) = enclosed_path(name) else {
+        return Err(ComdeError::PathTraversal { entry: name.to_string() });
+    };
+    let path = output.join(relative);
+    if file.is_dir() {
+        std::fs::create_dir_all(&path).map_err(|e| ComdeError::io(&path, e))?;
+    } else {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent).map_err(|e| ComdeError::io(parent, e))?;
+        }
+        let mut out_file = std::fs::File::create(&path).map_err(|e| ComdeError::io(&path, e))?;
+        utils::copy_entry(file, &mut out_file, name, &path)?;
+    }
+    if xattrs {
+        utils::restore_xattrs(&path, &xattrs_of(file))?;
+    }
+    Ok(())
+}
+
+/// The id of the extra field keeping extended attributes, `xa` in little endian.
+/// Zip has no standard field for them, so other tools skip it.
+const XATTR_EXTRA_ID: u16 = 0x6178;
+
+/// Pack extended attributes into the data of an extra field, each name and value after its u16 length.
+fn xattr_extra_field(xattrs: &[(String, Vec<u8>)]) -> Option<Vec<u8>> {
+    let mut data = vec![];
+    for (name, value) in xattrs {
+        for part in [name.as_bytes(), value] {
+            data.extend_from_slice(&u16::try_from(part.len()).ok()?.to_le_bytes());
+            data.extend_from_slice(part);
+        }
+    }
+    // The id and length of the field take 4 bytes of the 64 KiB of extra fields.
+    (data.len() <= u16::MAX as usize - 4).then_some(data)
+}
+
+/// Get the extended attributes of an entry from its extra fields, broken ones are dropped.
+fn xattrs_of<R: Read>(file: &zip::read::ZipFile<'_, R>) -> Vec<(String, Vec<u8>)> {
+    let mut fields = file.extra_data().unwrap_or_default();
+    let data = loop {
+        let [a, b, c, d, rest @ ..] = fields else { return vec![] };
+        let Some((data, next)) = rest.split_at_checked(u16::from_le_bytes([*c, *d]) as usize) else { return vec![] };
+        if u16::from_le_bytes([*a, *b]) == XATTR_EXTRA_ID {
+            break data;
+        }
+        fields = next;
+    };
+    let mut parts = vec![];
+    let mut data = data;
+    while let [a, b, rest @ ..] = data {
+        let Some((part, next)) = rest.split_at_checked(u16::from_le_bytes([*a, *b]) as usize) else { break };
+        parts.push(part);
+        data = next;
+    }
+    parts.chunks_exact(2)
+        .filter_map(|pair| Some((std::str::from_utf8(pair[0]).ok()?.to_string(), pair[1].to_vec())))
+        .collect()
+}
+
+/// Write files into the archive and finish it,
+/// return the size of the files, the stats of entries, the names of skipped inputs
+/// and the count of files stored as incompressible.
+/// With `stream`, the writer can't seek back, so encrypted entries are compressed into memory first even on one thread.
+/// The end of the archive is held in `tail` for the comments of entries, see [ZipCfg::release].
+fn write_archive<V: Vfs + ?Sized, P: AsRef<Path>, W: Write + Seek>(
+    vfs: &V,
+    input: &[P],
+    mut writer: zip::ZipWriter<W>,
+    tail: &comment::Tail,
+    config: &ZipCfg,
+    stream: bool,
+) -> Result<(u64, Vec<EntryStat>, Vec<String>, usize), ComdeError> {
+    let size_origin = utils::size_of_inarray_in(vfs, input).map_err(ComdeError::from)?;
+    config.apply_to(&mut writer)?;
+
+    let (mut entries, skipped) = utils::collect_entries(vfs, input).map_err(ComdeError::from)?;
+    if config.reproducible {
+        utils::make_reproducible(&mut entries);
+    }
+    if config.xattrs {
+        utils::read_xattrs(vfs, &mut entries)?;
+    }
+    let stores = entries.iter()
+        .map(|entry| Ok(!entry.is_dir && config.should_store(&entry.name, || utils::sample_in(vfs, &entry.path))?))
+        .collect::<Result<Vec<_>, ComdeError>>()?;
+    let compressed_sizes = if config.threads > 1 || (stream && config.password.is_some()) {
+        if stream && config.password.is_some() && entries.iter().any(|entry| !entry.is_dir && config.is_zip64(entry.metadata.len)) {
+            return Err(ComdeError::UnsupportedMethod("Encrypting Zip64 entries into a stream".to_string()));
+        }
+        compress_parallel(vfs, &mut writer, &entries, &stores, config)?
+    } else {
+        for (entry, &store) in entries.iter().zip(&stores) {
+            write_entry(vfs, &mut writer, entry, config.file_options_for(&entry.name, store, entry.metadata.len))?;
+        }
+        vec![None; entries.len()]
+    };
+    config.hold(tail);
+    writer.finish().map_err(ComdeError::from)?;
+
+    let stats = entries.iter().zip(compressed_sizes).map(|(entry, compressed_size)| {
+        let size = if entry.is_dir { 0 } else { entry.metadata.len };
+        EntryStat::new(&entry.name, entry.is_dir, size, compressed_size)
+    }).collect();
+    let stored = stores.iter().filter(|&&store| store).count();
+    Ok((size_origin, stats, skipped, stored))
+}
+
+/// Compress an entry and write it into the archive, with its modified time and permissions.
+fn write_entry<V: Vfs + ?Sized, W: Write + Seek>(
+    vfs: &V,
+    writer: &mut zip::ZipWriter<W>,
+    entry: &utils::SourceEntry,
+    mut options: FullFileOptions<'_>,
+) -> Result<(), ComdeError> {
+    if !entry.xattrs.is_empty() {
+        let data = xattr_extra_field(&entry.xattrs)
+            .ok_or_else(|| ComdeError::UnsupportedMethod(format!("Extended attributes of {} over 64 KiB", entry.name)))?;
+        options.add_extra_data(XATTR_EXTRA_ID, data.into_boxed_slice(), false).map_err(ComdeError::from)?;
+    }
+    if let Some(modified) = entry.metadata.modified {
+        options = options.last_modified_time(zip_datetime(modified));
+    }
+    if let Some(mode) = entry.metadata.mode {
+        options = options.unix_permissions(mode);
+    }
+    if entry.is_dir {
+        writer.add_directory(entry.name.as_str(), options).map_err(ComdeError::from)?;
+    } else {
+        let mut file = vfs.open(&entry.path).map_err(|e| ComdeError::io(&entry.path, e))?;
+        writer.start_file(entry.name.as_str(), options).map_err(ComdeError::from)?;
+        std::io::copy(&mut file, writer).map_err(ComdeError::from)?;
+    }
+    Ok(())
+}
+
+/// Write a file of `name` with `data` into the archive.
+fn write_data<W: Write + Seek>(writer: &mut zip::ZipWriter<W>, name: &str, options: FullFileOptions<'_>, data: &[u8]) -> Result<(), ComdeError> {
+    writer.start_file(name, options).map_err(ComdeError::from)?;
+    writer.write_all(data).map_err(ComdeError::from)
+}
+
+/// Convert a time into the MS-DOS time of zip, in UTC.
+/// Times out of its range 1980 - 2107 fall back to 1980-01-01 00:00:00.
+fn zip_datetime(time: SystemTime) -> zip::DateTime {
+    let secs = time.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, secs) = ((secs / 86400) as i64, secs % 86400);
+    // Civil date from days since 1970-01-01, see http://howardhinnant.github.io/date_algorithms.html
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    u16::try_from(year).ok()
+        .and_then(|year| zip::DateTime::from_date_and_time(
+            year, month as u8, day as u8,
+            (secs / 3600) as u8, (secs % 3600 / 60) as u8, (secs % 60) as u8,
+        ).ok())
+        .unwrap_or_default()
+}
+
+/// Compress entries on `config.threads` threads, each entry into a single-entry archive in memory,
+/// then copy the compressed entries into `writer` in the original order.
+/// Directories and Zip64 entries are written straight into `writer` in their turn instead,
+/// directories as they have nothing to compress, and Zip64 entries as they may not fit in memory
+/// and the zip writer can't copy them.
+/// Return the compressed size of each entry, `None` for those written straight.
+fn compress_parallel<V: Vfs + ?Sized, W: Write + Seek>(
+    vfs: &V,
+    writer: &mut zip::ZipWriter<W>,
+    entries: &[utils::SourceEntry],
+    stores: &[bool],
+    config: &ZipCfg,
+) -> Result<Vec<Option<u64>>, ComdeError> {
+    let next = AtomicUsize::new(0);
+    // Entries are only taken a window ahead of the one written, so few buffers wait for their turn.
+    let window = config.threads * 2;
+    let written = (Mutex::new(0usize), Condvar::new());
+    let (sender, receiver) = mpsc::sync_channel::<Compressed>(config.threads);
+
+    std::thread::scope(|scope| {
+        for _ in 0..config.threads.min(entries.len()) {
+            let sender = sender.clone();
+            let (next, written) = (&next, &written);
+            scope.spawn(move || {
+                loop {
+                    let index = next.fetch_add(1, Ordering::Relaxed);
+                    let Some(entry) = entries.get(index) else { break };
+                    let Ok(count) = written.0.lock() else { break };
+                    let Ok(count) = written.1.wait_while(count, |count| index >= count.saturating_add(window)) else { break };
+                    if *count == usize::MAX {
+                        break;
+                    }
+                    drop(count);
+                    if entry.is_dir || config.is_zip64(entry.metadata.len) {
+                        if sender.send((index, Ok(None))).is_err() {
+                            break;
+                        }
+                        continue;
+                    }
+                    let mut buffer = zip::ZipWriter::new(Cursor::new(Vec::new()));
+                    let result = write_entry(vfs, &mut buffer, entry, config.file_options_for(&entry.name, stores[index], entry.metadata.len))
+                        .and_then(|_| buffer.finish().map_err(ComdeError::from))
+                        .map(|buffer| Some(buffer.into_inner()));
+                    let failed = result.is_err();
+                    if sender.send((index, result)).is_err() || failed {
+                        break;
+                    }
+                }
+            });
+        }
+        drop(sender);
+
+        let result = write_in_order(vfs, writer, entries, stores, config, receiver, &written);
+        // Workers waiting for their turn stop, whether all is written or writing failed.
+        if let Ok(mut count) = written.0.lock() {
+            *count = usize::MAX;
+        }
+        written.1.notify_all();
+        result
+    })
+}
+
+/// An entry compressed into a buffer of an archive by its index, `None` if it's to be written straight.
+type Compressed = (usize, Result<Option<Vec<u8>>, ComdeError>);
+
+/// Write the buffers compressed by `compress_parallel` in the order of entries,
+/// counting those written in `written` to let workers go on.
+fn write_in_order<V: Vfs + ?Sized, W: Write + Seek>(
+    vfs: &V,
+    writer: &mut zip::ZipWriter<W>,
+    entries: &[utils::SourceEntry],
+    stores: &[bool],
+    config: &ZipCfg,
+    receiver: mpsc::Receiver<Compressed>,
+    written: &(Mutex<usize>, Condvar),
+) -> Result<Vec<Option<u64>>, ComdeError> {
+    // Buffers may finish out of order, keep them until it's their turn.
+    let mut pending = BTreeMap::new();
+    let mut compressed_sizes = Vec::with_capacity(entries.len());
+    for (index, result) in receiver {
+        pending.insert(index, result?);
+        while let Some(buffer) = pending.remove(&compressed_sizes.len()) {
+            let Some(buffer) = buffer else {
+                let index = compressed_sizes.len();
+                let entry = &entries[index];
+                write_entry(vfs, writer, entry, config.file_options_for(&entry.name, stores[index], entry.metadata.len))?;
+                compressed_sizes.push(None);
+                continue;
+            };
+            let index = compressed_sizes.len();
+            let mut archive = zip::ZipArchive::new(Cursor::new(buffer)).map_err(ComdeError::from)?;
+            let file = archive.by_index_raw(0).map_err(ComdeError::from)?;
+            compressed_sizes.push(Some(file.compressed_size()));
+            if entries[index].xattrs.is_empty() {
+                writer.raw_copy_file(file).map_err(ComdeError::from)?;
+            } else {
+                // Copying a file drops its extra fields, while merging keeps them.
+                drop(file);
+                writer.merge_archive(archive).map_err(ComdeError::from)?;
+            }
+        }
+        if let Ok(mut count) = written.0.lock() {
+            *count = compressed_sizes.len();
+        }
+        written.1.notify_all();
+    }
+    Ok(compressed_sizes)
+}
+
+/// Files from this size on are written with Zip64 sizes.
+/// It's under 4 GiB by a margin, as compressing incompressible data or encrypting makes it a little larger.
+const ZIP64_FILE_SIZE: u64 = u32::MAX as u64 - (u32::MAX as u64 >> 8);
+
+#[derive(Clone)]
+pub struct ZipCfg {
+    password: Option<String>,
+    password_provider: Option<Arc<dyn PasswordProvider>>,
+    password_attempts: usize,
+    method: CompressionMethod,
+    level: Option<i64>,
+    volume_size: Option<u64>,
+    threads: usize,
+    reproducible: bool,
+    rules: Vec<MethodRule>,
+    detect_incompressible: bool,
+    force_zip64: bool,
+    name_encoding: NameEncoding,
+    comment: Option<String>,
+    entry_comments: HashMap<String, String>,
+    xattrs: bool,
+}
+
+/// Files matching `pattern` are compressed with `method` and `level` instead.
+#[derive(Clone)]
+struct MethodRule {
+    pattern: String,
+    method: CompressionMethod,
+    level: Option<i64>,
+}
+
+/// # NameEncoding
+/// The encoding of entry names that are not flagged as UTF-8,
+/// as old tools on Windows write names in the code page of the system.
+/// Names flagged as UTF-8 or with a Unicode path extra field are always read as UTF-8.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum NameEncoding {
+    /// UTF-8 if all names are valid in it, otherwise the one of GBK and Shift-JIS
+    /// that the names look more like, or CP437 if they are valid in neither.
+    /// It's a guess, set the encoding if it's known.
+    #[default]
+    Auto,
+    Utf8,
+    /// The one of the zip spec, the code page of DOS in the US.
+    Cp437,
+    /// Simplified Chinese, names in GB18030 are read too.
+    Gbk,
+    /// Japanese.
+    ShiftJis,
+    /// Traditional Chinese.
+    Big5,
+    /// Korean.
+    EucKr,
+}
+
+impl NameEncoding {
+    /// Pick the encoding of all `names` if it's [NameEncoding::Auto], otherwise keep it.
+    pub(crate) fn resolve<'a>(self, names: impl IntoIterator<Item = &'a [u8]>) -> NameEncoding {
+        if self != NameEncoding::Auto {
+            return self;
+        }
+        let names = names.into_iter().collect::<Vec<_>>();
+        if names.iter().all(|name| std::str::from_utf8(name).is_ok()) {
+            return NameEncoding::Utf8;
+        }
+        // Ties go to GBK, as it's listed first.
+        let mut best = (0, NameEncoding::Cp437);
+        for encoding in [NameEncoding::Gbk, NameEncoding::ShiftJis] {
+            let texts = names.iter().map(|name| encoding.decode(name).filter(|text| !text.contains('\u{FFFD}'))).collect::<Option<Vec<_>>>();
+            if let Some(score) = texts.map(|texts| texts.iter().map(|text| cjk_score(text)).sum::<i64>()) && score > best.0 {
+                best = (score, encoding);
+            }
+        }
+        best.1
+    }
+
+    /// Decode a name, `None` for CP437 which the zip reader decodes by itself.
+    /// Bytes not valid in the encoding are replaced by `U+FFFD`.
+    fn decode(self, name: &[u8]) -> Option<String> {
+        let encoding = match self {
+            NameEncoding::Cp437 => return None,
+            NameEncoding::Auto => return self.resolve([name]).decode(name),
+            NameEncoding::Utf8 => return Some(String::from_utf8_lossy(name).into_owned()),
+            NameEncoding::Gbk => encoding_rs::GBK,
+            NameEncoding::ShiftJis => encoding_rs::SHIFT_JIS,
+            NameEncoding::Big5 => encoding_rs::BIG5,
+            NameEncoding::EucKr => encoding_rs::EUC_KR,
+        };
+        Some(encoding.decode_without_bom_handling(name).0.into_owned())
+    }
+}
+
+/// Score how much a text looks like Chinese or Japanese.
+/// Kana counts more than ideographs, as Japanese read as GBK gives rare ideographs but never kana,
+/// while Chinese read as Shift-JIS gives half-width katakana, which names hardly use.
+fn cjk_score(text: &str) -> i64 {
+    text.chars().map(|c| match c {
+        '\u{3040}'..='\u{30FF}' => 2,
+        '\u{4E00}'..='\u{9FFF}' => 1,
+        '\u{FF61}'..='\u{FF9F}' => -1,
+        _ => 0,
+    }).sum()
+}
+
+impl Default for ZipCfg {
+    fn default() -> Self {
+        Self::new()
+    }
+}
 
 impl ZipCfg {
-    
+
+    /// # Create a ZipCfg.
+    /// When you are not setting other algorithm params,
+    /// this crate uses `deflate` as default if feature `zip-deflate` is enabled,
+    /// otherwise files are only stored.
+    ///
+    /// # Method Chaning
+    /// When you try to call mutliple algorithm functions,
+    /// we only save your last call and its settings.
     pub fn new() -> ZipCfg {
-        ZipCfg
+        ZipCfg {
+            password: None,
+            password_provider: None,
+            password_attempts: 0,
+            #[cfg(feature = "zip-deflate")]
+            method: CompressionMethod::Deflated,
+            #[cfg(not(feature = "zip-deflate"))]
+            method: CompressionMethod::Stored,
+            level: None,
+            volume_size: None,
+            threads: 1,
+            reproducible: false,
+            rules: vec![],
+            detect_incompressible: false,
+            force_zip64: false,
+            name_encoding: NameEncoding::Auto,
+            comment: None,
+            entry_comments: HashMap::new(),
+            xattrs: false,
+        }
+    }
+
+    /// Set the password for compressing or decompressing, encrypt goes with AES256.
+    pub fn set_password(mut self, password: &str) -> ZipCfg {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Set a provider that is asked for the password when decompressing an encrypted entry
+    /// without a password or with a wrong one, at most `attempts` times.
+    pub fn set_password_provider<P: PasswordProvider + 'static>(mut self, provider: P, attempts: usize) -> ZipCfg {
+        self.password_provider = Some(Arc::new(provider));
+        self.password_attempts = attempts;
+        self
+    }
+
+    /// Run `job`, with passwords from the provider when the password is missing or wrong.
+    fn retry_password<T>(&self, job: impl FnMut(&ZipCfg) -> Result<T, ComdeError>) -> Result<T, ComdeError> {
+        password::retry(
+            self,
+            self.password_provider.as_deref(),
+            self.password_attempts,
+            |config, password| config.clone().set_password(password),
+            job,
+        )
+    }
+
+    /// Split the archive into volumes of `size` bytes when compressing with `compress_f`,
+    /// named `name.z01`, `name.z02`, ... and `name.zip`. The size is at least 64 KiB.
+    /// `None` writes a single file, which is the default.
+    pub fn set_volume_size(mut self, size: Option<u64>) -> ZipCfg {
+        self.volume_size = size;
+        self
+    }
+
+    /// Set the count of threads used to compress or decompress entries, 1 by default.
+    /// Entries are compressed in parallel into memory and then written in order,
+    /// so the archive is the same as a single-threaded one.
+    pub fn set_threads(mut self, threads: usize) -> ZipCfg {
+        self.threads = threads.max(1);
+        self
+    }
+
+    /// Switch whether to build the same archive from the same files on any machine.
+    /// Entries are sorted by name, their modified time is set to [utils::reproducible_mtime],
+    /// and permissions are fixed to `0o755` for directories and executables and `0o644` for other files.
+    /// Archives with a password still differ, as encrypting uses a random salt.
+    pub fn set_reproducible(mut self, enabled: bool) -> ZipCfg {
+        self.reproducible = enabled;
+        self
+    }
+
+    /// Store files without compression.
+    pub fn use_store(mut self) -> ZipCfg {
+        self.method = CompressionMethod::Stored;
+        self.level = None;
+        self
+    }
+
+    /// Use Deflate to compress.
+    /// Level range to 0 - 9 .
+    #[cfg(feature = "zip-deflate")]
+    pub fn use_deflate_with_level(mut self, level: u32) -> ZipCfg {
+        self.method = CompressionMethod::Deflated;
+        self.level = Some(level.min(9) as i64);
+        self
+    }
+
+    /// Use Zstd to compress.
+    /// Level range: 0 - 22 .
+    #[cfg(feature = "zip-zstd")]
+    pub fn use_zstd_with_level(mut self, level: u32) -> ZipCfg {
+        self.method = CompressionMethod::Zstd;
+        self.level = Some(level.min(22) as i64);
+        self
+    }
+
+    /// Use XZ (LZMA2) to compress.
+    /// Level range to 0 - 9 .
+    #[cfg(feature = "zip-lzma")]
+    pub fn use_xz_with_level(mut self, level: u32) -> ZipCfg {
+        self.method = CompressionMethod::Xz;
+        self.level = Some(level.min(9) as i64);
+        self
+    }
+
+    /// Use the method and level of a preset, and compress on all CPUs.
+    /// - `Store` stores files.
+    /// - `Fastest`, `Fast`, `Normal` and `Maximum` use Deflate of level 1, 3, 6 and 9, which any unzip tool reads.
+    /// - `Ultra` uses XZ of level 9 with feature `zip-lzma`, which some old tools can't read, otherwise it's the same as `Maximum`.
+    ///
+    /// Without feature `zip-deflate`, presets other than `Ultra` store files.
+    pub fn use_preset(self, preset: Preset) -> ZipCfg {
+        let config = self.set_threads(preset::preset_threads());
+        match preset {
+            Preset::Store => config.use_store(),
+            #[cfg(feature = "zip-lzma")]
+            Preset::Ultra => config.use_xz_with_level(9),
+            #[cfg(feature = "zip-deflate")]
+            preset => config.use_deflate_with_level(match preset {
+                Preset::Fastest => 1,
+                Preset::Fast => 3,
+                Preset::Normal => 6,
+                _ => 9,
+            }),
+            #[cfg(not(feature = "zip-deflate"))]
+            _ => config.use_store(),
+        }
+    }
+
+    /// Compress files matching `pattern` with the compress method of `config` instead,
+    /// such as `add_method_rule("*.jpg|*.png|*.zip", ZipCfg::new().use_store())`.
+    /// See [utils::matches_pattern] for patterns, the first matching rule is used.
+    pub fn add_method_rule(mut self, pattern: &str, config: ZipCfg) -> ZipCfg {
+        self.rules.push(MethodRule {
+            pattern: pattern.to_string(),
+            method: config.method,
+            level: config.level,
+        });
+        self
+    }
+
+    /// Switch whether to store files without compression when they look incompressible, off by default.
+    /// The head of each file is sampled by [utils::is_incompressible], files matching a method rule are not sampled.
+    pub fn set_detect_incompressible(mut self, enabled: bool) -> ZipCfg {
+        self.detect_incompressible = enabled;
+        self
+    }
+
+    /// Switch whether to write all entries and the end of the archive in Zip64, off by default.
+    /// Without it, Zip64 is only used where it's needed: for files of about 4 GiB or larger,
+    /// and for the end of the archive when it has more than 65535 entries or is larger than 4 GiB.
+    /// Some old tools can't read Zip64, while some tools that append to archives want it from the start.
+    /// Zip64 entries are compressed on one thread, so forcing it gives up `set_threads`.
+    pub fn set_force_zip64(mut self, enabled: bool) -> ZipCfg {
+        self.force_zip64 = enabled;
+        self
+    }
+
+    /// Set the encoding of entry names that are not flagged as UTF-8 when decompressing,
+    /// [NameEncoding::Auto] by default.
+    /// Names are always written in UTF-8 with the flag set where it matters, that is when they are not ASCII.
+    pub fn set_name_encoding(mut self, encoding: NameEncoding) -> ZipCfg {
+        self.name_encoding = encoding;
+        self
+    }
+
+    /// Set the comment of the archive, such as build metadata, at most 65535 bytes in UTF-8.
+    pub fn set_comment(mut self, comment: Option<&str>) -> ZipCfg {
+        self.comment = comment.map(str::to_string);
+        self
+    }
+
+    /// Set the comment of the entry `name`, the name in the archive such as `sub/file.txt`,
+    /// at most 65535 bytes in UTF-8. `None` removes the one set.
+    /// A comment out of ASCII is flagged as UTF-8 along with the name.
+    pub fn set_entry_comment(mut self, name: &str, comment: Option<&str>) -> ZipCfg {
+        let name = name.trim_end_matches('/');
+        match comment {
+            Some(comment) => self.entry_comments.insert(name.to_string(), comment.to_string()),
+            None => self.entry_comments.remove(name),
+        };
+        self
+    }
+
+    /// Get the comment of the entry `name`, directories are named with or without `/` at the end.
+    fn entry_comment(&self, name: &str) -> Option<&str> {
+        self.entry_comments.get(name.trim_end_matches('/')).map(String::as_str)
+    }
+
+    /// Hold the end of the archive in `tail` before finishing, if there are comments of entries to give.
+    fn hold(&self, tail: &comment::Tail) {
+        if !self.entry_comments.is_empty() {
+            tail.hold();
+        }
+    }
+
+    /// Write the end held in `tail` into `output` with the comments of entries, if any is held.
+    fn release<W: Write>(&self, tail: &comment::Tail, mut output: W) -> Result<(), ComdeError> {
+        tail.release(&mut output, |name| self.entry_comment(name))
+    }
+
+    /// Switch whether to keep extended attributes of entries, off by default.
+    /// They go into an extra field of each entry that only this crate reads, at most 64 KiB of them,
+    /// which takes SELinux labels, file capabilities and POSIX ACLs along on Linux.
+    /// Restoring `security.*` and `trusted.*` ones needs privileges. They are dropped off unix.
+    #[cfg(feature = "xattr")]
+    pub fn set_xattrs(mut self, enabled: bool) -> ZipCfg {
+        self.xattrs = enabled;
+        self
+    }
+
+    /// Pick the encoding of names of the archive, looking at all names not flagged as UTF-8.
+    fn resolve_name_encoding<R: Read + Seek>(&self, archive: &mut zip::ZipArchive<R>) -> Result<NameEncoding, ComdeError> {
+        if self.name_encoding != NameEncoding::Auto {
+            return Ok(self.name_encoding);
+        }
+        let mut names = vec![];
+        for i in 0..archive.len() {
+            let file = archive.by_index_raw(i).map_err(ComdeError::from)?;
+            if !file.get_metadata().is_utf8 {
+                names.push(file.name_raw().to_vec());
+            }
+        }
+        Ok(self.name_encoding.resolve(names.iter().map(Vec::as_slice)))
+    }
+
+    /// Check whether a file of `size` bytes is written with Zip64 sizes.
+    fn is_zip64(&self, size: u64) -> bool {
+        self.force_zip64 || size >= ZIP64_FILE_SIZE
+    }
+
+    /// Set the options of the whole archive to the writer.
+    /// The zip writer only writes the Zip64 end of the archive when it's needed or there's a Zip64 comment,
+    /// so an empty one forces it.
+    fn apply_to<W: Write + Seek>(&self, writer: &mut zip::ZipWriter<W>) -> Result<(), ComdeError> {
+        if let Some(comment) = &self.comment {
+            if comment.len() > u16::MAX as usize {
+                return Err(ComdeError::InvalidOption(format!("The zip comment of {} bytes is over 65535 bytes.", comment.len())));
+            }
+            writer.set_comment(comment.as_str());
+        }
+        if self.force_zip64 {
+            writer.set_zip64_comment(Some(""));
+        }
+        Ok(())
+    }
+
+    /// Check whether the file `name` is to be stored as incompressible, by the head of it from `sample`.
+    fn should_store(&self, name: &str, sample: impl FnOnce() -> Result<Vec<u8>, ComdeError>) -> Result<bool, ComdeError> {
+        if !self.detect_incompressible || self.rules.iter().any(|rule| utils::matches_pattern(&rule.pattern, name)) {
+            return Ok(false);
+        }
+        Ok(utils::is_incompressible(&sample()?))
+    }
+
+    /// Get the options of the entry `name` of `size` bytes, stored if `store`,
+    /// otherwise by the first matching rule or the default method.
+    fn file_options_for(&self, name: &str, store: bool, size: u64) -> FullFileOptions<'_> {
+        let (method, level) = match self.rules.iter().find(|rule| utils::matches_pattern(&rule.pattern, name)) {
+            _ if store => (CompressionMethod::Stored, None),
+            Some(rule) => (rule.method, rule.level),
+            None => (self.method, self.level),
+        };
+        let options = FileOptions::default()
+            .compression_method(method)
+            .compression_level(level)
+            .large_file(self.is_zip64(size));
+        match &self.password {
+            Some(password) => options.with_aes_encryption(zip::AesMode::Aes256, password),
+            None => options,
+        }
     }
 }
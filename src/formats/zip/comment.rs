@@ -0,0 +1,193 @@
+//! Comments of entries, which the zip writer can't set.
+//!
+//! The end of an archive, its central directory and end records, is held back while the zip writer finishes it.
+//! Then the record of each entry with a comment gets it, flagged as UTF-8 when the comment is out of ASCII,
+//! and the end records, the Zip64 ones included, get the new size of the central directory.
+use std::cell::RefCell;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::rc::Rc;
+
+use super::split::{self, CENTRAL_LEN, CENTRAL_SIGNATURE};
+use crate::error::ComdeError;
+
+/// The general purpose flag of a name and comment in UTF-8.
+const UTF8_FLAG: u16 = 1 << 11;
+
+/// The end of an archive held back by a [TailWriter], shared with the writer.
+#[derive(Clone, Default)]
+pub(super) struct Tail(Rc<RefCell<Held>>);
+
+#[derive(Default)]
+struct Held {
+    holding: bool,
+    /// The position of the first byte held, known once the zip writer writes or seeks after [Tail::hold].
+    start: Option<u64>,
+    bytes: Vec<u8>,
+}
+
+impl Tail {
+    /// Create a writer into `inner` that holds what it's given from [Tail::hold] on.
+    pub(super) fn writer<W: Write>(&self, inner: W) -> TailWriter<W> {
+        TailWriter { inner, position: 0, held: Rc::clone(&self.0) }
+    }
+
+    /// Hold back all written from here, that is the end of the archive when the zip writer is about to finish.
+    pub(super) fn hold(&self) {
+        self.0.borrow_mut().holding = true;
+    }
+
+    /// Give the entries held their comments by `comment` of each name, and write the end into `output`.
+    /// `output` is where the writer was at the start of the end, as the writer leaves it there.
+    pub(super) fn release<'a, W: Write>(&self, output: &mut W, comment: impl Fn(&str) -> Option<&'a str>) -> Result<(), ComdeError> {
+        let held = std::mem::take(&mut *self.0.borrow_mut());
+        let Some(start) = held.start else {
+            return Ok(());
+        };
+        let bytes = add_comments(start, &held.bytes, comment)?;
+        output.write_all(&bytes).map_err(ComdeError::from)
+    }
+}
+
+/// A writer that passes all to the inner writer until its [Tail] holds,
+/// then keeps what's written from there on in the [Tail].
+/// Updates before that, such as to the local header of the last entry, still go to the inner writer.
+pub(super) struct TailWriter<W> {
+    inner: W,
+    position: u64,
+    held: Rc<RefCell<Held>>,
+}
+
+impl<W: Write> Write for TailWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let mut held = self.held.borrow_mut();
+        let written = if !held.holding {
+            self.inner.write(buf)?
+        } else {
+            let start = *held.start.get_or_insert(self.position);
+            if self.position < start {
+                self.inner.write(&buf[..buf.len().min((start - self.position) as usize)])?
+            } else {
+                let at = (self.position - start) as usize;
+                if held.bytes.len() < at + buf.len() {
+                    held.bytes.resize(at + buf.len(), 0);
+                }
+                held.bytes[at..at + buf.len()].copy_from_slice(buf);
+                buf.len()
+            }
+        };
+        self.position += written as u64;
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl<W: Write + Seek> Seek for TailWriter<W> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let mut held = self.held.borrow_mut();
+        if !held.holding {
+            self.position = self.inner.seek(pos)?;
+            return Ok(self.position);
+        }
+        let start = *held.start.get_or_insert(self.position);
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => (start + held.bytes.len() as u64).checked_add_signed(offset),
+        };
+        let target = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeking out of the archive"))?;
+        // The inner writer stays at the start of the held bytes unless writing before them.
+        self.inner.seek(SeekFrom::Start(target.min(start)))?;
+        self.position = target;
+        Ok(target)
+    }
+}
+
+/// Add comments to the records of the central directory in `held`, the end of an archive from `start` on.
+fn add_comments<'a>(start: u64, held: &[u8], comment: impl Fn(&str) -> Option<&'a str>) -> Result<Vec<u8>, ComdeError> {
+    let end = split::read_end(&mut Shifted { bytes: held, start, position: start })?;
+    let central_start = end.central_offset.checked_sub(start)
+        .and_then(|at| usize::try_from(at).ok())
+        .ok_or_else(|| split::corrupt("The central directory is out of the end held"))?;
+    let central = held.get(central_start..central_start.saturating_add(end.central_size as usize))
+        .ok_or_else(|| split::corrupt("The central directory is out of the end held"))?;
+
+    let mut rewritten = held[..central_start].to_vec();
+    let mut at = 0;
+    while at < central.len() {
+        let mut header: [u8; CENTRAL_LEN] = split::field(central, at)?;
+        if !header.starts_with(&CENTRAL_SIGNATURE) {
+            return Err(split::corrupt("Invalid central directory record"));
+        }
+        let name_end = at + CENTRAL_LEN + split::u16_at(&header, 28)? as usize;
+        let comment_start = name_end + split::u16_at(&header, 30)? as usize;
+        let record_end = comment_start + split::u16_at(&header, 32)? as usize;
+        let record = central.get(at..record_end).ok_or_else(|| split::corrupt("Truncated central directory record"))?;
+        let name = &record[CENTRAL_LEN..name_end - at];
+
+        match std::str::from_utf8(name).ok().and_then(&comment) {
+            Some(comment) => {
+                let length = u16::try_from(comment.len()).map_err(|_| ComdeError::InvalidOption(format!(
+                    "The zip entry comment of {} bytes is over 65535 bytes.", comment.len(),
+                )))?;
+                header[32..34].copy_from_slice(&length.to_le_bytes());
+                if !comment.is_ascii() {
+                    let flags = split::u16_at(&header, 8)? | UTF8_FLAG;
+                    header[8..10].copy_from_slice(&flags.to_le_bytes());
+                }
+                rewritten.extend(header);
+                rewritten.extend(&record[CENTRAL_LEN..comment_start - at]);
+                rewritten.extend(comment.bytes());
+            }
+            None => rewritten.extend(record),
+        }
+        at = record_end;
+    }
+
+    let central_size = (rewritten.len() - central_start) as u64;
+    let end = split::End { central_size, ..end };
+    rewritten.extend(end.to_bytes(end.central_offset + central_size));
+    Ok(rewritten)
+}
+
+/// A reader of the end held, at its position in the archive.
+/// The bytes before it read as zeros, as the end records are searched in the last 64 KiB or so.
+struct Shifted<'a> {
+    bytes: &'a [u8],
+    start: u64,
+    position: u64,
+}
+
+impl Read for Shifted<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = match self.position.checked_sub(self.start) {
+            Some(at) => {
+                let rest = self.bytes.get(at as usize..).unwrap_or_default();
+                let read = rest.len().min(buf.len());
+                buf[..read].copy_from_slice(&rest[..read]);
+                read
+            }
+            None => {
+                let read = buf.len().min((self.start - self.position).try_into().unwrap_or(usize::MAX));
+                buf[..read].fill(0);
+                read
+            }
+        };
+        self.position += read as u64;
+        Ok(read)
+    }
+}
+
+impl Seek for Shifted<'_> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let target = match pos {
+            SeekFrom::Start(offset) => Some(offset),
+            SeekFrom::Current(offset) => self.position.checked_add_signed(offset),
+            SeekFrom::End(offset) => (self.start + self.bytes.len() as u64).checked_add_signed(offset),
+        };
+        self.position = target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Seeking out of the archive"))?;
+        Ok(self.position)
+    }
+}
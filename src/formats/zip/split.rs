@@ -0,0 +1,459 @@
+//! Split zip archives, whose volumes are `name.z01`, `name.z02`, ... and the last one `name.zip`.
+//!
+//! The first volume starts with the split signature, and the central directory tells
+//! the disk (volume) of each entry and the offset of it in that volume.
+//! The zip writer knows a single disk only, so a split archive is written into a temporary file first,
+//! and then cut into volumes with its central directory and end records rewritten.
+//! When reading, the volumes are joined and the central directory is rewritten back to a single disk.
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+
+use crate::comde::CompressStatus;
+use crate::error::ComdeError;
+use crate::utils::{self, VolumeReader};
+
+const SPLIT_SIGNATURE: [u8; 4] = *b"PK\x07\x08";
+pub(super) const CENTRAL_SIGNATURE: [u8; 4] = *b"PK\x01\x02";
+const END_SIGNATURE: [u8; 4] = *b"PK\x05\x06";
+const ZIP64_END_SIGNATURE: [u8; 4] = *b"PK\x06\x06";
+const ZIP64_LOCATOR_SIGNATURE: [u8; 4] = *b"PK\x06\x07";
+const ZIP64_EXTRA_ID: u16 = 0x0001;
+
+pub(super) const CENTRAL_LEN: usize = 46;
+const END_LEN: usize = 22;
+const ZIP64_END_LEN: usize = 56;
+const ZIP64_LOCATOR_LEN: usize = 20;
+
+/// The smallest volume size of a split archive, as 7-Zip and Info-ZIP take.
+pub(crate) const MIN_VOLUME_SIZE: u64 = 64 << 10;
+
+/// The end records of an archive, with the Zip64 ones merged in.
+pub(super) struct End {
+    pub(super) disk: u32,
+    pub(super) central_disk: u32,
+    pub(super) disk_entries: u64,
+    pub(super) entries: u64,
+    pub(super) central_size: u64,
+    pub(super) central_offset: u64,
+    /// The versions made by and needed of the Zip64 end record.
+    pub(super) versions: [u8; 4],
+    pub(super) comment: Vec<u8>,
+    pub(super) zip64: bool,
+}
+
+impl End {
+    fn needs_zip64(&self) -> bool {
+        self.zip64
+            || self.disk >= u16::MAX as u32
+            || self.central_disk >= u16::MAX as u32
+            || self.disk_entries >= u16::MAX as u64
+            || self.entries >= u16::MAX as u64
+            || self.central_size >= u32::MAX as u64
+            || self.central_offset >= u32::MAX as u64
+    }
+
+    pub(super) fn len(&self) -> u64 {
+        let zip64 = if self.needs_zip64() { ZIP64_END_LEN + ZIP64_LOCATOR_LEN } else { 0 };
+        (zip64 + END_LEN + self.comment.len()) as u64
+    }
+
+    /// Write the end records, the Zip64 one at `zip64_offset` of the last disk.
+    pub(super) fn to_bytes(&self, zip64_offset: u64) -> Vec<u8> {
+        let mut bytes = Vec::with_capacity(self.len() as usize);
+        if self.needs_zip64() {
+            bytes.extend(ZIP64_END_SIGNATURE);
+            bytes.extend((ZIP64_END_LEN as u64 - 12).to_le_bytes());
+            bytes.extend(self.versions);
+            bytes.extend(self.disk.to_le_bytes());
+            bytes.extend(self.central_disk.to_le_bytes());
+            bytes.extend(self.disk_entries.to_le_bytes());
+            bytes.extend(self.entries.to_le_bytes());
+            bytes.extend(self.central_size.to_le_bytes());
+            bytes.extend(self.central_offset.to_le_bytes());
+            bytes.extend(ZIP64_LOCATOR_SIGNATURE);
+            bytes.extend(self.disk.to_le_bytes());
+            bytes.extend(zip64_offset.to_le_bytes());
+            bytes.extend((self.disk + 1).to_le_bytes());
+        }
+        let short = |value: u64| u16::try_from(value).unwrap_or(u16::MAX).to_le_bytes();
+        let long = |value: u64| u32::try_from(value).unwrap_or(u32::MAX).to_le_bytes();
+        bytes.extend(END_SIGNATURE);
+        bytes.extend(short(self.disk as u64));
+        bytes.extend(short(self.central_disk as u64));
+        bytes.extend(short(self.disk_entries));
+        bytes.extend(short(self.entries));
+        bytes.extend(long(self.central_size));
+        bytes.extend(long(self.central_offset));
+        bytes.extend((self.comment.len() as u16).to_le_bytes());
+        bytes.extend(&self.comment);
+        bytes
+    }
+}
+
+/// Cut the archive that `write` writes into volumes of `volume_size` bytes:
+/// `output.z01`, `output.z02`, ... and the last one `output` itself.
+/// If the archive fits in one volume, it's written into `output` as is.
+pub(super) fn write<F>(output: &Path, volume_size: u64, write: F) -> Result<CompressStatus, ComdeError>
+where
+    F: FnOnce(&mut File) -> Result<CompressStatus, ComdeError>,
+{
+    if volume_size < MIN_VOLUME_SIZE {
+        return Err(ComdeError::InvalidOption(format!("The volume size of a zip archive must be at least {MIN_VOLUME_SIZE} bytes")));
+    }
+    let mut spool = tempfile::tempfile().map_err(ComdeError::from)?;
+    let status = write(&mut spool)?;
+    let io = |e| ComdeError::io(output, e);
+
+    if spool.seek(SeekFrom::End(0))? <= volume_size {
+        spool.seek(SeekFrom::Start(0))?;
+        let mut file = File::create(output).map_err(io)?;
+        std::io::copy(&mut spool, &mut file).map_err(io)?;
+        return Ok(status);
+    }
+
+    let end = read_end(&mut spool)?;
+    spool.seek(SeekFrom::Start(end.central_offset))?;
+    let mut central = Vec::with_capacity(end.central_size as usize);
+    (&mut spool).take(end.central_size).read_to_end(&mut central)?;
+
+    // Every offset moves by the split signature at the head of the first volume.
+    let head = SPLIT_SIGNATURE.len() as u64;
+    let locate = |position: u64| match u32::try_from(position / volume_size) {
+        Ok(disk) if disk < u32::MAX => Ok((disk, position % volume_size)),
+        _ => Err(ComdeError::InvalidOption("Too many volumes for a zip archive".to_string())),
+    };
+    let (central, starts) = rewrite_central(&central, end.entries, |_, offset| locate(head + offset))?;
+    let central_start = head + end.central_offset;
+    let data_end = central_start + central.len() as u64;
+    let (central_disk, central_offset) = locate(central_start)?;
+
+    // The end records are kept whole in the last volume.
+    let (mut disk, mut zip64_offset) = locate(data_end)?;
+    let mut last = End { disk, central_disk, disk_entries: 0, central_size: central.len() as u64, central_offset, ..end };
+    if zip64_offset + last.len() > volume_size {
+        (disk, zip64_offset) = (disk + 1, 0);
+        last.disk = disk;
+    }
+    if last.len() > volume_size {
+        return Err(ComdeError::InvalidOption("The comment of the archive doesn't fit in a volume".to_string()));
+    }
+    last.disk_entries = starts.iter().filter(|&&start| (central_start + start) / volume_size == disk as u64).count() as u64;
+
+    let mut volumes = Volumes::create(output, disk, volume_size).map_err(io)?;
+    volumes.write_all(&SPLIT_SIGNATURE).map_err(io)?;
+    spool.seek(SeekFrom::Start(0))?;
+    std::io::copy(&mut (&mut spool).take(end.central_offset), &mut volumes).map_err(io)?;
+    volumes.write_all(&central).map_err(io)?;
+    if volumes.disk < disk {
+        volumes.next().map_err(io)?;
+    }
+    volumes.write_all(&last.to_bytes(zip64_offset)).map_err(io)?;
+    volumes.flush().map_err(io)?;
+    Ok(status)
+}
+
+/// A reader of a zip archive of one or more volumes.
+/// The central directory of a split archive is replaced by one of a single disk.
+pub(crate) struct SplitReader {
+    inner: VolumeReader,
+    tail_start: u64,
+    tail: Vec<u8>,
+    pos: u64,
+}
+
+impl SplitReader {
+    pub(super) fn len(&self) -> u64 {
+        self.tail_start + self.tail.len() as u64
+    }
+}
+
+/// Join the volumes of a split archive that `path` is a part of,
+/// or read `volumes` opened from `path` as they are if it isn't split.
+pub(crate) fn join(volumes: VolumeReader, path: &Path) -> Result<SplitReader, ComdeError> {
+    let Some(last) = utils::zip_last_volume(path).filter(|last| utils::zip_volume_path(last, 1).is_file()) else {
+        return Ok(SplitReader { tail_start: volumes.len(), inner: volumes, tail: vec![], pos: 0 });
+    };
+    let io = |e| ComdeError::io(&last, e);
+    let end = read_end(&mut File::open(&last).map_err(io)?)?;
+    let mut paths = (1..=end.disk as usize).map(|index| utils::zip_volume_path(&last, index)).collect::<Vec<_>>();
+    paths.push(last.clone());
+    let mut inner = VolumeReader::from_paths(&paths).map_err(io)?;
+
+    let starts = inner.volume_sizes().scan(0, |start, size| {
+        let volume_start = *start;
+        *start += size;
+        Some(volume_start)
+    }).collect::<Vec<_>>();
+    let locate = |disk: u32, offset: u64| {
+        starts.get(disk as usize).map(|start| start + offset).ok_or_else(|| corrupt("Missing volume of a split archive"))
+    };
+    let central_start = locate(end.central_disk, end.central_offset)?;
+    inner.seek(SeekFrom::Start(central_start)).map_err(io)?;
+    let mut central = Vec::with_capacity(end.central_size as usize);
+    (&mut inner).take(end.central_size).read_to_end(&mut central).map_err(io)?;
+
+    let (mut tail, _) = rewrite_central(&central, end.entries, |disk, offset| Ok((0, locate(disk, offset)?)))?;
+    let joined = End { disk: 0, central_disk: 0, disk_entries: end.entries, central_size: tail.len() as u64, central_offset: central_start, ..end };
+    tail.extend(joined.to_bytes(central_start + tail.len() as u64));
+    Ok(SplitReader { inner, tail_start: central_start, tail, pos: 0 })
+}
+
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let n = if self.pos < self.tail_start {
+            let n = buf.len().min((self.tail_start - self.pos) as usize);
+            self.inner.seek(SeekFrom::Start(self.pos))?;
+            self.inner.read(&mut buf[..n])?
+        } else {
+            let rest = self.tail.get((self.pos - self.tail_start) as usize..).unwrap_or_default();
+            let n = buf.len().min(rest.len());
+            buf[..n].copy_from_slice(&rest[..n]);
+            n
+        };
+        self.pos += n as u64;
+        Ok(n)
+    }
+}
+
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = utils::seek_position(self.pos, self.len(), pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// The volumes being written, the one of disk `last` is the output itself.
+struct Volumes<'a> {
+    output: &'a Path,
+    last: u32,
+    volume_size: u64,
+    disk: u32,
+    file: File,
+    written: u64,
+}
+
+impl<'a> Volumes<'a> {
+    fn create(output: &'a Path, last: u32, volume_size: u64) -> std::io::Result<Self> {
+        let file = File::create(Self::path(output, last, 0))?;
+        Ok(Self { output, last, volume_size, disk: 0, file, written: 0 })
+    }
+
+    fn path(output: &Path, last: u32, disk: u32) -> std::path::PathBuf {
+        if disk == last { output.to_path_buf() } else { utils::zip_volume_path(output, disk as usize + 1) }
+    }
+
+    fn next(&mut self) -> std::io::Result<()> {
+        self.file.flush()?;
+        self.disk += 1;
+        self.file = File::create(Self::path(self.output, self.last, self.disk))?;
+        self.written = 0;
+        Ok(())
+    }
+}
+
+impl Write for Volumes<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.written == self.volume_size {
+            self.next()?;
+        }
+        let n = buf.len().min((self.volume_size - self.written) as usize);
+        let n = self.file.write(&buf[..n])?;
+        self.written += n as u64;
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Read the end records at the end of `input`, the Zip64 ones included.
+pub(super) fn read_end<R: Read + Seek>(input: &mut R) -> Result<End, ComdeError> {
+    let len = input.seek(SeekFrom::End(0))?;
+    let tail_len = len.min((END_LEN + u16::MAX as usize) as u64);
+    input.seek(SeekFrom::Start(len - tail_len))?;
+    let mut tail = vec![0; tail_len as usize];
+    input.read_exact(&mut tail)?;
+
+    let at = (0..=tail.len().checked_sub(END_LEN).ok_or_else(|| corrupt("Could not find the end of the central directory"))?)
+        .rev()
+        .find(|&at| {
+            tail[at..].starts_with(&END_SIGNATURE)
+                && u16_at(&tail, at + 20).is_ok_and(|comment_len| at + END_LEN + comment_len as usize == tail.len())
+        })
+        .ok_or_else(|| corrupt("Could not find the end of the central directory"))?;
+    let record = &tail[at..];
+    let mut end = End {
+        disk: u16_at(record, 4)? as u32,
+        central_disk: u16_at(record, 6)? as u32,
+        disk_entries: u16_at(record, 8)? as u64,
+        entries: u16_at(record, 10)? as u64,
+        central_size: u32_at(record, 12)? as u64,
+        central_offset: u32_at(record, 16)? as u64,
+        versions: [45, 0, 45, 0],
+        comment: record[END_LEN..].to_vec(),
+        zip64: false,
+    };
+
+    let end_start = len - tail_len + at as u64;
+    if end_start < ZIP64_LOCATOR_LEN as u64 {
+        return Ok(end);
+    }
+    input.seek(SeekFrom::Start(end_start - ZIP64_LOCATOR_LEN as u64))?;
+    let mut locator = [0; ZIP64_LOCATOR_LEN];
+    input.read_exact(&mut locator)?;
+    if !locator.starts_with(&ZIP64_LOCATOR_SIGNATURE) {
+        return Ok(end);
+    }
+    // The Zip64 end record is in the last volume too, which `input` is.
+    input.seek(SeekFrom::Start(u64_at(&locator, 8)?))?;
+    let mut record = [0; ZIP64_END_LEN];
+    input.read_exact(&mut record)?;
+    if !record.starts_with(&ZIP64_END_SIGNATURE) || u32_at(&locator, 4)? != u32_at(&record, 16)? {
+        return Err(corrupt("Invalid Zip64 end of the central directory"));
+    }
+    end.versions.copy_from_slice(&record[12..16]);
+    end.disk = u32_at(&record, 16)?;
+    end.central_disk = u32_at(&record, 20)?;
+    end.disk_entries = u64_at(&record, 24)?;
+    end.entries = u64_at(&record, 32)?;
+    end.central_size = u64_at(&record, 40)?;
+    end.central_offset = u64_at(&record, 48)?;
+    end.zip64 = true;
+    Ok(end)
+}
+
+/// Rewrite the disk and the offset of the local header of each record in the central directory with `locate`.
+/// Get the new central directory and the offset of each record in it.
+fn rewrite_central<F>(central: &[u8], entries: u64, mut locate: F) -> Result<(Vec<u8>, Vec<u64>), ComdeError>
+where
+    F: FnMut(u32, u64) -> Result<(u32, u64), ComdeError>,
+{
+    let mut rewritten = Vec::with_capacity(central.len());
+    let mut starts = vec![];
+    let mut at = 0;
+    while at < central.len() {
+        let mut header: [u8; CENTRAL_LEN] = field(central, at)?;
+        if !header.starts_with(&CENTRAL_SIGNATURE) {
+            return Err(corrupt("Invalid central directory record"));
+        }
+        let name_len = u16_at(&header, 28)? as usize;
+        let extra_len = u16_at(&header, 30)? as usize;
+        let comment_len = u16_at(&header, 32)? as usize;
+        let name_start = at + CENTRAL_LEN;
+        let extra_start = name_start + name_len;
+        let comment_start = extra_start + extra_len;
+        let record_end = comment_start + comment_len;
+        let extra = central.get(extra_start..comment_start).ok_or_else(|| corrupt("Truncated central directory record"))?;
+        let comment = central.get(comment_start..record_end).ok_or_else(|| corrupt("Truncated central directory record"))?;
+
+        let mut zip64 = &[][..];
+        let mut others: Vec<u8> = vec![];
+        let mut field_at = 0;
+        while field_at + 4 <= extra.len() {
+            let id = u16_at(extra, field_at)?;
+            let data_end = field_at + 4 + u16_at(extra, field_at + 2)? as usize;
+            let data = extra.get(field_at + 4..data_end).ok_or_else(|| corrupt("Truncated extra field"))?;
+            if id == ZIP64_EXTRA_ID {
+                zip64 = data;
+            } else {
+                others.extend(&extra[field_at..data_end]);
+            }
+            field_at = data_end;
+        }
+        others.extend(&extra[field_at..]);
+
+        let [uncompressed, compressed, offset, disk] = zip64_values(&header, zip64)?;
+
+        let (new_disk, new_offset) = locate(
+            disk.map_or(u16_at(&header, 34)? as u32, |disk| disk as u32),
+            offset.unwrap_or(u32_at(&header, 42)? as u64),
+        )?;
+        let offset_in_zip64 = offset.is_some() || new_offset >= u32::MAX as u64;
+        let disk_in_zip64 = disk.is_some() || new_disk >= u16::MAX as u32;
+        // Only the values whose main fields are all ones are written back, as the spec has them.
+        let mut new_zip64 = vec![];
+        uncompressed.into_iter().chain(compressed).for_each(|value| new_zip64.extend(value.to_le_bytes()));
+        if offset_in_zip64 {
+            new_zip64.extend(new_offset.to_le_bytes());
+        }
+        if disk_in_zip64 {
+            new_zip64.extend(new_disk.to_le_bytes());
+        }
+        header[34..36].copy_from_slice(&(if disk_in_zip64 { u16::MAX } else { new_disk as u16 }).to_le_bytes());
+        header[42..46].copy_from_slice(&(if offset_in_zip64 { u32::MAX } else { new_offset as u32 }).to_le_bytes());
+
+        let mut new_extra = vec![];
+        if !new_zip64.is_empty() {
+            new_extra.extend(ZIP64_EXTRA_ID.to_le_bytes());
+            new_extra.extend((new_zip64.len() as u16).to_le_bytes());
+            new_extra.extend(new_zip64);
+        }
+        new_extra.extend(others);
+        let new_extra_len = u16::try_from(new_extra.len()).map_err(|_| corrupt("Too long extra field"))?;
+        header[30..32].copy_from_slice(&new_extra_len.to_le_bytes());
+
+        starts.push(rewritten.len() as u64);
+        rewritten.extend(header);
+        rewritten.extend(&central[name_start..extra_start]);
+        rewritten.extend(new_extra);
+        rewritten.extend(comment);
+        at = record_end;
+    }
+    if starts.len() as u64 != entries {
+        return Err(corrupt("Wrong count of entries in the central directory"));
+    }
+    Ok((rewritten, starts))
+}
+
+/// Get the uncompressed size, the compressed size, the offset and the disk in the Zip64 extra field `data`
+/// of the central directory record `header`.
+/// As the spec says, each is there, in this order, when its main field is all ones.
+/// The zip crate also puts both sizes of a large file there though they fit in their main fields,
+/// so a size equal to its main field, with room left for the others, is skipped.
+fn zip64_values(header: &[u8; CENTRAL_LEN], mut data: &[u8]) -> Result<[Option<u64>; 4], ComdeError> {
+    let mains = [u32_at(header, 24)?, u32_at(header, 20)?, u32_at(header, 42)?];
+    let disk_main = u16_at(header, 34)?;
+    let needed = 8 * mains.iter().filter(|&&main| main == u32::MAX).count() + if disk_main == u16::MAX { 4 } else { 0 };
+    let mut spare = data.len().saturating_sub(needed);
+    let truncated = |_| corrupt("Truncated Zip64 extra field");
+
+    let mut values = [None; 4];
+    for (i, main) in mains.into_iter().enumerate() {
+        if main == u32::MAX {
+            values[i] = Some(u64_at(data, 0).map_err(truncated)?);
+            data = &data[8..];
+        } else if i < 2 && spare >= 8 && u64_at(data, 0)? == main as u64 {
+            spare -= 8;
+            data = &data[8..];
+        }
+    }
+    if disk_main == u16::MAX {
+        values[3] = Some(u32_at(data, 0).map_err(truncated)? as u64);
+    }
+    Ok(values)
+}
+
+pub(super) fn field<const N: usize>(bytes: &[u8], at: usize) -> Result<[u8; N], ComdeError> {
+    bytes.get(at..at + N).and_then(|field| field.try_into().ok()).ok_or_else(|| corrupt("Truncated zip record"))
+}
+
+pub(super) fn u16_at(bytes: &[u8], at: usize) -> Result<u16, ComdeError> {
+    field(bytes, at).map(u16::from_le_bytes)
+}
+
+fn u32_at(bytes: &[u8], at: usize) -> Result<u32, ComdeError> {
+    field(bytes, at).map(u32::from_le_bytes)
+}
+
+fn u64_at(bytes: &[u8], at: usize) -> Result<u64, ComdeError> {
+    field(bytes, at).map(u64::from_le_bytes)
+}
+
+pub(super) fn corrupt(message: &str) -> ComdeError {
+    ComdeError::Corrupt { entry: None, source: message.into() }
+}
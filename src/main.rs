@@ -1,33 +1,275 @@
-// use ziphere::{Configs, Formats, _comde::Compress};
-// use ziphere::_formats::_sevenz::{SevenzComde, SevenzConfigs};
-use clap::{ Parser, Subcommand};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+
+use clap::{Parser, Subcommand, ValueEnum};
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+use ziphere::bench::{self, Candidate};
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+use ziphere::password::PasswordProvider;
+use ziphere::preset::Preset;
+#[cfg(feature = "profiles")]
+use ziphere::preset::{Profile, Profiles};
+use ziphere::{Configs, Formats};
 
 fn main() {
-    println!("Hello, world!");
+    let app = App::parse();
 
-    // if let Formats::SevenZ(zip) = Formats::new_with("zip")
-    //     && let Configs::ZipConfig(zipc) = Configs::new_compress("zip")
-    // {
-    //     let result = zip.compress_file("input", "output", zipc).unwrap();
-    //     println!("{}", result);
-    // }
-    
-    ziphere::run_test();
+    let result = match app.command {
+        #[cfg(feature = "profiles")]
+        Commands::Compress { output, format, password, encrypt_headers, preset, comment, profile, profiles, inputs } => {
+            load_profile(profile.as_deref(), &profiles).and_then(|profile| {
+                let options = CompressOptions { password: password.as_deref(), encrypt_headers, preset, comment: comment.as_deref(), profile };
+                compress(&inputs, &output, format, &options, app.verbose)
+            })
+        }
+        #[cfg(not(feature = "profiles"))]
+        Commands::Compress { output, format, password, encrypt_headers, preset, comment, inputs } => {
+            let options = CompressOptions { password: password.as_deref(), encrypt_headers, preset, comment: comment.as_deref() };
+            compress(&inputs, &output, format, &options, app.verbose)
+        }
+        Commands::Extract { input, output, format, password, max_memory } => {
+            extract(&input, &output, format, password.as_deref(), max_memory, app.verbose)
+        }
+        #[cfg(any(feature = "zip", feature = "sevenz"))]
+        Commands::Bench { inputs, sample_size, budget } => {
+            run_bench(&inputs, sample_size, budget.map(std::time::Duration::from_secs_f64))
+        }
+    };
 
-    let app = App::parse();
-    
-    match app.command {
-        Commands::Hello => println!("Hello"),
+    if let Err(e) = result {
+        eprintln!("Error: {}", e);
+        std::process::exit(1);
+    }
+}
+
+/// Options of compressing given on the command line, which go over the profile.
+/// Some are taken by only one format, so they are left unread without its feature.
+#[cfg_attr(not(all(feature = "zip", feature = "sevenz")), allow(dead_code))]
+struct CompressOptions<'a> {
+    password: Option<&'a str>,
+    encrypt_headers: bool,
+    preset: Option<Preset>,
+    comment: Option<&'a str>,
+    #[cfg(feature = "profiles")]
+    profile: Profile,
+}
+
+/// Load the profile of `name` from the profiles file, an empty profile if no name is given.
+#[cfg(feature = "profiles")]
+fn load_profile(name: Option<&str>, path: &Path) -> Result<Profile, Box<dyn std::error::Error>> {
+    let Some(name) = name else {
+        return Ok(Profile::default());
+    };
+    let profiles = Profiles::load(path)?;
+    let profile = profiles.get(name)
+        .ok_or_else(|| format!("No profile named {} in {}.", name, path.display()))?;
+    Ok(profile.clone())
+}
+
+/// Compress inputs into the output archive, `-` writes the archive to stdout.
+/// The status goes to stderr, so it never mixes with the archive.
+#[cfg_attr(not(any(feature = "zip", feature = "sevenz")), allow(unused_variables))]
+fn compress(
+    inputs: &[PathBuf],
+    output: &str,
+    format: Option<ArchiveFormat>,
+    options: &CompressOptions,
+    verbose: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let format = format
+        .or_else(|| ArchiveFormat::from_path(Path::new(output)))
+        .ok_or("Can't detect the archive format of output, please set it by --format.")?;
+
+    let status = match format {
+        #[cfg(feature = "zip")]
+        ArchiveFormat::Zip => {
+            let mut config = zip_config(options.password).set_comment(options.comment);
+            #[cfg(feature = "profiles")]
+            {
+                config = options.profile.apply_zip(config);
+            }
+            if let Some(preset) = options.preset {
+                config = config.use_preset(preset);
+            }
+            match output {
+                "-" => Formats::get_zip().compress_stream(inputs, std::io::stdout().lock(), &config)?,
+                _ => Formats::get_zip().compress_f(inputs, PathBuf::from(output), &config)?,
+            }
+        }
+        #[cfg(feature = "sevenz")]
+        ArchiveFormat::SevenZ => {
+            let mut config = sevenz_config(options.password);
+            #[cfg(feature = "profiles")]
+            {
+                config = options.profile.apply_7z(config);
+            }
+            if let Some(preset) = options.preset {
+                config = config.use_preset(preset);
+            }
+            if options.encrypt_headers {
+                config = config.set_encrypt_headers(true);
+            }
+            match output {
+                "-" => Formats::get_7z().compress_stream(inputs, std::io::stdout().lock(), &config)?,
+                _ => Formats::get_7z().compress_f(inputs, PathBuf::from(output), &config)?,
+            }
+        }
+        #[cfg(feature = "tar")]
+        ArchiveFormat::Tar => {
+            let config = Configs::get_tar();
+            match output {
+                "-" => Formats::get_tar().compress(inputs, std::io::stdout().lock(), &config)?,
+                _ => Formats::get_tar().compress_f(inputs, PathBuf::from(output), &config)?,
+            }
+        }
+    };
+    if verbose {
+        print_entries(status.entries(), status.skipped());
+    }
+    eprintln!("Status: {}", status);
+    Ok(())
+}
+
+/// Extract the input archive into the output directory, `-` reads the archive from stdin.
+#[cfg_attr(not(feature = "sevenz"), allow(unused_variables))]
+fn extract(
+    input: &str,
+    output: &Path,
+    format: Option<ArchiveFormat>,
+    password: Option<&str>,
+    max_memory: Option<u64>,
+    verbose: bool,
+) -> Result<(), Box<dyn std::error::Error>> {
+    #[cfg(feature = "sevenz")]
+    let sevenz_extract_config = || sevenz_config(password).set_max_memory(max_memory);
+    let status = if input == "-" {
+        // Peek the head of stdin to detect the format, then read it again before the rest.
+        let mut head = Vec::with_capacity(512);
+        std::io::stdin().lock().take(512).read_to_end(&mut head)?;
+        let format = format
+            .or_else(|| ArchiveFormat::from_signature(&head))
+            .ok_or("Can't detect the archive format of input, please set it by --format.")?;
+        let stdin = std::io::Cursor::new(head).chain(std::io::stdin().lock());
+        match format {
+            #[cfg(feature = "zip")]
+            ArchiveFormat::Zip => Formats::get_zip().decompress_stream(stdin, output, &zip_config(password))?,
+            #[cfg(feature = "sevenz")]
+            ArchiveFormat::SevenZ => Formats::get_7z().decompress_stream(stdin, output, &sevenz_extract_config())?,
+            #[cfg(feature = "tar")]
+            ArchiveFormat::Tar => Formats::get_tar().decompress(stdin, output, &Configs::get_tar())?,
+        }
+    } else {
+        let input = Path::new(input);
+        let format = format
+            .or_else(|| ArchiveFormat::from_path(input))
+            .ok_or("Can't detect the archive format of input, please set it by --format.")?;
+        match format {
+            #[cfg(feature = "zip")]
+            ArchiveFormat::Zip => Formats::get_zip().decompress_f(input, output, &zip_config(password))?,
+            #[cfg(feature = "sevenz")]
+            ArchiveFormat::SevenZ => Formats::get_7z().decompress_f(input, output, &sevenz_extract_config())?,
+            #[cfg(feature = "tar")]
+            ArchiveFormat::Tar => Formats::get_tar().decompress_f(input, output, &Configs::get_tar())?,
+        }
+    };
+    if verbose {
+        print_entries(status.entries(), status.skipped());
     }
+    eprintln!("Status: {}", status);
+    Ok(())
+}
+
+/// Print entries with their sizes and then the skipped ones to stderr.
+fn print_entries(entries: &[ziphere::comde::EntryStat], skipped: &[String]) {
+    for entry in entries {
+        match entry.compressed_size() {
+            Some(compressed_size) => eprintln!("{:>12} {:>12}  {}", entry.size(), compressed_size, entry.name()),
+            None => eprintln!("{:>12} {:>12}  {}", entry.size(), "", entry.name()),
+        }
+    }
+    for name in skipped {
+        eprintln!("{:>12} {:>12}  {}", "skipped", "", name);
+    }
+}
+
+/// Try common methods on a sample of inputs, and print their results and the best one.
+/// Only methods of the features enabled are tried.
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+fn run_bench(
+    inputs: &[PathBuf],
+    sample_size: u64,
+    budget: Option<std::time::Duration>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let candidates: &[(&str, Candidate)] = &[
+        #[cfg(feature = "zip-deflate")]
+        ("zip deflate 6", Candidate::Zip(Configs::get_zip().use_deflate_with_level(6))),
+        #[cfg(feature = "zip-zstd")]
+        ("zip zstd 3", Candidate::Zip(Configs::get_zip().use_zstd_with_level(3))),
+        #[cfg(feature = "zip-lzma")]
+        ("zip xz 6", Candidate::Zip(Configs::get_zip().use_xz_with_level(6))),
+        #[cfg(feature = "sevenz")]
+        ("7z lzma2 5", Candidate::SevenZ(Configs::get_7z().set_solid_compress(true).use_lzma2_with_level(5))),
+        #[cfg(feature = "sevenz")]
+        ("7z lzma2 9", Candidate::SevenZ(Configs::get_7z().set_solid_compress(true).use_lzma2_with_level(9))),
+        #[cfg(feature = "sevenz-zstd")]
+        ("7z zstd 9", Candidate::SevenZ(Configs::get_7z().set_solid_compress(true).use_zstd_with_level(9))),
+        #[cfg(feature = "sevenz-bzip2")]
+        ("7z bzip2 9", Candidate::SevenZ(Configs::get_7z().set_solid_compress(true).use_bzip2_with_level(9))),
+        #[cfg(feature = "sevenz-ppmd")]
+        ("7z ppmd 6", Candidate::SevenZ(Configs::get_7z().set_solid_compress(true).use_ppmd_with_level(6))),
+    ];
+    let report = bench::evaluate_with(inputs, candidates, sample_size)?;
+    println!("{}", report);
+    match report.best(budget) {
+        Some(best) => println!("Best: {} (about {:.2}s for all inputs)", best.name(), report.estimated_time(best).as_secs_f64()),
+        None => println!("Best: none within the time budget"),
+    }
+    Ok(())
+}
+
+/// Times to prompt for the password of an encrypted archive when it's not given by `--password`.
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+const PASSWORD_ATTEMPTS: usize = 3;
 
+#[cfg(feature = "zip")]
+fn zip_config(password: Option<&str>) -> ziphere::formats::zip::ZipCfg {
+    match password {
+        Some(password) => Configs::get_zip().set_password(password),
+        None => Configs::get_zip().set_password_provider(TtyPrompt, PASSWORD_ATTEMPTS),
+    }
+}
+
+#[cfg(feature = "sevenz")]
+fn sevenz_config(password: Option<&str>) -> ziphere::formats::sevenz::SevenZCfg {
+    match password {
+        Some(password) => Configs::get_7z().set_password(password),
+        None => Configs::get_7z().set_password_provider(TtyPrompt, PASSWORD_ATTEMPTS),
+    }
+}
+
+/// Prompts for the password on the terminal without echo.
+/// Gives up when there's no terminal, such as in scripts.
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+struct TtyPrompt;
+
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+impl PasswordProvider for TtyPrompt {
+    fn password(&self, attempt: usize) -> Option<String> {
+        let prompt = match attempt {
+            0 => "Password: ".to_string(),
+            _ => format!("Wrong password, try again ({}/{}): ", attempt + 1, PASSWORD_ATTEMPTS),
+        };
+        rpassword::prompt_password(prompt).ok()
+    }
 }
 
 #[derive(Parser)]
 #[command(version, about)]
 struct App {
 
+    /// Print each entry compressed or extracted, and the skipped ones.
     #[arg(short, long)]
-    verbose: Option<String>,
+    verbose: bool,
 
     #[command(subcommand)]
     command: Commands,
@@ -35,5 +277,132 @@ struct App {
 
 #[derive(Subcommand)]
 enum Commands {
-    Hello
-}
\ No newline at end of file
+    /// Compress files and directories into an archive.
+    Compress {
+        /// The archive to write, `-` for stdout.
+        #[arg(short, long)]
+        output: String,
+
+        /// The archive format, detected by the extension of output if not set.
+        #[arg(short, long)]
+        format: Option<ArchiveFormat>,
+
+        /// Encrypt the archive with a password, ignored by tar.
+        #[arg(short, long)]
+        password: Option<String>,
+
+        /// Encrypt entry names too, so the archive can't be listed without the password, 7z only.
+        #[arg(long, requires = "password")]
+        encrypt_headers: bool,
+
+        /// How hard to compress: store, fastest, fast, normal, maximum or ultra, ignored by tar.
+        #[arg(long)]
+        preset: Option<Preset>,
+
+        /// A comment of the archive, such as build metadata, zip only.
+        #[arg(long)]
+        comment: Option<String>,
+
+        /// Use the settings of a named profile in the profiles file, which `--preset` goes over.
+        #[cfg(feature = "profiles")]
+        #[arg(long)]
+        profile: Option<String>,
+
+        /// The TOML file of profiles, see `[profile.<name>]` tables.
+        #[cfg(feature = "profiles")]
+        #[arg(long, default_value = "ziphere.toml")]
+        profiles: PathBuf,
+
+        /// Files and directories to compress.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+    },
+
+    /// Extract an archive into a directory.
+    Extract {
+        /// The archive to read, `-` for stdin.
+        input: String,
+
+        /// The directory to extract into.
+        #[arg(short, long, default_value = ".")]
+        output: PathBuf,
+
+        /// The archive format, detected by the extension or the signature of input if not set.
+        #[arg(short, long)]
+        format: Option<ArchiveFormat>,
+
+        /// Decrypt the archive with a password, ignored by tar.
+        #[arg(short, long)]
+        password: Option<String>,
+
+        /// Refuse an archive that needs more bytes of memory than this to extract, 7z only.
+        #[arg(long)]
+        max_memory: Option<u64>,
+    },
+
+    /// Try compress methods on a sample of files and tell which suits them.
+    #[cfg(any(feature = "zip", feature = "sevenz"))]
+    Bench {
+        /// Files and directories to sample.
+        #[arg(required = true)]
+        inputs: Vec<PathBuf>,
+
+        /// Bytes to sample from inputs.
+        #[arg(long, default_value_t = bench::DEFAULT_SAMPLE_SIZE)]
+        sample_size: u64,
+
+        /// Only pick methods guessed to compress all inputs within these seconds.
+        #[arg(long)]
+        budget: Option<f64>,
+    },
+}
+
+/// The formats of the features enabled.
+#[derive(Clone, Copy, ValueEnum)]
+enum ArchiveFormat {
+    #[cfg(feature = "zip")]
+    Zip,
+    #[cfg(feature = "sevenz")]
+    #[value(name = "7z")]
+    SevenZ,
+    #[cfg(feature = "tar")]
+    Tar,
+}
+
+impl ArchiveFormat {
+    /// Detect the format by the extension, the volume suffix `.001` is skipped
+    /// and a part `.z01`, `.z02`, ... of a split zip archive is taken as zip.
+    fn from_path(path: &Path) -> Option<ArchiveFormat> {
+        #[cfg(feature = "zip")]
+        if ziphere::utils::zip_last_volume(path).is_some() {
+            return Some(ArchiveFormat::Zip);
+        }
+        let path = if ziphere::utils::is_first_volume(path) { path.with_extension("") } else { path.to_path_buf() };
+        match path.extension()?.to_str()? {
+            #[cfg(feature = "zip")]
+            "zip" => Some(ArchiveFormat::Zip),
+            #[cfg(feature = "sevenz")]
+            "7z" => Some(ArchiveFormat::SevenZ),
+            #[cfg(feature = "tar")]
+            "tar" => Some(ArchiveFormat::Tar),
+            _ => None,
+        }
+    }
+
+    /// Detect the format by the signature at the head of an archive.
+    fn from_signature(head: &[u8]) -> Option<ArchiveFormat> {
+        #[cfg(feature = "zip")]
+        if head.starts_with(b"PK\x03\x04") || head.starts_with(b"PK\x07\x08") {
+            return Some(ArchiveFormat::Zip);
+        }
+        #[cfg(feature = "sevenz")]
+        if head.starts_with(&[b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C]) {
+            return Some(ArchiveFormat::SevenZ);
+        }
+        #[cfg(feature = "tar")]
+        if head.get(257..262) == Some(b"ustar") {
+            return Some(ArchiveFormat::Tar);
+        }
+        None
+    }
+}
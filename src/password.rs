@@ -0,0 +1,51 @@
+//! # Password
+//!
+//! Ask for a password only when an encrypted archive or entry is met,
+//! and ask again when it's wrong.
+//!
+//! Set a [PasswordProvider] by `set_password_provider` of the zip or 7z config.
+//! A closure `Fn(usize) -> Option<String>` is a provider too.
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+use crate::error::ComdeError;
+
+/// Gives passwords for encrypted archives.
+pub trait PasswordProvider: Send + Sync {
+    /// Give the password for the `attempt`-th try, counted from 0.
+    /// Return `None` to give up, then the error of the last try is returned.
+    fn password(&self, attempt: usize) -> Option<String>;
+}
+
+impl <F: Fn(usize) -> Option<String> + Send + Sync> PasswordProvider for F {
+    fn password(&self, attempt: usize) -> Option<String> {
+        self(attempt)
+    }
+}
+
+/// Run `job` with `config`, and when it fails for a missing or wrong password,
+/// run it again with the next password from `provider`, at most `attempts` times.
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+pub(crate) fn retry<C, T>(
+    config: &C,
+    provider: Option<&dyn PasswordProvider>,
+    attempts: usize,
+    set_password: impl Fn(&C, &str) -> C,
+    mut job: impl FnMut(&C) -> Result<T, ComdeError>,
+) -> Result<T, ComdeError> {
+    let mut current = None;
+    let mut attempt = 0;
+    loop {
+        match job(current.as_ref().unwrap_or(config)) {
+            Err(e @ (ComdeError::PasswordRequired | ComdeError::WrongPassword)) => {
+                let Some(provider) = provider.filter(|_| attempt < attempts) else {
+                    return Err(e);
+                };
+                let Some(password) = provider.password(attempt) else {
+                    return Err(e);
+                };
+                current = Some(set_password(config, &password));
+                attempt += 1;
+            }
+            result => return result,
+        }
+    }
+}
@@ -0,0 +1,189 @@
+//! # Preset
+//!
+//! Pick how hard to compress without knowing the levels of each method.
+//! - [Preset] is taken by `use_preset` of the zip and 7z configs.
+//! - [Profiles] are named presets with other settings, loaded from a TOML file
+//!   so a team can share the same settings, with feature `profiles`.
+//!
+//! A profile file looks like:
+//! ```toml
+//! [profile.backup]
+//! preset = "ultra"
+//! solid = true
+//! reproducible = true
+//!
+//! [profile.quick]
+//! preset = "fastest"
+//! threads = 4
+//! ```
+use std::str::FromStr;
+
+#[cfg(feature = "profiles")]
+use std::collections::BTreeMap;
+#[cfg(feature = "profiles")]
+use std::path::Path;
+
+#[cfg(feature = "profiles")]
+use crate::error::ProfileError;
+
+/// How hard to compress, from storing only to the smallest archive.
+/// Each format maps it to its own method, level and threads, see `use_preset` of the configs.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(any(feature = "serde", feature = "profiles"), derive(serde::Serialize, serde::Deserialize))]
+#[cfg_attr(any(feature = "serde", feature = "profiles"), serde(rename_all = "lowercase"))]
+pub enum Preset {
+    Store,
+    Fastest,
+    Fast,
+    Normal,
+    Maximum,
+    Ultra,
+}
+
+impl Preset {
+    /// All presets, from the fastest to the smallest.
+    pub const ALL: [Preset; 6] = [Preset::Store, Preset::Fastest, Preset::Fast, Preset::Normal, Preset::Maximum, Preset::Ultra];
+
+    /// Get the lowercase name, the same one parsed by [FromStr].
+    pub fn name(&self) -> &'static str {
+        match self {
+            Preset::Store => "store",
+            Preset::Fastest => "fastest",
+            Preset::Fast => "fast",
+            Preset::Normal => "normal",
+            Preset::Maximum => "maximum",
+            Preset::Ultra => "ultra",
+        }
+    }
+}
+
+impl std::fmt::Display for Preset {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
+/// Parse a name of [Preset::name], ignoring ASCII case.
+impl FromStr for Preset {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Preset::ALL.into_iter()
+            .find(|preset| preset.name().eq_ignore_ascii_case(s))
+            .ok_or_else(|| format!("Unknown preset {}, expected one of store, fastest, fast, normal, maximum, ultra.", s))
+    }
+}
+
+/// Get the count of threads presets compress with, which is the count of CPUs.
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+pub(crate) fn preset_threads() -> usize {
+    std::thread::available_parallelism().map_or(1, |n| n.get())
+}
+
+/// Settings of a named profile, those not set are left as they are in the config.
+/// Settings a format doesn't have are ignored by it, such as `solid` by zip.
+#[cfg(feature = "profiles")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    pub preset: Option<Preset>,
+    /// Threads to compress with, by the preset if there's one.
+    pub threads: Option<usize>,
+    pub solid: Option<bool>,
+    pub volume_size: Option<u64>,
+    pub reproducible: Option<bool>,
+    pub encrypt_headers: Option<bool>,
+    pub detect_incompressible: Option<bool>,
+}
+
+#[cfg(feature = "profiles")]
+impl Profile {
+    /// Apply the profile to a zip config, the preset goes first, so `threads` overrides the one of it.
+    #[cfg(feature = "zip")]
+    pub fn apply_zip(&self, mut config: crate::formats::zip::ZipCfg) -> crate::formats::zip::ZipCfg {
+        if let Some(preset) = self.preset {
+            config = config.use_preset(preset);
+        }
+        if let Some(threads) = self.threads {
+            config = config.set_threads(threads);
+        }
+        if let Some(size) = self.volume_size {
+            config = config.set_volume_size(Some(size));
+        }
+        if let Some(enabled) = self.reproducible {
+            config = config.set_reproducible(enabled);
+        }
+        if let Some(enabled) = self.detect_incompressible {
+            config = config.set_detect_incompressible(enabled);
+        }
+        config
+    }
+
+    /// Apply the profile to a 7z config, `threads` is taken by the preset if there's one,
+    /// otherwise it's set by [SevenZCfg::set_compress_threads](crate::formats::sevenz::SevenZCfg::set_compress_threads).
+    #[cfg(feature = "sevenz")]
+    pub fn apply_7z(&self, mut config: crate::formats::sevenz::SevenZCfg) -> crate::formats::sevenz::SevenZCfg {
+        match (self.preset, self.threads) {
+            (Some(preset), Some(threads)) => config = config.use_preset_with_threads(preset, threads),
+            (Some(preset), None) => config = config.use_preset(preset),
+            (None, Some(threads)) => config = config.set_compress_threads(threads),
+            (None, None) => {}
+        }
+        if let Some(enabled) = self.solid {
+            config = config.set_solid_compress(enabled);
+        }
+        if let Some(size) = self.volume_size {
+            config = config.set_volume_size(Some(size));
+        }
+        if let Some(enabled) = self.reproducible {
+            config = config.set_reproducible(enabled);
+        }
+        if let Some(enabled) = self.encrypt_headers {
+            config = config.set_encrypt_headers(enabled);
+        }
+        if let Some(enabled) = self.detect_incompressible {
+            config = config.set_detect_incompressible(enabled);
+        }
+        config
+    }
+}
+
+/// Named profiles, from the `[profile.<name>]` tables of a TOML file.
+#[cfg(feature = "profiles")]
+#[derive(Debug, Clone, Default, PartialEq, Eq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Profiles {
+    #[serde(default)]
+    profile: BTreeMap<String, Profile>,
+}
+
+#[cfg(feature = "profiles")]
+impl Profiles {
+    /// Load profiles from a TOML file.
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Profiles, ProfileError> {
+        let path = path.as_ref();
+        let text = std::fs::read_to_string(path)
+            .map_err(|source| ProfileError::Io { path: path.to_path_buf(), source })?;
+        text.parse()
+    }
+
+    /// Get the profile of `name`.
+    pub fn get(&self, name: &str) -> Option<&Profile> {
+        self.profile.get(name)
+    }
+
+    /// Get the names of all profiles, sorted.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.profile.keys().map(String::as_str)
+    }
+}
+
+/// Parse profiles from the text of a TOML file.
+#[cfg(feature = "profiles")]
+impl FromStr for Profiles {
+    type Err = ProfileError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(|e| ProfileError::Parse(e.to_string()))
+    }
+}
@@ -1,9 +1,11 @@
 //! Utils
 //!
 
-use std::{cell::RefCell, io::{Seek, Write, Read}, path::Path, rc::Rc};
-use crate::error::AppError;
-use walkdir::WalkDir;
+use std::{cell::RefCell, fs::File, io::{Seek, SeekFrom, Write, Read}, path::{Path, PathBuf}, rc::Rc};
+use std::sync::{Arc, Mutex, atomic::{AtomicU64, Ordering}};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use crate::error::{AppError, ComdeError};
+use crate::vfs::{Metadata, RealFs, Vfs};
 
 /// The wrapper for type [std::io::Write] .
 /// We use this to count written bytes.
@@ -115,35 +117,552 @@ impl <R: Read + Seek> Seek for CountingReader<R> {
         self.inner.borrow_mut().seek(pos)
     }
 }
+/// The wrapper for type [std::io::Read] + [std::io::Seek] that can be shared between threads.
+/// Every shared one keeps its own position, so they can read different parts at the same time.
+/// We also count read bytes of all shared ones.
+/// Positions are relative to where the reader is when it's wrapped, so data after a head is read as if it starts at 0.
+pub struct SharedReader<R: Read + Seek> {
+    inner: Arc<Mutex<R>>,
+    bytes_read: Arc<AtomicU64>,
+    start: u64,
+    pos: u64,
+}
+
+impl <R: Read + Seek> SharedReader<R> {
+    /// Create a new SharedReader, starting at the current position of `reader`.
+    pub fn new(mut reader: R) -> std::io::Result<Self> {
+        let start = reader.stream_position()?;
+        Ok(Self {
+            inner: Arc::new(Mutex::new(reader)),
+            bytes_read: Arc::new(AtomicU64::new(0)),
+            start,
+            pos: 0,
+        })
+    }
+
+    /// Get the read bytes of all shared Readers.
+    pub fn bytes_read(&self) -> u64 {
+        self.bytes_read.load(Ordering::Relaxed)
+    }
+
+    /// Return a new SharedReader that shares inner data, starting at the same position.
+    pub fn share(&self) -> Self {
+        Self {
+            inner: Arc::clone(&self.inner),
+            bytes_read: Arc::clone(&self.bytes_read),
+            start: self.start,
+            pos: self.pos,
+        }
+    }
+
+    fn lock(&self) -> std::io::Result<std::sync::MutexGuard<'_, R>> {
+        self.inner.lock().map_err(|_| std::io::Error::other("The shared reader is poisoned."))
+    }
+}
+
+impl <R: Read + Seek> Clone for SharedReader<R> {
+    fn clone(&self) -> Self {
+        self.share()
+    }
+}
+
+impl <R: Read + Seek> Read for SharedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let bytes_read = {
+            let mut inner = self.lock()?;
+            inner.seek(SeekFrom::Start(self.start + self.pos))?;
+            inner.read(buf)?
+        };
+        self.pos += bytes_read as u64;
+        self.bytes_read.fetch_add(bytes_read as u64, Ordering::Relaxed);
+        Ok(bytes_read)
+    }
+}
+
+impl <R: Read + Seek> Seek for SharedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        let len = match pos {
+            SeekFrom::End(_) => self.lock()?.seek(SeekFrom::End(0))?.saturating_sub(self.start),
+            _ => 0,
+        };
+        self.pos = seek_position(self.pos, len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// Get the path of the `index`-th volume (start from 1) of a volume set,
+/// e.g. `name.7z` -> `name.7z.001`.
+pub fn volume_path<P: AsRef<Path>>(base: P, index: usize) -> PathBuf {
+    let mut name = base.as_ref().as_os_str().to_os_string();
+    name.push(format!(".{:03}", index));
+    PathBuf::from(name)
+}
+
+/// Check whether the path looks like the first part of a volume set,
+/// that is, ends with `.001` .
+pub fn is_first_volume<P: AsRef<Path>>(path: P) -> bool {
+    path.as_ref().extension().is_some_and(|ext| ext == "001")
+}
+
+/// Get the path of the `index`-th volume (start from 1) of a split zip archive
+/// whose last part is `last`, e.g. `name.zip` -> `name.z01`.
+pub fn zip_volume_path<P: AsRef<Path>>(last: P, index: usize) -> PathBuf {
+    last.as_ref().with_extension(format!("z{:02}", index))
+}
 
+/// Get the last part `name.zip` of a split zip archive from any of its parts,
+/// or `None` if the path is neither `name.zip` nor `name.zNN`.
+pub fn zip_last_volume<P: AsRef<Path>>(path: P) -> Option<PathBuf> {
+    let path = path.as_ref();
+    let ext = path.extension()?.to_str()?;
+    let is_part = ext.len() > 1 && ext.starts_with(['z', 'Z']) && ext[1..].bytes().all(|b| b.is_ascii_digit());
+    (is_part || ext.eq_ignore_ascii_case("zip")).then(|| path.with_extension("zip"))
+}
+
+/// A [std::io::Write] + [std::io::Seek] that splits its output into
+/// several files (volumes) of a fixed size: `name.001`, `name.002`, ...
+///
+/// Volumes are created lazily, so the last one may be smaller.
+pub struct VolumeWriter {
+    base: PathBuf,
+    volume_size: u64,
+    volumes: Vec<File>,
+    pos: u64,
+    len: u64,
+}
+
+impl VolumeWriter {
+    /// Create a new VolumeWriter.
+    /// `base` is the archive path without the volume suffix.
+    pub fn new<P: AsRef<Path>>(base: P, volume_size: u64) -> std::io::Result<Self> {
+        if volume_size == 0 {
+            return Err(std::io::Error::new(std::io::ErrorKind::InvalidInput, "Volume size must be greater than 0."));
+        }
+        Ok(Self {
+            base: base.as_ref().to_path_buf(),
+            volume_size,
+            volumes: vec![],
+            pos: 0,
+            len: 0,
+        })
+    }
+
+    /// Get the paths of all volumes created so far.
+    pub fn volume_paths(&self) -> Vec<PathBuf> {
+        (1..=self.volumes.len()).map(|i| volume_path(&self.base, i)).collect()
+    }
+
+    fn volume(&mut self, index: usize) -> std::io::Result<&mut File> {
+        while self.volumes.len() <= index {
+            let path = volume_path(&self.base, self.volumes.len() + 1);
+            let file = File::options().read(true).write(true).create(true).truncate(true).open(path)?;
+            self.volumes.push(file);
+        }
+        Ok(&mut self.volumes[index])
+    }
+}
+
+impl Write for VolumeWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        let index = (self.pos / self.volume_size) as usize;
+        let offset = self.pos % self.volume_size;
+        let n = buf.len().min((self.volume_size - offset) as usize);
+        let volume = self.volume(index)?;
+        volume.seek(SeekFrom::Start(offset))?;
+        let n = volume.write(&buf[..n])?;
+        self.pos += n as u64;
+        self.len = self.len.max(self.pos);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.volumes.iter_mut().try_for_each(|v| v.flush())
+    }
+}
+
+impl Seek for VolumeWriter {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = seek_position(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+/// A [std::io::Read] + [std::io::Seek] that reads a volume set
+/// (`name.001`, `name.002`, ... or `name.z01`, `name.z02`, ..., `name.zip`) as one continuous stream.
+pub struct VolumeReader {
+    volumes: Vec<(File, u64)>,
+    pos: u64,
+    len: u64,
+}
+
+impl VolumeReader {
+    /// Open a volume set by its first part.
+    /// A split zip archive is opened by its first part `name.z01` or its last part `name.zip`.
+    /// If the path is neither, it is read as a single file.
+    pub fn open<P: AsRef<Path>>(first: P) -> std::io::Result<Self> {
+        let first = first.as_ref();
+        let mut paths = vec![first.to_path_buf()];
+        if is_first_volume(first) {
+            let base = first.with_extension("");
+            let mut index = 2;
+            while volume_path(&base, index).is_file() {
+                paths.push(volume_path(&base, index));
+                index += 1;
+            }
+        } else if let Some(last) = zip_last_volume(first).filter(|last| zip_volume_path(last, 1).is_file()) {
+            paths = (1..).map(|i| zip_volume_path(&last, i)).take_while(|path| path.is_file()).collect();
+            paths.push(last);
+        }
+        Self::from_paths(&paths)
+    }
+
+    /// Open the volumes of given paths, in that order.
+    pub fn from_paths<P: AsRef<Path>>(paths: &[P]) -> std::io::Result<Self> {
+        let mut volumes = Vec::with_capacity(paths.len());
+        for path in paths {
+            let file = File::open(path)?;
+            let size = file.metadata()?.len();
+            volumes.push((file, size));
+        }
+        let len = volumes.iter().map(|(_, size)| size).sum();
+        Ok(Self { volumes, pos: 0, len })
+    }
+
+    /// Get the total size of all volumes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Check whether all volumes are empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Get the sizes of the volumes, in order.
+    pub fn volume_sizes(&self) -> impl Iterator<Item = u64> + '_ {
+        self.volumes.iter().map(|(_, size)| *size)
+    }
+}
+
+impl Read for VolumeReader {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut start = 0u64;
+        for (file, size) in self.volumes.iter_mut() {
+            if self.pos < start + *size {
+                let offset = self.pos - start;
+                let n = buf.len().min((*size - offset) as usize);
+                file.seek(SeekFrom::Start(offset))?;
+                let n = file.read(&mut buf[..n])?;
+                self.pos += n as u64;
+                return Ok(n);
+            }
+            start += *size;
+        }
+        Ok(0)
+    }
+}
+
+impl Seek for VolumeReader {
+    fn seek(&mut self, pos: SeekFrom) -> std::io::Result<u64> {
+        self.pos = seek_position(self.pos, self.len, pos)?;
+        Ok(self.pos)
+    }
+}
+
+pub(crate) fn seek_position(current: u64, len: u64, pos: SeekFrom) -> std::io::Result<u64> {
+    let target = match pos {
+        SeekFrom::Start(n) => Some(n),
+        SeekFrom::End(n) => len.checked_add_signed(n),
+        SeekFrom::Current(n) => current.checked_add_signed(n),
+    };
+    target.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidInput, "Invalid seek to a negative or overflowing position."))
+}
+
+/// Run a blocking job on the blocking thread pool of tokio.
+#[cfg(feature = "async")]
+pub(crate) async fn spawn_blocking<T, F>(job: F) -> std::io::Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    tokio::task::spawn_blocking(job).await.map_err(std::io::Error::other)
+}
+
+/// Spool an async reader into a temporary file, so that it can be read with seeking.
+/// The file is deleted once it's dropped.
+#[cfg(feature = "async")]
+pub(crate) async fn spool_async_read<R: tokio::io::AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<File> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut spool = tokio::fs::File::from_std(tempfile::tempfile()?);
+    tokio::io::copy(reader, &mut spool).await?;
+    spool.flush().await?;
+    let mut spool = spool.into_std().await;
+    spool.seek(SeekFrom::Start(0))?;
+    Ok(spool)
+}
+
+/// Copy a spooled file from its beginning into an async writer.
+#[cfg(feature = "async")]
+pub(crate) async fn unspool_async_write<W: tokio::io::AsyncWrite + Unpin>(mut spool: File, writer: &mut W) -> std::io::Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    spool.seek(SeekFrom::Start(0))?;
+    tokio::io::copy(&mut tokio::fs::File::from_std(spool), writer).await?;
+    writer.flush().await
+}
+
+/// Copy the data of entry `name` into the file of `path`.
+/// Invalid data read from the archive is told as [ComdeError::Corrupt], failed writes as [ComdeError::Io] at `path`.
+#[cfg(feature = "zip")]
+pub(crate) fn copy_entry<R: Read + ?Sized, W: Write>(data: &mut R, out: &mut W, name: &str, path: &Path) -> Result<u64, ComdeError> {
+    let mut buf = vec![0u8; 64 * 1024];
+    let mut copied = 0;
+    loop {
+        let n = match data.read(&mut buf) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) if e.kind() == std::io::ErrorKind::InvalidData => {
+                return Err(ComdeError::Corrupt { entry: Some(name.to_string()), source: Box::new(e) });
+            }
+            Err(e) => return Err(ComdeError::from(e)),
+        };
+        out.write_all(&buf[..n]).map_err(|e| ComdeError::io(path, e))?;
+        copied += n as u64;
+    }
+    Ok(copied)
+}
+
+/// A file or directory to be put into an archive.
+pub(crate) struct SourceEntry {
+    pub(crate) path: PathBuf,
+    pub(crate) name: String,
+    pub(crate) is_dir: bool,
+    pub(crate) metadata: Metadata,
+    /// Extended attributes, only read by [read_xattrs] for formats asked to keep them.
+    #[cfg_attr(not(any(feature = "zip", feature = "tar")), allow(dead_code))]
+    pub(crate) xattrs: Vec<(String, Vec<u8>)>,
+}
+
+/// Walk through the input paths of `vfs` and collect entries in order,
+/// along with the names of skipped paths that are neither files nor directories.
+/// A directory in input is stored with its contents at the root of the archive,
+/// a file in input is stored by its file name.
+pub(crate) fn collect_entries<V: Vfs + ?Sized, P: AsRef<Path>>(vfs: &V, input: &[P]) -> Result<(Vec<SourceEntry>, Vec<String>), AppError> {
+    let (mut entries, mut skipped) = (vec![], vec![]);
+    for p in input {
+        let p = p.as_ref();
+        let metadata = vfs.metadata(p).map_err(|source| AppError::Io { path: p.to_path_buf(), source })?;
+        let name = entry_name(p, p.parent().unwrap_or(Path::new("")));
+        if metadata.is_dir {
+            collect_dir(vfs, p, p, &mut entries, &mut skipped)?;
+        } else if metadata.is_file {
+            entries.push(SourceEntry { path: p.to_path_buf(), name, is_dir: false, metadata, xattrs: vec![] });
+        } else {
+            skipped.push(name);
+        }
+    }
+    Ok((entries, skipped))
+}
+
+fn collect_dir<V: Vfs + ?Sized>(
+    vfs: &V,
+    dir: &Path,
+    root: &Path,
+    entries: &mut Vec<SourceEntry>,
+    skipped: &mut Vec<String>,
+) -> Result<(), AppError> {
+    for path in vfs.list(dir).map_err(|source| AppError::Io { path: dir.to_path_buf(), source })? {
+        let metadata = vfs.metadata(&path).map_err(|source| AppError::Io { path: path.clone(), source })?;
+        let name = entry_name(&path, root);
+        if !(metadata.is_dir || metadata.is_file) {
+            skipped.push(name);
+            continue;
+        }
+        entries.push(SourceEntry { path: path.clone(), name, is_dir: metadata.is_dir, metadata, xattrs: vec![] });
+        if metadata.is_dir {
+            collect_dir(vfs, &path, root, entries, skipped)?;
+        }
+    }
+    Ok(())
+}
+
+/// Read the extended attributes of collected entries from `vfs`.
+#[cfg(any(feature = "zip", feature = "tar"))]
+pub(crate) fn read_xattrs<V: Vfs + ?Sized>(vfs: &V, entries: &mut [SourceEntry]) -> Result<(), ComdeError> {
+    for entry in entries {
+        entry.xattrs = vfs.xattrs(&entry.path).map_err(|e| ComdeError::io(&entry.path, e))?;
+    }
+    Ok(())
+}
+
+/// Set extended attributes on an extracted path, links are not followed.
+#[cfg(all(unix, feature = "xattr", any(feature = "zip", feature = "tar")))]
+pub(crate) fn restore_xattrs(path: &Path, xattrs: &[(String, Vec<u8>)]) -> Result<(), ComdeError> {
+    for (name, value) in xattrs {
+        xattr::set(path, name, value).map_err(|e| ComdeError::io(path, e))?;
+    }
+    Ok(())
+}
+
+/// Extended attributes can't be set off unix, so they are dropped.
+#[cfg(all(not(all(unix, feature = "xattr")), any(feature = "zip", feature = "tar")))]
+pub(crate) fn restore_xattrs(_path: &Path, _xattrs: &[(String, Vec<u8>)]) -> Result<(), ComdeError> {
+    Ok(())
+}
+
+/// The modified time of entries in reproducible archives:
+/// `SOURCE_DATE_EPOCH` if it's set, otherwise 1980-01-01 00:00:00 UTC, the earliest time zip can store.
+pub fn reproducible_mtime() -> SystemTime {
+    let secs = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|v| v.trim().parse::<u64>().ok())
+        .unwrap_or(315_532_800);
+    UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Make entries independent of the machine they are collected on:
+/// sort them by name, normalize their modified time,
+/// and fix permissions to `0o755` for directories and executables and `0o644` for other files.
+pub(crate) fn make_reproducible(entries: &mut [SourceEntry]) {
+    let mtime = reproducible_mtime();
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    for entry in entries {
+        let executable = entry.metadata.mode.is_some_and(|mode| mode & 0o111 != 0);
+        entry.metadata.modified = Some(mtime);
+        entry.metadata.mode = Some(if entry.is_dir || executable { 0o755 } else { 0o644 });
+    }
+}
+
+/// Get the name of an entry in the archive, relative to `root` and separated by `/`.
+fn entry_name(path: &Path, root: &Path) -> String {
+    let relative = path.strip_prefix(root).unwrap_or(path);
+    relative
+        .components()
+        .map(|c| c.as_os_str().to_string_lossy())
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+/// Check whether an entry name matches a pattern such as `*.jpg|*.png`, alternatives are separated by `|`.
+/// `*` matches any characters and `?` matches one, letters are compared ignoring ASCII case.
+/// A pattern without `/` is matched against the file name only, otherwise against the whole name.
+pub fn matches_pattern(pattern: &str, name: &str) -> bool {
+    let name = name.trim_end_matches('/');
+    pattern.split('|').map(str::trim).filter(|p| !p.is_empty()).any(|p| {
+        let target = if p.contains('/') { name } else { name.rsplit('/').next().unwrap_or(name) };
+        wildcard(&p.chars().collect::<Vec<_>>(), &target.chars().collect::<Vec<_>>())
+    })
+}
+
+/// The size of the head of a file that is sampled to tell whether it's incompressible.
+pub const INCOMPRESSIBLE_SAMPLE_SIZE: usize = 64 * 1024;
+
+/// Guess whether data is not worth compressing by the entropy of its bytes in `sample`,
+/// such as JPEGs, videos and archives, which are compressed already.
+/// Samples shorter than 4 KiB are too short to tell, and taken as compressible.
+pub fn is_incompressible(sample: &[u8]) -> bool {
+    if sample.len() < 4096 {
+        return false;
+    }
+    let mut counts = [0u64; 256];
+    for &b in sample {
+        counts[b as usize] += 1;
+    }
+    let len = sample.len() as f64;
+    let entropy: f64 = counts.iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| {
+            let p = c as f64 / len;
+            -p * p.log2()
+        })
+        .sum();
+    // Bits per byte, 8 for random data, text is around 4 - 5.
+    entropy > 7.8
+}
+
+/// Read the head of a file of `vfs` to be sampled by [is_incompressible].
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+pub(crate) fn sample_in<V: Vfs + ?Sized>(vfs: &V, path: &Path) -> Result<Vec<u8>, ComdeError> {
+    let mut sample = Vec::with_capacity(INCOMPRESSIBLE_SAMPLE_SIZE);
+    vfs.open(path)
+        .and_then(|file| file.take(INCOMPRESSIBLE_SAMPLE_SIZE as u64).read_to_end(&mut sample))
+        .map_err(|e| ComdeError::io(path, e))?;
+    Ok(sample)
+}
+
+fn wildcard(pattern: &[char], name: &[char]) -> bool {
+    let (mut p, mut n) = (0, 0);
+    // Where the last `*` is and where it starts matching, to fall back to on a mismatch.
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(c) if *c == '?' || c.eq_ignore_ascii_case(&name[n]) => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    star = Some((star_p, star_n + 1));
+                    p = star_p + 1;
+                    n = star_n + 1;
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|&c| c == '*')
+}
 
 /// Calculate the size of the given path, 
 /// whether it's a file or direcrory.
 pub fn size_of<P: AsRef<Path>>(path: P) -> Result<u64, AppError> {
+    size_of_in(&RealFs, path)
+}
+
+/// Calculate the size of the given path in `vfs`,
+/// whether it's a file or direcrory.
+pub fn size_of_in<V: Vfs + ?Sized, P: AsRef<Path>>(vfs: &V, path: P) -> Result<u64, AppError> {
     let path = path.as_ref();
-    if path.is_file() {
-        Ok(std::fs::metadata(path)
-            .map_err(|e| AppError::FsError(e.to_string().to_string()))?
-            .len())
-    } else if path.is_dir() {
+    let metadata = vfs.metadata(path).map_err(|source| AppError::Io { path: path.to_path_buf(), source })?;
+    if metadata.is_file {
+        Ok(metadata.len)
+    } else if metadata.is_dir {
         let mut total_size = 0u64;
-        for entry in WalkDir::new(path) {
-            let entry = entry.map_err(|e| AppError::FsError(e.to_string()))?;
-            if entry.file_type().is_file() {
-                total_size += entry.metadata().map_err(|e| AppError::FsError(e.to_string()))?.len();
+        for child in vfs.list(path).map_err(|source| AppError::Io { path: path.to_path_buf(), source })? {
+            let metadata = vfs.metadata(&child).map_err(|source| AppError::Io { path: child.clone(), source })?;
+            if metadata.is_file {
+                total_size += metadata.len;
+            } else if metadata.is_dir {
+                total_size += size_of_in(vfs, &child)?;
             }
         }
         Ok(total_size)
     } else {
-        Err(AppError::FsError("The input path is not a file.".to_string()))
+        // Links and other special files are skipped when compressing.
+        Ok(0)
     }
 }
 
 /// Calculate the size of all paths that given in an array,
 /// whether there's files or directorys.
 pub fn size_of_inarray<P: AsRef<Path>>(paths: &[P]) -> Result<u64, AppError> {
+    size_of_inarray_in(&RealFs, paths)
+}
+
+/// Calculate the size of all paths in `vfs` that given in an array,
+/// whether there's files or directorys.
+pub fn size_of_inarray_in<V: Vfs + ?Sized, P: AsRef<Path>>(vfs: &V, paths: &[P]) -> Result<u64, AppError> {
     paths.iter().try_fold(0, |acc, x| {
-        Ok(acc + size_of(x)?)
+        Ok(acc + size_of_in(vfs, x)?)
     })
 }
 
@@ -0,0 +1,298 @@
+//! # Vfs
+//!
+//! A virtual filesystem that compressing reads inputs from and extracting writes outputs into.
+//! - [RealFs] works on the real filesystem, which is used by default.
+//! - [MemFs] keeps a tree in memory, useful for sandboxes and tests.
+//!
+//! Extract into a [Vfs] by passing a [VfsSink] to `decompress_into` of a format.
+use std::collections::BTreeMap;
+use std::io::{self, Read, Write};
+use std::path::{Component, Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
+
+use crate::comde::Sink;
+use crate::error::ComdeError;
+
+/// A filesystem that archives are built from or extracted into.
+/// Paths are given as they are to the filesystem.
+pub trait Vfs: Send + Sync {
+    /// List paths of the entries in a directory, sorted by their names.
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>>;
+
+    /// Open a file for reading.
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>>;
+
+    /// Create or truncate a file for writing, missing parent directories are created.
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + '_>>;
+
+    /// Create a directory and all of its missing parents.
+    fn create_dir(&self, path: &Path) -> io::Result<()>;
+
+    /// Get the metadata of a path, links are not followed.
+    fn metadata(&self, path: &Path) -> io::Result<Metadata>;
+
+    /// Get the extended attributes of a path as pairs of name and value, sorted by name.
+    /// On Linux, POSIX ACLs are among them as `system.posix_acl_access` and `system.posix_acl_default`.
+    /// None by default, for filesystems that don't have them.
+    fn xattrs(&self, _path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+        Ok(vec![])
+    }
+}
+
+/// Metadata of a path in a [Vfs].
+/// A path that is neither a file nor a directory, such as a link, is skipped when compressing.
+#[derive(Debug, Clone, Copy)]
+pub struct Metadata {
+    pub is_file: bool,
+    pub is_dir: bool,
+    pub len: u64,
+    pub modified: Option<SystemTime>,
+    /// Unix permission bits, `None` where they are not known.
+    pub mode: Option<u32>,
+}
+
+/// # RealFs
+/// The real filesystem, through [std::fs].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+impl Vfs for RealFs {
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let mut paths = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|e| e.path()))
+            .collect::<io::Result<Vec<_>>>()?;
+        paths.sort();
+        Ok(paths)
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        Ok(Box::new(std::fs::File::open(path)?))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + '_>> {
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)?;
+        }
+        Ok(Box::new(std::fs::File::create(path)?))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        std::fs::create_dir_all(path)
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let meta = std::fs::symlink_metadata(path)?;
+        Ok(Metadata {
+            is_file: meta.is_file(),
+            is_dir: meta.is_dir(),
+            len: meta.len(),
+            modified: meta.modified().ok(),
+            #[cfg(unix)]
+            mode: Some(std::os::unix::fs::PermissionsExt::mode(&meta.permissions()) & 0o7777),
+            #[cfg(not(unix))]
+            mode: None,
+        })
+    }
+
+    #[cfg(all(unix, feature = "xattr"))]
+    fn xattrs(&self, path: &Path) -> io::Result<Vec<(String, Vec<u8>)>> {
+        let names = match xattr::list(path) {
+            Ok(names) => names,
+            Err(e) if e.kind() == io::ErrorKind::Unsupported => return Ok(vec![]),
+            Err(e) => return Err(e),
+        };
+        let mut xattrs = vec![];
+        for name in names {
+            // Names have to be keys of PAX headers, so the few that are not UTF-8 are left out.
+            let Some(key) = name.to_str() else { continue };
+            if let Some(value) = xattr::get(path, &name)? {
+                xattrs.push((key.to_string(), value));
+            }
+        }
+        xattrs.sort();
+        Ok(xattrs)
+    }
+}
+
+/// # MemFs
+/// A filesystem in memory.
+/// Paths are relative to its root, so `/a/b`, `./a/b` and `a/b` are the same path.
+#[derive(Debug, Default)]
+pub struct MemFs {
+    nodes: Mutex<BTreeMap<PathBuf, MemNode>>,
+}
+
+#[derive(Debug, Clone)]
+struct MemNode {
+    data: Option<Arc<Mutex<Vec<u8>>>>,
+    modified: SystemTime,
+}
+
+impl MemFs {
+    /// Create an empty MemFs.
+    pub fn new() -> MemFs {
+        MemFs::default()
+    }
+
+    /// Put a file into the filesystem, missing parent directories are created.
+    pub fn insert_file<P: AsRef<Path>, D: Into<Vec<u8>>>(&self, path: P, data: D) {
+        let key = mem_key(path.as_ref());
+        let mut nodes = self.lock();
+        insert_parents(&mut nodes, &key);
+        nodes.insert(key, MemNode { data: Some(Arc::new(Mutex::new(data.into()))), modified: SystemTime::now() });
+    }
+
+    /// Put a directory into the filesystem, missing parent directories are created.
+    pub fn insert_dir<P: AsRef<Path>>(&self, path: P) {
+        let key = mem_key(path.as_ref());
+        let mut nodes = self.lock();
+        insert_parents(&mut nodes, &key);
+        nodes.entry(key).or_insert(MemNode { data: None, modified: SystemTime::now() });
+    }
+
+    /// Get a copy of the data of a file, `None` if it's not a file.
+    pub fn read_file<P: AsRef<Path>>(&self, path: P) -> Option<Vec<u8>> {
+        let nodes = self.lock();
+        let data = nodes.get(&mem_key(path.as_ref()))?.data.as_ref()?;
+        Some(data.lock().map(|d| d.clone()).unwrap_or_default())
+    }
+
+    /// Get all paths in the filesystem, sorted.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        self.lock().keys().cloned().collect()
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, BTreeMap<PathBuf, MemNode>> {
+        self.nodes.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Vfs for MemFs {
+    fn list(&self, path: &Path) -> io::Result<Vec<PathBuf>> {
+        let key = mem_key(path);
+        self.metadata(&key).and_then(|m| if m.is_dir { Ok(()) } else { Err(not_a_dir(path)) })?;
+        Ok(self.lock().keys().filter(|k| k.parent() == Some(key.as_path())).cloned().collect())
+    }
+
+    fn open(&self, path: &Path) -> io::Result<Box<dyn Read + Send + '_>> {
+        let data = self.read_file(path).ok_or_else(|| not_found(path))?;
+        Ok(Box::new(io::Cursor::new(data)))
+    }
+
+    fn create(&self, path: &Path) -> io::Result<Box<dyn Write + Send + '_>> {
+        let key = mem_key(path);
+        let mut nodes = self.lock();
+        if nodes.get(&key).is_some_and(|n| n.data.is_none()) {
+            return Err(io::Error::new(io::ErrorKind::IsADirectory, format!("{} is a directory", path.display())));
+        }
+        insert_parents(&mut nodes, &key);
+        let data = Arc::new(Mutex::new(Vec::new()));
+        nodes.insert(key, MemNode { data: Some(data.clone()), modified: SystemTime::now() });
+        Ok(Box::new(MemWriter(data)))
+    }
+
+    fn create_dir(&self, path: &Path) -> io::Result<()> {
+        let key = mem_key(path);
+        if self.lock().get(&key).is_some_and(|n| n.data.is_some()) {
+            return Err(not_a_dir(path));
+        }
+        self.insert_dir(key);
+        Ok(())
+    }
+
+    fn metadata(&self, path: &Path) -> io::Result<Metadata> {
+        let key = mem_key(path);
+        if key.as_os_str().is_empty() {
+            return Ok(Metadata { is_file: false, is_dir: true, len: 0, modified: None, mode: None });
+        }
+        let nodes = self.lock();
+        let node = nodes.get(&key).ok_or_else(|| not_found(path))?;
+        let len = match &node.data {
+            Some(data) => data.lock().map(|d| d.len() as u64).unwrap_or(0),
+            None => 0,
+        };
+        Ok(Metadata { is_file: node.data.is_some(), is_dir: node.data.is_none(), len, modified: Some(node.modified), mode: None })
+    }
+}
+
+/// Appends to a file of [MemFs].
+struct MemWriter(Arc<Mutex<Vec<u8>>>);
+
+impl Write for MemWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().map_err(|e| io::Error::other(e.to_string()))?.extend_from_slice(buf);
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// Keep only the normal components, so the path is relative to the root.
+fn mem_key(path: &Path) -> PathBuf {
+    path.components().filter_map(|c| match c {
+        Component::Normal(name) => Some(name),
+        _ => None,
+    }).collect()
+}
+
+fn insert_parents(nodes: &mut BTreeMap<PathBuf, MemNode>, key: &Path) {
+    for parent in key.ancestors().skip(1) {
+        if parent.as_os_str().is_empty() {
+            break;
+        }
+        nodes.entry(parent.to_path_buf()).or_insert(MemNode { data: None, modified: SystemTime::now() });
+    }
+}
+
+fn not_found(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotFound, format!("{} is not found", path.display()))
+}
+
+fn not_a_dir(path: &Path) -> io::Error {
+    io::Error::new(io::ErrorKind::NotADirectory, format!("{} is not a directory", path.display()))
+}
+
+/// # VfsSink
+/// A [Sink] that extracts entries under `root` of a [Vfs].
+/// Entries that would be written outside of `root` are refused with [ComdeError::PathTraversal].
+pub struct VfsSink<'a, V: Vfs + ?Sized> {
+    vfs: &'a V,
+    root: PathBuf,
+}
+
+impl <'a, V: Vfs + ?Sized> VfsSink<'a, V> {
+    /// Create a VfsSink writing under `root` of `vfs`.
+    pub fn new<P: AsRef<Path>>(vfs: &'a V, root: P) -> VfsSink<'a, V> {
+        VfsSink {
+            vfs,
+            root: root.as_ref().to_path_buf(),
+        }
+    }
+
+    fn enclosed(&self, name: &str) -> io::Result<PathBuf> {
+        let mut path = self.root.clone();
+        for c in Path::new(name).components() {
+            match c {
+                Component::Normal(part) => path.push(part),
+                Component::CurDir => {}
+                _ => return Err(io::Error::new(io::ErrorKind::InvalidData, ComdeError::PathTraversal { entry: name.to_string() })),
+            }
+        }
+        Ok(path)
+    }
+}
+
+impl <V: Vfs + ?Sized> Sink for VfsSink<'_, V> {
+    fn dir(&mut self, name: &str) -> io::Result<()> {
+        self.vfs.create_dir(&self.enclosed(name)?)
+    }
+
+    fn file(&mut self, name: &str, data: &mut dyn Read) -> io::Result<()> {
+        let mut out = self.vfs.create(&self.enclosed(name)?)?;
+        io::copy(data, &mut out)?;
+        out.flush()
+    }
+}
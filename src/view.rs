@@ -1,25 +1,169 @@
-//! TODO
+//! # View
 //!
-use std::path::{Path, PathBuf};
+//! List entries of an archive without decompressing it.
+//! The format is detected by the signature of the archive,
+//! and a volume set can be viewed by its first part `name.001` ,
+//! a split zip archive by its first part `name.z01` or its last part `name.zip` .
+use std::io::Read;
+use std::path::Path;
+
+#[cfg(any(feature = "zip", feature = "sevenz"))]
+use crate::error::ComdeError;
+use crate::error::ViewError;
+#[cfg(feature = "zip")]
+use crate::formats::zip::NameEncoding;
+use crate::utils;
 
 /// # Archive
 ///
 pub struct Archive<'a> {
     path: &'a Path,
-
+    password: Option<String>,
+    #[cfg(feature = "zip")]
+    name_encoding: NameEncoding,
 }
 
 impl <'a> Archive<'a> {
 
-    ///
+    /// Create an Archive of the given path.
+    /// The archive is not opened until it's viewed.
     pub fn new<P: AsRef<Path>>(path: &'a P) -> Archive<'a> {
         Archive {
             path: path.as_ref(),
+            password: None,
+            #[cfg(feature = "zip")]
+            name_encoding: NameEncoding::Auto,
+        }
+    }
+
+    /// Set the password to read a 7z archive whose header is encrypted.
+    /// Entry names of zip are never encrypted, so it's not needed for zip.
+    pub fn set_password(mut self, password: &str) -> Archive<'a> {
+        self.password = Some(password.to_string());
+        self
+    }
+
+    /// Set the encoding of zip entry names that are not flagged as UTF-8, see [NameEncoding].
+    #[cfg(feature = "zip")]
+    pub fn set_name_encoding(mut self, encoding: NameEncoding) -> Archive<'a> {
+        self.name_encoding = encoding;
+        self
+    }
+
+    /// Get the comment of the archive, `None` if it has none.
+    /// Only zip has archive comments, the 7z format has no place for one, so 7z gives `None`.
+    #[cfg_attr(not(feature = "zip"), allow(unused_variables))]
+    pub fn comment(&self) -> Result<Option<String>, ViewError> {
+        let (reader, signature) = self.open()?;
+        match signature {
+            #[cfg(feature = "sevenz")]
+            [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C] => Ok(None),
+            #[cfg(feature = "zip")]
+            [b'P', b'K', ..] => {
+                let archive = zip::ZipArchive::new(crate::formats::zip::split::join(reader, self.path)?).map_err(ComdeError::from)?;
+                let comment = archive.comment();
+                Ok((!comment.is_empty()).then(|| String::from_utf8_lossy(comment).into_owned()))
+            }
+            _ => Err(ViewError::UnknownFormat),
+        }
+    }
+
+    /// Open the archive and read the signature at the head of it.
+    fn open(&self) -> Result<(utils::VolumeReader, [u8; 6]), ViewError> {
+        let mut reader = utils::VolumeReader::open(self.path)
+            .map_err(|source| ViewError::Io { path: self.path.to_path_buf(), source })?;
+        let mut signature = [0u8; 6];
+        reader.read_exact(&mut signature).map_err(|_| ViewError::UnknownFormat)?;
+        Ok((reader, signature))
+    }
+
+    /// List all entries of the archive.
+    #[cfg_attr(not(any(feature = "zip", feature = "sevenz")), allow(unused_variables))]
+    pub fn entries(&self) -> Result<Vec<Entry>, ViewError> {
+        let (reader, signature) = self.open()?;
+
+        match signature {
+            #[cfg(feature = "sevenz")]
+            [b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C] => {
+                let mut reader = reader;
+                let password = self.password.as_deref().map(sevenz_rust2::Password::new).unwrap_or_default();
+                let archive = sevenz_rust2::Archive::read(&mut reader, &password).map_err(ComdeError::from)?;
+                Ok(archive.files.iter().map(|f| Entry {
+                    name: f.name.clone(),
+                    size: f.size,
+                    compressed_size: f.compressed_size,
+                    is_dir: f.is_directory,
+                    comment: None,
+                }).collect())
+            }
+            #[cfg(feature = "zip")]
+            [b'P', b'K', ..] => {
+                use zip::read::HasZipMetadata;
+                let mut archive = zip::ZipArchive::new(crate::formats::zip::split::join(reader, self.path)?).map_err(ComdeError::from)?;
+                // Names are decoded after all of them are read, so the encoding is picked by all names.
+                let mut raw_names = vec![];
+                for i in 0..archive.len() {
+                    let f = archive.by_index_raw(i).map_err(ComdeError::from)?;
+                    if !f.get_metadata().is_utf8 {
+                        raw_names.push(f.name_raw().to_vec());
+                    }
+                }
+                let encoding = self.name_encoding.resolve(raw_names.iter().map(Vec::as_slice));
+                let mut entries = Vec::with_capacity(archive.len());
+                for i in 0..archive.len() {
+                    let f = archive.by_index_raw(i).map_err(ComdeError::from)?;
+                    entries.push(Entry {
+                        name: crate::formats::zip::entry_name(&f, encoding),
+                        size: f.size(),
+                        compressed_size: f.compressed_size(),
+                        is_dir: f.is_dir(),
+                        comment: Some(f.comment()).filter(|comment| !comment.is_empty()).map(str::to_string),
+                    });
+                }
+                Ok(entries)
+            }
+            _ => Err(ViewError::UnknownFormat),
         }
     }
 }
 
 /// # Entry
+/// A file or directory in an archive.
+#[derive(Debug, Clone)]
 pub struct Entry {
+    name: String,
+    size: u64,
+    compressed_size: u64,
+    is_dir: bool,
+    comment: Option<String>,
+}
+
+impl Entry {
+    /// Get the path of the entry in the archive.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Get the original size of the entry.
+    pub fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Get the compressed size of the entry.
+    /// It may be 0 when the entry is packed with others in a solid 7z block.
+    pub fn compressed_size(&self) -> u64 {
+        self.compressed_size
+    }
 
+    /// Check whether the entry is a directory.
+    pub fn is_dir(&self) -> bool {
+        self.is_dir
+    }
+
+    /// Get the comment of the entry, `None` if it has none.
+    /// Only zip has entry comments, which the zip crate decodes as UTF-8 or CP437 by the flag.
+    /// 7-Zip neither writes nor reads comments of 7z entries, so 7z gives `None`.
+    pub fn comment(&self) -> Option<&str> {
+        self.comment.as_deref()
+    }
 }
@@ -0,0 +1,12 @@
+#![cfg(all(feature = "profiles", feature = "sevenz"))]
+
+use ziphere::Configs;
+use ziphere::preset::Profiles;
+
+#[test]
+fn profile_threads_compress_7z() {
+    let profiles: Profiles = "[profile.quick]\nthreads = 4\n".parse().unwrap();
+    let config = profiles.get("quick").unwrap().apply_7z(Configs::get_7z());
+    // Each LZMA2 encoder thread takes its own memory.
+    assert!(config.estimated_memory() > Configs::get_7z().estimated_memory() * 3);
+}
@@ -0,0 +1,379 @@
+#![cfg(feature = "sevenz")]
+
+use ziphere::error::ComdeError;
+use ziphere::{Configs, Formats};
+
+/// Make a 7z archive of a start header, `data` and the `header` after it.
+fn crafted_archive_with(data: &[u8], header: &[u8]) -> Vec<u8> {
+    let mut start_header = vec![];
+    start_header.extend_from_slice(&(data.len() as u64).to_le_bytes());
+    start_header.extend_from_slice(&(header.len() as u64).to_le_bytes());
+    start_header.extend_from_slice(&crc32fast::hash(header).to_le_bytes());
+    let mut archive = vec![b'7', b'z', 0xBC, 0xAF, 0x27, 0x1C, 0, 4];
+    archive.extend_from_slice(&crc32fast::hash(&start_header).to_le_bytes());
+    archive.extend_from_slice(&start_header);
+    archive.extend_from_slice(data);
+    archive.extend_from_slice(header);
+    archive
+}
+
+/// Make a 7z archive of only a start header and an encoded `header` right after it.
+fn crafted_archive(header: &[u8]) -> Vec<u8> {
+    crafted_archive_with(&[], header)
+}
+
+/// An encoded header with one packed stream of `pack_size` bytes,
+/// decoded by a block of one coder to `unpack_size` bytes.
+fn encoded_header(pack_size: u64, coder_id: &[u8], properties: &[u8], unpack_size: u64) -> Vec<u8> {
+    let mut header = vec![0x17, 0x06, 0x00, 0x01, 0x09, 0xFF];
+    header.extend_from_slice(&pack_size.to_le_bytes());
+    header.extend_from_slice(&[0x00, 0x07, 0x0B, 0x01, 0x00, 0x01]);
+    header.push(0x20 | coder_id.len() as u8);
+    header.extend_from_slice(coder_id);
+    header.push(properties.len() as u8);
+    header.extend_from_slice(properties);
+    header.extend_from_slice(&[0x0C, 0xFF]);
+    header.extend_from_slice(&unpack_size.to_le_bytes());
+    header.extend_from_slice(&[0x00, 0x00]);
+    header
+}
+
+fn decompress_crafted(header: &[u8], config: &ziphere::formats::sevenz::SevenZCfg) -> ComdeError {
+    decompress_crafted_archive(&crafted_archive(header), config)
+}
+
+fn decompress_crafted_archive(archive: &[u8], config: &ziphere::formats::sevenz::SevenZCfg) -> ComdeError {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("crafted.7z");
+    std::fs::write(&path, archive).unwrap();
+    Formats::get_7z().decompress_f(&path, &dir.path().join("out"), config).unwrap_err()
+}
+
+/// A plain header of one block of one stored stream of `size` bytes, without files.
+fn plain_header(size: u64) -> Vec<u8> {
+    let mut header = vec![0x01, 0x04, 0x06, 0x00, 0x01, 0x09];
+    header.push(size as u8);
+    header.extend_from_slice(&[0x00, 0x07, 0x0B, 0x01, 0x00, 0x01, 0x01, 0x00, 0x0C]);
+    header.push(size as u8);
+    header.extend_from_slice(&[0x00, 0x00, 0x00]);
+    header
+}
+
+#[test]
+fn memory_limit_with_huge_sizes() {
+    // An LZMA2 dictionary of 4 GiB, with packed and unpacked sizes adding up to over `u64::MAX`.
+    let header = encoded_header(u64::MAX - 1, &[0x21], &[40], u64::MAX);
+    let config = Configs::get_7z().set_max_memory(Some(1 << 30));
+    match decompress_crafted(&header, &config) {
+        ComdeError::MemoryLimit { required, limit } => {
+            assert_eq!(required, u64::MAX);
+            assert_eq!(limit, 1 << 30);
+        }
+        e => panic!("expected a memory limit error, got {e:?}"),
+    }
+}
+
+#[test]
+fn too_many_key_cycles() {
+    // AES256 with 2^62 rounds of key derivation, a salt and an IV of 16 bytes each.
+    let mut properties = vec![0xC0 | 62, 0xFF];
+    properties.extend_from_slice(&[0u8; 32]);
+    let header = encoded_header(16, &[0x06, 0xF1, 0x07, 0x01], &properties, 16);
+    // The same coder for a block of files, in a plain header.
+    let mut plain = vec![0x01, 0x04];
+    plain.extend_from_slice(&header[1..]);
+    plain.push(0x00);
+    for header in [header, plain] {
+        for config in [Configs::get_7z(), Configs::get_7z().set_max_memory(Some(1 << 30))] {
+            match decompress_crafted(&header, &config.set_password("password")) {
+                ComdeError::UnsupportedMethod(_) => {}
+                e => panic!("expected an unsupported method error, got {e:?}"),
+            }
+        }
+    }
+}
+
+#[test]
+fn truncated_header() {
+    let header = encoded_header(16, &[0x21], &[16], 100);
+    for len in [1, 3, 10, header.len() - 1] {
+        for config in [Configs::get_7z(), Configs::get_7z().set_max_memory(Some(1 << 30))] {
+            match decompress_crafted(&header[..len], &config) {
+                ComdeError::Corrupt { .. } => {}
+                e => panic!("expected a corrupt archive error for {len} bytes, got {e:?}"),
+            }
+        }
+    }
+}
+
+#[test]
+fn huge_counts() {
+    // 2^40 packed streams, blocks, coders and files, each count in a header of a few bytes.
+    let huge = [0xFF, 0, 0, 0, 0, 0x01, 0, 0, 0];
+    let mut pack_streams = vec![0x17, 0x06, 0x00];
+    pack_streams.extend_from_slice(&huge);
+    pack_streams.extend_from_slice(&[0x09, 0x10, 0x00, 0x00]);
+    let mut blocks = vec![0x17, 0x07, 0x0B];
+    blocks.extend_from_slice(&huge);
+    blocks.extend_from_slice(&[0x00, 0x00]);
+    let mut coders = vec![0x17, 0x07, 0x0B, 0x01, 0x00];
+    coders.extend_from_slice(&huge);
+    coders.extend_from_slice(&[0x00]);
+    let mut files = plain_header(0);
+    files.pop();
+    files.push(0x05);
+    files.extend_from_slice(&huge);
+    files.extend_from_slice(&[0x00, 0x00]);
+    // The streams of a coder, over what a block may have.
+    let mut streams = vec![0x17, 0x07, 0x0B, 0x01, 0x00, 0x01, 0x31, 0x00];
+    streams.extend_from_slice(&huge);
+    streams.extend_from_slice(&huge);
+    streams.push(0x00);
+
+    for header in [pack_streams, blocks, coders, files, streams] {
+        match decompress_crafted(&header, &Configs::get_7z()) {
+            ComdeError::Corrupt { .. } | ComdeError::UnsupportedMethod(_) => {}
+            e => panic!("expected a corrupt archive error for {header:02X?}, got {e:?}"),
+        }
+    }
+}
+
+#[test]
+fn nested_encoded_header() {
+    // A header stored in a block of its own, which is an encoded header again, and so on.
+    let mut data = plain_header(0);
+    let mut offset = 0;
+    let mut archive_data = vec![];
+    for _ in 0..3 {
+        let mut header = encoded_header(data.len() as u64, &[0x00], &[], data.len() as u64);
+        // The coder of copy has no properties.
+        header.splice(20..23, [0x01, 0x00]);
+        header[2] = offset as u8;
+        archive_data.extend_from_slice(&data);
+        offset += data.len();
+        data = header;
+    }
+    for config in [Configs::get_7z(), Configs::get_7z().set_max_memory(Some(1 << 30))] {
+        match decompress_crafted_archive(&crafted_archive_with(&archive_data, &data), &config) {
+            ComdeError::Corrupt { .. } => {}
+            e => panic!("expected a corrupt archive error, got {e:?}"),
+        }
+    }
+}
+
+#[test]
+fn broken_plain_archive_with_password() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("data.txt"), "some text to compress ".repeat(1000)).unwrap();
+    let mut archive = std::io::Cursor::new(Vec::new());
+    Formats::get_7z().compress(&[dir.path().join("data.txt")], &mut archive, &Configs::get_7z()).unwrap();
+    let mut archive = archive.into_inner();
+    // Break the packed stream right after the start header, the header at the end is left as it is.
+    archive[40] ^= 0xFF;
+
+    let config = Configs::get_7z().set_password("password");
+    let e = Formats::get_7z().decompress(std::io::Cursor::new(archive), dir.path().join("out"), &config).unwrap_err();
+    assert!(!matches!(e, ComdeError::WrongPassword), "a broken archive without encryption is taken as a wrong password");
+}
+
+#[test]
+fn wrong_password() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join("data.txt"), "some text to compress ".repeat(1000)).unwrap();
+    for encrypt_headers in [false, true] {
+        let mut archive = std::io::Cursor::new(Vec::new());
+        let config = Configs::get_7z().set_password("password").set_encrypt_headers(encrypt_headers);
+        Formats::get_7z().compress(&[dir.path().join("data.txt")], &mut archive, &config).unwrap();
+
+        let archive = archive.into_inner();
+        let config = Configs::get_7z().set_password("wrong");
+        match Formats::get_7z().decompress(std::io::Cursor::new(archive.clone()), dir.path().join("out"), &config) {
+            Err(ComdeError::WrongPassword) => {}
+            r => panic!("expected a wrong password error, got {r:?}"),
+        }
+
+        let config = Configs::get_7z().set_password("password").set_max_memory(Some(1 << 30));
+        let files = Formats::get_7z().decompress_mem(std::io::Cursor::new(archive), &config).unwrap();
+        assert_eq!(files["data.txt"], "some text to compress ".repeat(1000).as_bytes());
+    }
+}
+
+#[test]
+fn blocks_have_own_iv() {
+    // Two files of the same 64 bytes, stored in blocks of their own, so the packed streams are only the ciphertext.
+    let data = [7u8; 64];
+    let config = Configs::get_7z().use_copy().set_password("password");
+    let mut archive = std::io::Cursor::new(Vec::new());
+    Formats::get_7z().compress_mem(&[("a", data), ("b", data)], &mut archive, &config).unwrap();
+    let archive = archive.into_inner();
+    assert_ne!(archive[32..96], archive[96..160], "blocks are encrypted with the same key and IV");
+
+    let files = Formats::get_7z().decompress_mem(std::io::Cursor::new(archive), &config).unwrap();
+    assert_eq!(files["a"], data);
+    assert_eq!(files["b"], data);
+}
+
+#[test]
+fn no_comments() {
+    let dir = tempfile::tempdir().unwrap();
+    let archive = dir.path().join("plain.7z");
+    let output = std::fs::File::create(&archive).unwrap();
+    Formats::get_7z().compress_mem(&[("a.txt", "a")], output, &Configs::get_7z()).unwrap();
+    let view = ziphere::view::Archive::new(&archive);
+    assert_eq!(view.comment().unwrap(), None);
+    assert!(view.entries().unwrap().iter().all(|entry| entry.comment().is_none()));
+}
+
+#[test]
+fn lzma2_tuning() {
+    use ziphere::formats::sevenz::Lzma2Tuning;
+
+    let data = "some text to compress ".repeat(1000);
+    let config = Configs::get_7z().use_lzma2_with_tuning(Lzma2Tuning::from_level(1).set_dictionary_size(8 << 20)).unwrap();
+    let mut archive = std::io::Cursor::new(Vec::new());
+    Formats::get_7z().compress_mem(&[("a.txt", data.as_str())], &mut archive, &config).unwrap();
+    let files = Formats::get_7z().decompress_mem(std::io::Cursor::new(archive.into_inner()), &config).unwrap();
+    assert_eq!(files["a.txt"], data.as_bytes());
+
+    for tuning in [
+        Lzma2Tuning::from_level(10),
+        Lzma2Tuning::from_level(1).set_dictionary_size(1024),
+        Lzma2Tuning::from_level(1).set_threads(0, None),
+        Lzma2Tuning::from_level(1).set_dictionary_size(8 << 20).set_threads(2, Some(1 << 20)),
+    ] {
+        match Configs::get_7z().use_lzma2_with_tuning(tuning) {
+            Err(ComdeError::InvalidOption(_)) => {}
+            r => panic!("expected an invalid option error, got {:?}", r.err()),
+        }
+    }
+}
+
+#[test]
+fn reproducible_same_bytes() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut archives = vec![];
+    for (i, mode) in [(0, 0o600), (1, 0o664)] {
+        let src = dir.path().join(format!("src{i}"));
+        std::fs::create_dir_all(src.join("sub")).unwrap();
+        std::fs::write(src.join("a.txt"), "hello world".repeat(100)).unwrap();
+        std::fs::write(src.join("sub/b.sh"), "echo b").unwrap();
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            std::fs::set_permissions(src.join("a.txt"), std::fs::Permissions::from_mode(mode)).unwrap();
+            std::fs::set_permissions(src.join("sub/b.sh"), std::fs::Permissions::from_mode(mode | 0o100)).unwrap();
+        }
+        let time = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000 + i * 86400);
+        std::fs::File::options().write(true).open(src.join("a.txt")).unwrap().set_modified(time).unwrap();
+
+        let mut archive = std::io::Cursor::new(Vec::new());
+        Formats::get_7z().compress(&[&src], &mut archive, &Configs::get_7z().set_reproducible(true)).unwrap();
+        archives.push(archive.into_inner());
+    }
+    assert_eq!(archives[0], archives[1]);
+
+    let archive = sevenz_rust2::Archive::read(&mut std::io::Cursor::new(&archives[0]), &sevenz_rust2::Password::empty()).unwrap();
+    let modes = archive.files.iter().map(|entry| (entry.name(), entry.windows_attributes() >> 16)).collect::<Vec<_>>();
+    assert_eq!(modes, [("a.txt", 0o100644), ("sub", 0o040755), ("sub/b.sh", 0o100755)]);
+}
+
+#[test]
+fn filters_round_trip() {
+    use sevenz_rust2::EncoderMethod;
+    use ziphere::formats::sevenz::Filter;
+
+    // Samples of 32-bit words, which delta and BCJ filters are made for.
+    let data = (0u32..20_000).flat_map(|i| (i * 3).to_le_bytes()).collect::<Vec<_>>();
+    for (filter, id) in [
+        (Filter::BcjX86, EncoderMethod::ID_BCJ_X86),
+        (Filter::BcjArm, EncoderMethod::ID_BCJ_ARM),
+        (Filter::BcjArm64, EncoderMethod::ID_BCJ_ARM64),
+        (Filter::Delta(4), EncoderMethod::ID_DELTA),
+    ] {
+        let mut archive = std::io::Cursor::new(Vec::new());
+        let config = Configs::get_7z().add_filter(filter);
+        Formats::get_7z().compress_mem(&[("data.bin", &data)], &mut archive, &config).unwrap();
+
+        let read = sevenz_rust2::Archive::read(&mut std::io::Cursor::new(archive.get_ref()), &sevenz_rust2::Password::empty()).unwrap();
+        assert!(read.blocks[0].coders.iter().any(|coder| coder.encoder_method_id() == id), "{filter:?}");
+        let files = Formats::get_7z().decompress_mem(std::io::Cursor::new(archive.get_ref()), &Configs::get_7z()).unwrap();
+        assert_eq!(files["data.bin"], data, "{filter:?}");
+    }
+}
+
+#[test]
+fn method_rules_round_trip() {
+    use sevenz_rust2::EncoderMethod;
+    use ziphere::formats::sevenz::Filter;
+
+    let mut state = 1u32;
+    let noise = (0..64 << 10).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect::<Vec<_>>();
+    let text = "hello world ".repeat(1000).into_bytes();
+    let input = [
+        ("a.log", text.clone()),
+        ("a.txt", text.clone()),
+        ("b.txt", text.clone()),
+        ("c.dat", text.clone()),
+        ("noise.bin", noise),
+    ];
+    // `a.txt` matches both rules, and the first one is taken.
+    let config = Configs::get_7z()
+        .set_solid_compress(true)
+        .set_detect_incompressible(true)
+        .add_method_rule("*.txt", Configs::get_7z().use_copy())
+        .add_method_rule("a.*", Configs::get_7z().add_filter(Filter::Delta(1)));
+    let mut archive = std::io::Cursor::new(Vec::new());
+    Formats::get_7z().compress_mem(&input, &mut archive, &config).unwrap();
+
+    let read = sevenz_rust2::Archive::read(&mut std::io::Cursor::new(archive.get_ref()), &sevenz_rust2::Password::empty()).unwrap();
+    let block_of = |name: &str| {
+        let index = read.files.iter().position(|file| file.name() == name).unwrap();
+        read.stream_map.file_block_index[index].unwrap()
+    };
+    let methods = |name: &str| read.blocks[block_of(name)].coders.iter().map(|coder| coder.encoder_method_id().to_vec()).collect::<Vec<_>>();
+    assert_eq!(methods("a.txt"), [EncoderMethod::ID_COPY]);
+    assert_eq!(block_of("a.txt"), block_of("b.txt"));
+    assert!(methods("a.log").contains(&EncoderMethod::ID_DELTA.to_vec()));
+    assert_eq!(methods("c.dat"), [EncoderMethod::ID_LZMA2]);
+    // Files stored as incompressible don't share a block with those of a rule, though both are copied.
+    assert_eq!(methods("noise.bin"), [EncoderMethod::ID_COPY]);
+    assert_ne!(block_of("noise.bin"), block_of("a.txt"));
+
+    let files = Formats::get_7z().decompress_mem(std::io::Cursor::new(archive.get_ref()), &Configs::get_7z()).unwrap();
+    for (name, data) in &input {
+        assert_eq!(&files[*name], data, "{name}");
+    }
+}
+
+#[test]
+fn solid_block_limits() {
+    let input = ["a.txt", "b.log", "c.txt", "d.log", "e.txt"].map(|name| (name, name.repeat(250).into_bytes()));
+    let base = Configs::get_7z().set_solid_compress(true);
+    for (config, expected) in [
+        (base.clone(), vec![vec!["a.txt", "b.log", "c.txt", "d.log", "e.txt"]]),
+        // Files of 1250 bytes each, two in a block of 2500 bytes at most.
+        (base.clone().set_solid_block_size(Some(2500)), vec![vec!["a.txt", "b.log"], vec!["c.txt", "d.log"], vec!["e.txt"]]),
+        // A file larger than the limit gets a block of its own.
+        (base.clone().set_solid_block_size(Some(1000)), vec![vec!["a.txt"], vec!["b.log"], vec!["c.txt"], vec!["d.log"], vec!["e.txt"]]),
+        (base.clone().set_solid_block_files(Some(3)), vec![vec!["a.txt", "b.log", "c.txt"], vec!["d.log", "e.txt"]]),
+        (base.clone().set_solid_by_extension(true), vec![vec!["b.log", "d.log"], vec!["a.txt", "c.txt", "e.txt"]]),
+    ] {
+        let mut archive = std::io::Cursor::new(Vec::new());
+        Formats::get_7z().compress_mem(&input, &mut archive, &config).unwrap();
+
+        let read = sevenz_rust2::Archive::read(&mut std::io::Cursor::new(archive.get_ref()), &sevenz_rust2::Password::empty()).unwrap();
+        let mut blocks = vec![vec![]; read.blocks.len()];
+        for (file, block) in read.files.iter().zip(&read.stream_map.file_block_index) {
+            blocks[block.unwrap()].push(file.name());
+        }
+        assert_eq!(blocks, expected);
+
+        let files = Formats::get_7z().decompress_mem(std::io::Cursor::new(archive.get_ref()), &Configs::get_7z()).unwrap();
+        for (name, data) in &input {
+            assert_eq!(&files[*name], data, "{name}");
+        }
+    }
+}
@@ -0,0 +1,48 @@
+#![cfg(feature = "tar")]
+
+#[cfg(all(unix, feature = "xattr"))]
+#[test]
+fn xattrs_round_trip() {
+    use ziphere::{Configs, Formats};
+
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    std::fs::create_dir_all(src.join("sub")).unwrap();
+    std::fs::write(src.join("a.txt"), "hello").unwrap();
+    std::fs::write(src.join("sub/b.txt"), "world").unwrap();
+    if xattr::set(src.join("a.txt"), "user.origin", b"build 42").is_err() {
+        // The filesystem of temporary files has no user attributes.
+        return;
+    }
+    xattr::set(src.join("sub"), "user.kind", b"directory").unwrap();
+    let archive = dir.path().join("xattrs.tar");
+    let config = Configs::get_tar().set_xattrs(true);
+    Formats::get_tar().compress_f(&[&src], &archive, &config).unwrap();
+
+    // They go into PAX headers the way GNU tar writes them.
+    let mut tar = tar::Archive::new(std::fs::File::open(&archive).unwrap());
+    let keys = tar.entries().unwrap()
+        .map(|entry| {
+            let mut entry = entry.unwrap();
+            let name = entry.path().unwrap().to_string_lossy().into_owned();
+            let keys = entry.pax_extensions().unwrap().into_iter().flatten()
+                .map(|extension| extension.unwrap().key().unwrap().to_string())
+                .collect::<Vec<_>>();
+            (name, keys)
+        })
+        .collect::<std::collections::BTreeMap<_, _>>();
+    assert_eq!(keys["a.txt"], ["SCHILY.xattr.user.origin"]);
+    assert!(keys["sub/b.txt"].is_empty());
+
+    let out = dir.path().join("out");
+    Formats::get_tar().decompress_f(&archive, &out, &config).unwrap();
+    assert_eq!(std::fs::read(out.join("a.txt")).unwrap(), b"hello");
+    assert_eq!(xattr::get(out.join("a.txt"), "user.origin").unwrap().as_deref(), Some(&b"build 42"[..]));
+    assert_eq!(xattr::get(out.join("sub"), "user.kind").unwrap().as_deref(), Some(&b"directory"[..]));
+    assert_eq!(xattr::get(out.join("sub/b.txt"), "user.origin").unwrap(), None);
+
+    // They are only restored when asked for.
+    let plain = dir.path().join("plain");
+    Formats::get_tar().decompress_f(&archive, &plain, &Configs::get_tar()).unwrap();
+    assert_eq!(xattr::get(plain.join("a.txt"), "user.origin").unwrap(), None);
+}
@@ -0,0 +1,534 @@
+#![cfg(feature = "zip")]
+
+use std::io::{Read, Seek, SeekFrom, Write};
+use std::path::Path;
+use ziphere::comde::FnSink;
+use ziphere::{Configs, Formats};
+
+/// Over 4 GiB, so the file needs Zip64.
+const LARGE_SIZE: u64 = (4 << 30) + (1 << 20);
+
+/// Make a tree of files and directories under `dir`.
+fn make_tree(dir: &Path) {
+    std::fs::create_dir_all(dir.join("sub/empty")).unwrap();
+    for i in 0..20 {
+        std::fs::write(dir.join(format!("file{i}.txt")), format!("file {i} ").repeat(i * 100)).unwrap();
+    }
+    std::fs::write(dir.join("sub/inner.txt"), "hello world".repeat(1000)).unwrap();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        std::fs::set_permissions(dir.join("sub/inner.txt"), std::fs::Permissions::from_mode(0o600)).unwrap();
+        std::fs::set_permissions(dir.join("sub/empty"), std::fs::Permissions::from_mode(0o700)).unwrap();
+    }
+}
+
+#[test]
+fn threads_same_archive() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+
+    let compress = |threads: usize| {
+        let mut output = std::io::Cursor::new(Vec::new());
+        Formats::get_zip().compress(&[&src], &mut output, &Configs::get_zip().set_threads(threads)).unwrap();
+        output.into_inner()
+    };
+    let single = compress(1);
+    assert!(single == compress(4), "archives compressed on 1 and 4 threads differ");
+}
+
+/// Compare the files of two trees.
+fn assert_same_tree(expected: &Path, actual: &Path) {
+    for entry in std::fs::read_dir(expected).unwrap() {
+        let entry = entry.unwrap();
+        let path = actual.join(entry.file_name());
+        if entry.file_type().unwrap().is_dir() {
+            assert!(path.is_dir(), "{} is missing", path.display());
+            assert_same_tree(&entry.path(), &path);
+        } else {
+            assert!(std::fs::read(entry.path()).unwrap() == std::fs::read(&path).unwrap(), "{} differs", path.display());
+        }
+    }
+}
+
+#[test]
+fn stream_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+
+    for config in [Configs::get_zip(), Configs::get_zip().set_password("password")] {
+        // A writer that can't seek.
+        let mut output = Vec::new();
+        Formats::get_zip().compress_stream(&[&src], &mut output, &config).unwrap();
+        let out = dir.path().join("out");
+        Formats::get_zip().decompress(std::io::Cursor::new(output), &out, &config).unwrap();
+        assert_same_tree(&src, &out);
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+}
+
+#[test]
+fn stream_falls_back_from_unstreamable_entry() {
+    // A stored entry which is streamed, and then encrypted ones which need the central directory.
+    let mut archive = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    let plain = zip::write::SimpleFileOptions::default();
+    archive.start_file("a.txt", plain).unwrap();
+    archive.write_all(b"plain").unwrap();
+    for name in ["b.txt", "c.txt"] {
+        archive.start_file(name, plain.with_aes_encryption(zip::AesMode::Aes256, "password")).unwrap();
+        archive.write_all(name.as_bytes()).unwrap();
+    }
+    let archive = archive.finish().unwrap().into_inner();
+
+    let dir = tempfile::tempdir().unwrap();
+    let config = Configs::get_zip().set_password("password");
+    let status = Formats::get_zip().decompress_stream(&archive[..], dir.path(), &config).unwrap();
+    let names = status.entries().iter().map(|entry| entry.name()).collect::<Vec<_>>();
+    assert_eq!(names, ["a.txt", "b.txt", "c.txt"]);
+    assert_eq!(std::fs::read(dir.path().join("a.txt")).unwrap(), b"plain");
+    assert_eq!(std::fs::read(dir.path().join("b.txt")).unwrap(), b"b.txt");
+    assert_eq!(std::fs::read(dir.path().join("c.txt")).unwrap(), b"c.txt");
+
+    // Entries with data descriptors, from the first one, and entries streamed to the end.
+    let src = dir.path().join("src");
+    make_tree(&src);
+    let mut streamed = Vec::new();
+    Formats::get_zip().compress_stream(&[&src], &mut streamed, &Configs::get_zip()).unwrap();
+    let mut seekable = std::io::Cursor::new(Vec::new());
+    Formats::get_zip().compress(&[&src], &mut seekable, &Configs::get_zip()).unwrap();
+    for archive in [streamed, seekable.into_inner()] {
+        let out = dir.path().join("out");
+        Formats::get_zip().decompress_stream(&archive[..], &out, &Configs::get_zip()).unwrap();
+        assert_same_tree(&src, &out);
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+}
+
+/// Make a sparse file of [LARGE_SIZE] bytes, zeros but a mark at its end.
+fn make_large(path: &Path) {
+    let mut file = std::fs::File::create(path).unwrap();
+    file.set_len(LARGE_SIZE).unwrap();
+    file.seek(SeekFrom::End(-4)).unwrap();
+    file.write_all(b"mark").unwrap();
+}
+
+/// Decompress the archive and check the large file and the small ones next to it.
+fn check_large(archive: &Path) {
+    let mut names = vec![];
+    let mut sink = FnSink(|name: &str, data: &mut dyn Read| {
+        let mut tail = Vec::new();
+        let mut size = 0;
+        let mut buf = vec![0; 1 << 20];
+        loop {
+            let read = data.read(&mut buf)?;
+            if read == 0 {
+                break;
+            }
+            size += read as u64;
+            tail.extend_from_slice(&buf[..read]);
+            tail.drain(..tail.len().saturating_sub(4));
+        }
+        if name == "large.bin" {
+            assert_eq!(size, LARGE_SIZE);
+            assert_eq!(tail, b"mark");
+        }
+        names.push(name.to_string());
+        Ok(())
+    });
+    let file = std::fs::File::open(archive).unwrap();
+    Formats::get_zip().decompress_into(file, &mut sink, &Configs::get_zip()).unwrap();
+    assert!(names.iter().any(|name| name == "large.bin"));
+    assert!(names.iter().any(|name| name == "sub/inner.txt"));
+}
+
+#[test]
+#[ignore = "compresses over 4 GiB"]
+fn large_file_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+    make_large(&src.join("large.bin"));
+    let archive = dir.path().join("large.zip");
+
+    for threads in [1, 4] {
+        Formats::get_zip().compress_f(&[&src], &archive, &Configs::get_zip().set_threads(threads)).unwrap();
+        check_large(&archive);
+    }
+
+    // A writer that can't seek.
+    let output = std::fs::File::create(&archive).unwrap();
+    Formats::get_zip().compress_stream(&[&src], output, &Configs::get_zip()).unwrap();
+    check_large(&archive);
+}
+
+#[test]
+fn many_entries_round_trip() {
+    let input = (0..70_000).map(|i| (format!("{i}.txt"), i.to_string())).collect::<Vec<_>>();
+    let mut output = std::io::Cursor::new(Vec::new());
+    Formats::get_zip().compress_mem(&input, &mut output, &Configs::get_zip().use_store()).unwrap();
+
+    output.set_position(0);
+    let files = Formats::get_zip().decompress_mem(output, &Configs::get_zip()).unwrap();
+    assert_eq!(files.len(), input.len());
+    assert_eq!(files["69999.txt"], b"69999");
+}
+
+#[test]
+fn comments_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+    let archive = dir.path().join("comments.zip");
+    let config = Configs::get_zip()
+        .set_comment(Some("build 42"))
+        .set_entry_comment("file3.txt", Some("commit abc"))
+        .set_entry_comment("sub/inner.txt", Some("build of 2026"))
+        .set_entry_comment("sub/empty/", Some("empty directory"));
+
+    let check = || {
+        let view = ziphere::view::Archive::new(&archive);
+        assert_eq!(view.comment().unwrap().as_deref(), Some("build 42"));
+        let comments = view.entries().unwrap().into_iter()
+            .filter_map(|entry| Some((entry.name().to_string(), entry.comment()?.to_string())))
+            .collect::<std::collections::BTreeMap<_, _>>();
+        assert_eq!(comments.len(), 3, "{comments:?}");
+        assert_eq!(comments["file3.txt"], "commit abc");
+        assert_eq!(comments["sub/inner.txt"], "build of 2026");
+        assert_eq!(comments["sub/empty/"], "empty directory");
+
+        let out = dir.path().join("out");
+        Formats::get_zip().decompress_f(&archive, &out, &Configs::get_zip()).unwrap();
+        assert_same_tree(&src, &out);
+        std::fs::remove_dir_all(&out).unwrap();
+    };
+    for threads in [1, 4] {
+        Formats::get_zip().compress_f(&[&src], &archive, &config.clone().set_threads(threads)).unwrap();
+        check();
+    }
+    let output = std::fs::File::create(&archive).unwrap();
+    Formats::get_zip().compress_stream(&[&src], output, &config.clone().set_password("password")).unwrap();
+    let view = ziphere::view::Archive::new(&archive);
+    assert_eq!(view.entries().unwrap().iter().find(|entry| entry.name() == "file3.txt").unwrap().comment(), Some("commit abc"));
+
+    let output = std::fs::File::create(&archive).unwrap();
+    Formats::get_zip().compress_mem(&[("a.txt", "a"), ("b.txt", "b")], output, &config.set_entry_comment("b.txt", Some("only b"))).unwrap();
+    let entries = ziphere::view::Archive::new(&archive).entries().unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.comment()).collect::<Vec<_>>(), [None, Some("only b")]);
+
+    // A comment out of ASCII is flagged as UTF-8, whatever the name is.
+    let output = std::fs::File::create(&archive).unwrap();
+    let config = Configs::get_zip()
+        .set_entry_comment("größe.txt", Some("größe"))
+        .set_entry_comment("a.txt", Some("サイズ"));
+    Formats::get_zip().compress_mem(&[("a.txt", "a"), ("größe.txt", "b")], output, &config).unwrap();
+    let entries = ziphere::view::Archive::new(&archive).entries().unwrap();
+    assert_eq!(entries.iter().map(|entry| entry.comment()).collect::<Vec<_>>(), [Some("サイズ"), Some("größe")]);
+    let mut zip = zip::ZipArchive::new(std::fs::File::open(&archive).unwrap()).unwrap();
+    assert_eq!(zip.by_name("a.txt").unwrap().comment(), "サイズ");
+}
+
+#[test]
+fn comments_of_zip64_entries() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+    let archive = dir.path().join("comments.zip");
+    let config = Configs::get_zip()
+        .set_force_zip64(true)
+        .set_comment(Some("build 42"))
+        .set_entry_comment("file3.txt", Some("größe"))
+        .set_entry_comment("sub/empty", Some("empty directory"));
+
+    for (threads, password) in [(1, None), (4, None), (1, Some("password"))] {
+        let mut config = config.clone().set_threads(threads);
+        if let Some(password) = password {
+            config = config.set_password(password);
+        }
+        Formats::get_zip().compress_f(&[&src], &archive, &config).unwrap();
+        let view = ziphere::view::Archive::new(&archive);
+        assert_eq!(view.comment().unwrap().as_deref(), Some("build 42"));
+        let comments = view.entries().unwrap().into_iter()
+            .filter_map(|entry| Some((entry.name().to_string(), entry.comment()?.to_string())))
+            .collect::<Vec<_>>();
+        assert_eq!(comments, [("file3.txt".to_string(), "größe".to_string()), ("sub/empty/".to_string(), "empty directory".to_string())]);
+
+        let out = dir.path().join("out");
+        let mut unzip = Configs::get_zip();
+        if let Some(password) = password {
+            unzip = unzip.set_password(password);
+        }
+        Formats::get_zip().decompress_f(&archive, &out, &unzip).unwrap();
+        assert_same_tree(&src, &out);
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+
+    let mut output = std::io::Cursor::new(Vec::new());
+    Formats::get_zip().compress_stream(&[&src], &mut output, &config).unwrap();
+    let mut zip = zip::ZipArchive::new(std::io::Cursor::new(output.into_inner())).unwrap();
+    assert_eq!(zip.by_name("file3.txt").unwrap().comment(), "größe");
+    assert_eq!(zip.by_name("file3.txt").unwrap().size(), "file 3 ".repeat(300).len() as u64);
+}
+
+
+#[test]
+fn split_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+    // Bytes that don't compress, so the archive is cut into several volumes.
+    let mut state = 1u32;
+    let noise = (0..300 << 10).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect::<Vec<_>>();
+    std::fs::write(src.join("noise.bin"), noise).unwrap();
+    let archive = dir.path().join("split.zip");
+    let config = Configs::get_zip().set_comment(Some("split")).set_volume_size(Some(64 << 10));
+
+    for threads in [1, 4] {
+        Formats::get_zip().compress_f(&[&src], &archive, &config.clone().set_threads(threads)).unwrap();
+        let parts = (1..).map(|i| archive.with_extension(format!("z{i:02}"))).take_while(|part| part.is_file()).collect::<Vec<_>>();
+        assert!(parts.len() >= 4, "{parts:?}");
+        assert!(std::fs::read(&parts[0]).unwrap().starts_with(b"PK\x07\x08"));
+        for part in &parts {
+            assert_eq!(std::fs::metadata(part).unwrap().len(), 64 << 10);
+        }
+        // The end of the central directory tells the last disk.
+        let last = std::fs::read(&archive).unwrap();
+        let end = last.len() - 22 - "split".len();
+        assert_eq!(&last[end..end + 4], b"PK\x05\x06");
+        assert_eq!(u16::from_le_bytes([last[end + 4], last[end + 5]]) as usize, parts.len());
+
+        for first in [&archive, &parts[0]] {
+            let out = dir.path().join("out");
+            Formats::get_zip().decompress_f(first, &out, &Configs::get_zip()).unwrap();
+            assert_same_tree(&src, &out);
+            std::fs::remove_dir_all(&out).unwrap();
+
+            let view = ziphere::view::Archive::new(first);
+            assert_eq!(view.comment().unwrap().as_deref(), Some("split"));
+            assert!(view.entries().unwrap().iter().any(|entry| entry.name() == "noise.bin" && entry.size() == 300 << 10));
+        }
+        parts.iter().for_each(|part| std::fs::remove_file(part).unwrap());
+    }
+
+    // An archive that fits in one volume is a plain one.
+    Formats::get_zip().compress_f(&[&src.join("sub")], &archive, &config).unwrap();
+    assert!(!archive.with_extension("z01").exists());
+    assert!(std::fs::read(&archive).unwrap().starts_with(b"PK\x03\x04"));
+
+    let result = Formats::get_zip().compress_f(&[&src], &archive, &config.set_volume_size(Some(1000)));
+    assert!(matches!(result, Err(ziphere::error::ComdeError::InvalidOption(_))));
+}
+
+/// Write `len` bytes that don't compress into `path`.
+fn write_noise(path: &Path, len: usize, seed: u32) {
+    let mut state = seed;
+    let noise = (0..len).map(|_| {
+        state = state.wrapping_mul(1_103_515_245).wrapping_add(12345);
+        (state >> 16) as u8
+    }).collect::<Vec<_>>();
+    std::fs::write(path, noise).unwrap();
+}
+
+/// Split `src` into volumes of `volume_size` bytes and check it extracts the same.
+fn check_split(src: &Path, archive: &Path, config: &ziphere::formats::zip::ZipCfg, volume_size: u64) {
+    Formats::get_zip().compress_f(&[src], archive, &config.clone().set_volume_size(Some(volume_size))).unwrap();
+    let parts = (1..).map(|i| archive.with_extension(format!("z{i:02}"))).take_while(|part| part.is_file()).collect::<Vec<_>>();
+    assert!(!parts.is_empty());
+    for first in [archive, &parts[0]] {
+        let out = archive.with_extension("out");
+        Formats::get_zip().decompress_f(first, &out, &Configs::get_zip()).unwrap();
+        assert_same_tree(src, &out);
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+    parts.iter().for_each(|part| std::fs::remove_file(part).unwrap());
+}
+
+#[test]
+fn split_zip64_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+    write_noise(&src.join("noise.bin"), 300 << 10, 1);
+    let archive = dir.path().join("split.zip");
+    let config = Configs::get_zip().set_force_zip64(true);
+
+    // Each file has Zip64 sizes, and the noise spans several volumes.
+    check_split(&src, &archive, &config, 64 << 10);
+    Formats::get_zip().compress_f(&[&src], &archive, &config.clone().set_volume_size(Some(64 << 10))).unwrap();
+    let view = ziphere::view::Archive::new(&archive);
+    assert!(view.entries().unwrap().iter().any(|entry| entry.name() == "noise.bin" && entry.size() == 300 << 10));
+}
+
+#[test]
+fn split_at_records() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    std::fs::create_dir_all(&src).unwrap();
+    for i in 0..20 {
+        write_noise(&src.join(format!("noise{i:02}.bin")), 7 << 10, i);
+    }
+    for i in 0..200 {
+        std::fs::write(src.join(format!("small{i:03}.txt")), format!("{i}")).unwrap();
+    }
+    let archive = dir.path().join("split.zip");
+
+    for config in [Configs::get_zip(), Configs::get_zip().set_force_zip64(true)] {
+        // Where the records are in the archive before it's cut, the volumes have the split signature ahead.
+        let plain = dir.path().join("plain.zip");
+        Formats::get_zip().compress_f(&[&src], &plain, &config).unwrap();
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&plain).unwrap()).unwrap();
+        let header = (0..zip.len()).map(|i| zip.by_index_raw(i).unwrap().header_start()).find(|&start| start > 64 << 10).unwrap();
+        let central = zip.central_directory_start();
+        let end = std::fs::metadata(&plain).unwrap().len() - 22;
+        assert!(central > 64 << 10);
+
+        // The cut goes through a local header, a record of the central directory, and the end records,
+        // which then go into the volume after the central directory.
+        for volume_size in [4 + header + 10, 4 + central + 50, 4 + end + 10] {
+            check_split(&src, &archive, &config, volume_size);
+        }
+    }
+}
+
+#[test]
+fn vfs_round_trip() {
+    use ziphere::vfs::{MemFs, VfsSink};
+
+    let fs = MemFs::new();
+    fs.insert_file("src/a.txt", "hello");
+    fs.insert_file("src/sub/b.txt", "world".repeat(100));
+    fs.insert_dir("src/empty");
+    let mut archive = std::io::Cursor::new(Vec::new());
+    Formats::get_zip().compress_vfs(&fs, &["src"], &mut archive, &Configs::get_zip()).unwrap();
+
+    let out = MemFs::new();
+    Formats::get_zip().decompress_into(std::io::Cursor::new(archive.get_ref()), &mut VfsSink::new(&out, "out"), &Configs::get_zip()).unwrap();
+    assert_eq!(out.read_file("out/a.txt").unwrap(), b"hello");
+    assert_eq!(out.read_file("out/sub/b.txt").unwrap(), "world".repeat(100).as_bytes());
+    assert!(out.paths().contains(&Path::new("out/empty").to_path_buf()));
+
+    // An entry out of the root is refused, and nothing lands out of it.
+    let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+    writer.start_file("../evil.txt", zip::write::SimpleFileOptions::default()).unwrap();
+    writer.write_all(b"evil").unwrap();
+    let archive = writer.finish().unwrap().into_inner();
+    let out = MemFs::new();
+    let result = Formats::get_zip().decompress_into(std::io::Cursor::new(&archive), &mut VfsSink::new(&out, "out"), &Configs::get_zip());
+    assert!(matches!(result, Err(ziphere::error::ComdeError::PathTraversal { .. })), "{result:?}");
+    assert!(out.paths().is_empty(), "{:?}", out.paths());
+}
+
+#[test]
+fn password_provider_retries() {
+    let mut archive = std::io::Cursor::new(Vec::new());
+    Formats::get_zip().compress_mem(&[("a.txt", "secret")], &mut archive, &Configs::get_zip().set_password("password")).unwrap();
+
+    // The provider is asked again after a wrong password, until the right one.
+    let asked = std::sync::Arc::new(std::sync::Mutex::new(vec![]));
+    let provider = {
+        let asked = asked.clone();
+        move |attempt: usize| {
+            asked.lock().unwrap().push(attempt);
+            Some(if attempt < 2 { "wrong" } else { "password" }.to_string())
+        }
+    };
+    let config = Configs::get_zip().set_password_provider(provider.clone(), 3);
+    let files = Formats::get_zip().decompress_mem(std::io::Cursor::new(archive.get_ref()), &config).unwrap();
+    assert_eq!(files["a.txt"], b"secret");
+    assert_eq!(*asked.lock().unwrap(), [0, 1, 2]);
+
+    // It gives up after the attempts allowed, with the error of the last try.
+    asked.lock().unwrap().clear();
+    let config = Configs::get_zip().set_password_provider(provider, 2);
+    let result = Formats::get_zip().decompress_mem(std::io::Cursor::new(archive.get_ref()), &config);
+    assert!(matches!(result, Err(ziphere::error::ComdeError::WrongPassword)), "{result:?}");
+    assert_eq!(*asked.lock().unwrap(), [0, 1]);
+}
+
+#[test]
+fn legacy_names_detected() {
+    use ziphere::formats::zip::NameEncoding;
+
+    // Names are written in a placeholder of the same length, then replaced by their bytes without the UTF-8 flag.
+    let archive_of = |names: &[&str], encoding: &'static encoding_rs::Encoding| {
+        let encoded = names.iter().map(|name| encoding.encode(name).0.into_owned()).collect::<Vec<_>>();
+        let placeholders = (0..names.len()).map(|i| format!("{i}").repeat(encoded[i].len())).collect::<Vec<_>>();
+        let mut writer = zip::ZipWriter::new(std::io::Cursor::new(Vec::new()));
+        for (placeholder, name) in placeholders.iter().zip(names) {
+            writer.start_file(placeholder.as_str(), zip::write::SimpleFileOptions::default()).unwrap();
+            writer.write_all(name.as_bytes()).unwrap();
+        }
+        let mut archive = writer.finish().unwrap().into_inner();
+        for (placeholder, name) in placeholders.iter().zip(&encoded) {
+            let positions = archive.windows(name.len()).enumerate()
+                .filter(|(_, window)| *window == placeholder.as_bytes())
+                .map(|(at, _)| at)
+                .collect::<Vec<_>>();
+            assert_eq!(positions.len(), 2);
+            positions.into_iter().for_each(|at| archive[at..at + name.len()].copy_from_slice(name));
+        }
+        archive
+    };
+
+    for (names, encoding) in [
+        (["中文文件.txt", "简体中文/说明.txt"], encoding_rs::GBK),
+        (["日本語のファイル.txt", "データ/説明書.txt"], encoding_rs::SHIFT_JIS),
+    ] {
+        let archive = archive_of(&names, encoding);
+        let files = Formats::get_zip().decompress_mem(std::io::Cursor::new(&archive), &Configs::get_zip()).unwrap();
+        for name in names {
+            assert_eq!(files[name], name.as_bytes(), "{name}");
+        }
+        // CP437 reads them as something else.
+        let config = Configs::get_zip().set_name_encoding(NameEncoding::Cp437);
+        let files = Formats::get_zip().decompress_mem(std::io::Cursor::new(&archive), &config).unwrap();
+        assert!(names.iter().all(|name| !files.contains_key(*name)));
+    }
+}
+
+#[cfg(all(unix, feature = "xattr"))]
+#[test]
+fn xattrs_round_trip() {
+    let dir = tempfile::tempdir().unwrap();
+    let src = dir.path().join("src");
+    make_tree(&src);
+    if xattr::set(src.join("file3.txt"), "user.origin", b"build 42").is_err() {
+        // The filesystem of temporary files has no user attributes.
+        return;
+    }
+    xattr::set(src.join("sub/empty"), "user.kind", b"empty").unwrap();
+    let archive = dir.path().join("xattrs.zip");
+
+    for threads in [1, 4] {
+        let config = Configs::get_zip().set_xattrs(true).set_threads(threads);
+        Formats::get_zip().compress_f(&[&src], &archive, &config).unwrap();
+        // They go into the extra field 0x6178 of the entry.
+        let mut zip = zip::ZipArchive::new(std::fs::File::open(&archive).unwrap()).unwrap();
+        let file = zip.by_name("file3.txt").unwrap();
+        let mut extra = file.extra_data().unwrap();
+        let mut ids = vec![];
+        while extra.len() >= 4 {
+            ids.push(u16::from_le_bytes([extra[0], extra[1]]));
+            extra = &extra[4 + u16::from_le_bytes([extra[2], extra[3]]) as usize..];
+        }
+        assert!(ids.contains(&0x6178), "{ids:x?}");
+        drop(file);
+
+        let out = dir.path().join("out");
+        Formats::get_zip().decompress_f(&archive, &out, &config).unwrap();
+        assert_same_tree(&src, &out);
+        assert_eq!(xattr::get(out.join("file3.txt"), "user.origin").unwrap().as_deref(), Some(&b"build 42"[..]));
+        assert_eq!(xattr::get(out.join("sub/empty"), "user.kind").unwrap().as_deref(), Some(&b"empty"[..]));
+        assert_eq!(xattr::get(out.join("file4.txt"), "user.origin").unwrap(), None);
+        std::fs::remove_dir_all(&out).unwrap();
+
+        // They are only restored when asked for.
+        Formats::get_zip().decompress_f(&archive, &out, &Configs::get_zip()).unwrap();
+        assert_eq!(xattr::get(out.join("file3.txt"), "user.origin").unwrap(), None);
+        std::fs::remove_dir_all(&out).unwrap();
+    }
+}